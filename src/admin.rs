@@ -0,0 +1,106 @@
+use std::process::exit;
+use vpfs::*;
+use vpfs::messages::{Fault, FaultPlan, FaultRule, Node};
+use clap::{Parser, Subcommand};
+
+#[derive(Parser, Debug)]
+#[command(name = "vpfs-admin", about = "Administrative commands for a running VPFS daemon.")]
+struct Opt {
+    #[arg(short, long, default_value_t = 8080)]
+    port: u16,
+
+    // Connect through the daemon's Unix domain socket instead of TCP. The daemon only takes
+    // commands through it from root or the user running the daemon
+    #[arg(short = 'u', long)]
+    socket: Option<String>,
+
+    // File holding the cluster key, which the daemon requires of commands sent over TCP
+    #[arg(short = 'k', long)]
+    key_file: Option<String>,
+
+    #[command(subcommand)]
+    command: AdminCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum AdminCommand {
+    /// Promote the local daemon, which must be a standby, to be the root node
+    Promote,
+    /// Move every file and directory off a node, then remove it from the cluster
+    Drain {
+        node: String,
+    },
+    /// Move files from the fullest nodes to the emptiest ones
+    Rebalance,
+    /// Replace the faults the local daemon injects, for testing. Without options, stop injecting faults
+    Faults {
+        /// Stop connecting to the node
+        #[arg(long, value_name = "NODE")]
+        partition: Vec<String>,
+        /// Close the connection instead of sending matching requests, `*` matches every peer or request
+        #[arg(long, value_name = "PEER:REQUEST")]
+        disconnect: Vec<String>,
+        /// Hold back responses to matching requests for a random time in milliseconds
+        #[arg(long, value_name = "PEER:REQUEST:MIN-MAX")]
+        delay: Vec<String>,
+        /// Fail writes of files and directory entries stored on the local node
+        #[arg(long)]
+        fail_writes: bool,
+    },
+    /// Shut the local daemon down after it finishes the requests it is serving
+    Shutdown,
+}
+
+// Parses PEER:REQUEST, where `*` stands for every peer or every request
+fn fault_rule(rule: &str, fault: Fault) -> Result<FaultRule, String> {
+    let (peer, request) = rule.split_once(':').ok_or(format!("Expected PEER:REQUEST, got {rule}"))?;
+    let any = |name: &str| (name != "*").then(|| name.to_string());
+    Ok(FaultRule { peer: any(peer).map(|name| Node { name }), request: any(request), fault })
+}
+
+fn fault_plan(partition: Vec<String>, disconnect: Vec<String>, delay: Vec<String>, fail_writes: bool) -> Result<FaultPlan, String> {
+    let mut rules: Vec<FaultRule> = partition.into_iter().map(|name| FaultRule { peer: Some(Node { name }), request: None, fault: Fault::Partition }).collect();
+    for rule in disconnect {
+        rules.push(fault_rule(&rule, Fault::Disconnect)?);
+    }
+    for rule in delay {
+        let (rule, range) = rule.rsplit_once(':').ok_or(format!("Expected PEER:REQUEST:MIN-MAX, got {rule}"))?;
+        let (min, max) = range.split_once('-').ok_or(format!("Expected MIN-MAX, got {range}"))?;
+        let millis = |millis: &str| millis.parse::<u64>().map_err(|_| format!("Bad delay {millis}"));
+        rules.push(fault_rule(rule, Fault::Delay(millis(min)?, millis(max)?))?);
+    }
+    Ok(FaultPlan { rules, fail_writes })
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let vpfs = match (opt.socket, opt.key_file) {
+        (Some(socket_path), _) => VPFS::connect_unix(socket_path),
+        (None, Some(key_file)) => {
+            let cluster_key = std::fs::read(&key_file).expect("Could not read cluster key file");
+            VPFS::connect_as(opt.port, &current_user(), &cluster_key)
+        }
+        (None, None) => VPFS::connect(opt.port),
+    };
+    let vpfs = vpfs.expect("Failed to connect to local daemon");
+
+    let result = match opt.command {
+        AdminCommand::Promote => vpfs.promote_to_root(),
+        AdminCommand::Drain { node } => vpfs.drain(Node { name: node }).map(|moved| println!("Moved {moved} files and directories")),
+        AdminCommand::Rebalance => vpfs.rebalance().map(|moved| println!("Moved {moved} files")),
+        AdminCommand::Faults { partition, disconnect, delay, fail_writes } => {
+            match fault_plan(partition, disconnect, delay, fail_writes) {
+                Ok(plan) => vpfs.inject_faults(plan),
+                Err(error) => {
+                    eprintln!("{error}");
+                    exit(2);
+                }
+            }
+        }
+        AdminCommand::Shutdown => vpfs.shutdown_daemon(),
+    };
+    if let Err(error) = result {
+        eprintln!("{:?}", error);
+        exit(1);
+    }
+}
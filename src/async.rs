@@ -0,0 +1,441 @@
+use std::future::Future;
+use std::io;
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use crate::frame::*;
+use crate::messages::*;
+use crate::stream::*;
+use crate::{client_hello, current_user, DEFAULT_DIRECTORY_MODE, DEFAULT_FILE_MODE};
+
+// Connections opened by Client::connect, each serves its requests in order
+pub const DEFAULT_CONNECTIONS: usize = 4;
+
+// A client for async programs, with the same operations as VPFS. Requests are pipelined over a few
+// connections to the local daemon, so many can be in flight at once without a thread per call.
+// Each connection is driven by a thread writing requests and a thread reading responses, so the
+// futures work with any executor
+pub struct Client {
+    pub local: Node,
+    pub user: String,
+    pub protocol: NegotiatedProtocol,
+    connections: Mutex<Vec<Pipeline>>,
+    // Number of connections to keep open, ones that are lost are replaced by the next request
+    size: usize,
+    reconnect: Arc<dyn Fn() -> io::Result<Pipeline> + Send + Sync>,
+}
+
+impl Client {
+    // Connect over TCP as the user nobody
+    pub async fn connect(listen_port: u16) -> io::Result<Client> {
+        Client::connect_with(listen_port, DEFAULT_CONNECTIONS).await
+    }
+
+    // Connect over TCP as any user, which only processes holding the cluster key may do
+    pub async fn connect_as(listen_port: u16, user: &str, cluster_key: &[u8]) -> io::Result<Client> {
+        Client::connect_as_with(listen_port, user, cluster_key, DEFAULT_CONNECTIONS).await
+    }
+
+    // The daemon serves each connection's requests one at a time, more connections let it work on
+    // more of them at once
+    pub async fn connect_with(listen_port: u16, connections: usize) -> io::Result<Client> {
+        Client::connect_tcp(listen_port, NOBODY.to_string(), None, connections).await
+    }
+
+    pub async fn connect_as_with(listen_port: u16, user: &str, cluster_key: &[u8], connections: usize) -> io::Result<Client> {
+        Client::connect_tcp(listen_port, user.to_string(), Some(cluster_key.to_vec()), connections).await
+    }
+
+    async fn connect_tcp(listen_port: u16, user: String, cluster_key: Option<Vec<u8>>, connections: usize) -> io::Result<Client> {
+        blocking(move || {
+            Client::open(user, cluster_key, connections, move || {
+                let stream = TcpStream::connect(format!("localhost:{}", listen_port))?;
+                Ok((stream.try_clone()?, stream))
+            })
+        }).await
+    }
+
+    // Connect over the daemon's Unix domain socket, as the user running this process
+    pub async fn connect_unix<P: AsRef<Path>>(socket_path: P) -> io::Result<Client> {
+        let socket_path = socket_path.as_ref().to_path_buf();
+        blocking(move || {
+            Client::open(current_user(), None, DEFAULT_CONNECTIONS, move || {
+                let stream = UnixStream::connect(&socket_path)?;
+                Ok((stream.try_clone()?, stream))
+            })
+        }).await
+    }
+
+    // connect returns the two halves of a new stream, one to write requests to and one to read
+    // responses from. It is kept to replace connections that are lost later
+    fn open<S: Stream + 'static>(
+        user: String,
+        cluster_key: Option<Vec<u8>>,
+        connections: usize,
+        connect: impl Fn() -> io::Result<(S, S)> + Send + Sync + 'static,
+    ) -> io::Result<Client> {
+        let open_pipeline = {
+            let user = user.clone();
+            move || {
+                let (writer, reader) = connect()?;
+                let mut writer = Connection::new(writer);
+                let (negotiated, local_node) = client_hello(&mut writer, &user, cluster_key.as_deref())?;
+                let mut reader = Connection::new(reader);
+                reader.protocol = Some(negotiated.clone());
+                Ok((Pipeline::start(writer, reader), negotiated, local_node))
+            }
+        };
+        let size = connections.max(1);
+        let mut pipelines = vec![];
+        let mut hello = None;
+        for _ in 0..size {
+            let (pipeline, negotiated, local_node) = open_pipeline()?;
+            pipelines.push(pipeline);
+            hello = Some((negotiated, local_node));
+        }
+        let (protocol, local) = hello.unwrap();
+        Ok(Client {
+            local,
+            user,
+            protocol,
+            connections: Mutex::new(pipelines),
+            size,
+            reconnect: Arc::new(move || open_pipeline().map(|(pipeline, _, _)| pipeline)),
+        })
+    }
+
+    // Drops the connections that were lost and opens new ones in their place. Only fails when no
+    // connection is left at all
+    async fn replace_lost_connections(&self) -> Result<(), VPFSError> {
+        let missing = {
+            let mut connections = self.connections.lock().unwrap();
+            connections.retain(|pipeline| pipeline.alive.load(Ordering::SeqCst));
+            self.size.saturating_sub(connections.len())
+        };
+        for _ in 0..missing {
+            let reconnect = self.reconnect.clone();
+            match blocking(move || reconnect()).await {
+                Ok(pipeline) => self.connections.lock().unwrap().push(pipeline),
+                Err(error) if self.connections.lock().unwrap().is_empty() => return Err(lost_connection(&error)),
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn find(&self, path: &str) -> Result<DirectoryEntry, VPFSError> {
+        self.request(ClientRequest::Find(path.to_string()), None, |response, _| match response {
+            ClientResponse::Find(find_result) => find_result,
+            response => bad_response("find", response),
+        }).await
+    }
+
+    pub async fn place(&self, path: &str, at: impl Into<Placement>) -> Result<Location, VPFSError> {
+        self.place_with_mode(path, at, DEFAULT_FILE_MODE).await
+    }
+
+    pub async fn place_with_mode(&self, path: &str, at: impl Into<Placement>, mode: u16) -> Result<Location, VPFSError> {
+        self.request(ClientRequest::Place(path.to_string(), at.into(), mode), None, |response, _| match response {
+            ClientResponse::Place(place_result) => place_result,
+            response => bad_response("place", response),
+        }).await
+    }
+
+    pub async fn mkdir(&self, path: &str, at: impl Into<Placement>) -> Result<Location, VPFSError> {
+        self.mkdir_with_mode(path, at, DEFAULT_DIRECTORY_MODE).await
+    }
+
+    pub async fn mkdir_with_mode(&self, path: &str, at: impl Into<Placement>, mode: u16) -> Result<Location, VPFSError> {
+        self.request(ClientRequest::Mkdir(path.to_string(), at.into(), mode), None, |response, _| match response {
+            ClientResponse::Mkdir(mkdir_result) => mkdir_result,
+            response => bad_response("mkdir", response),
+        }).await
+    }
+
+    pub async fn read(&self, what: Location) -> Result<Vec<u8>, VPFSError> {
+        self.request(ClientRequest::Read(what), None, |response, stream| match response {
+            ClientResponse::Read(Ok((len, hash))) => {
+                let buf = receive_data(stream, len).map_err(|error| VPFSError::BadFrame(error.to_string()))?;
+                if content_hash(&buf) != hash {
+                    return Err(VPFSError::Corrupted);
+                }
+                Ok(buf)
+            }
+            ClientResponse::Read(Err(error)) => Err(error),
+            response => bad_response("read", response),
+        }).await
+    }
+
+    pub async fn write(&self, what: Location, buf: &[u8]) -> Result<(), VPFSError> {
+        if buf.len() > MAX_FRAME_LEN {
+            return Err(VPFSError::TooLarge);
+        }
+        let len = buf.len();
+        self.request(ClientRequest::Write(what, len), Some(buf.to_vec()), move |response, _| match response {
+            ClientResponse::Write(Ok(written)) if written == len => Ok(()),
+            ClientResponse::Write(Err(error)) => Err(error),
+            response => bad_response("write", response),
+        }).await
+    }
+
+    pub async fn nodes(&self) -> Result<Vec<NodeStatus>, VPFSError> {
+        self.request(ClientRequest::Nodes, None, |response, _| match response {
+            ClientResponse::Nodes(nodes) => Ok(nodes),
+            response => bad_response("nodes", response),
+        }).await
+    }
+
+    pub async fn df(&self) -> Result<Vec<NodeCapacity>, VPFSError> {
+        self.request(ClientRequest::Df, None, |response, _| match response {
+            ClientResponse::Df(capacities) => Ok(capacities),
+            response => bad_response("df", response),
+        }).await
+    }
+
+    // Needs write permission on the directory, None removes the directory's policy
+    pub async fn set_placement_policy(&self, directory: &str, policy: Option<PlacementPolicy>) -> Result<(), VPFSError> {
+        self.request(ClientRequest::SetPlacementPolicy(directory.to_string(), policy), None, |response, _| match response {
+            ClientResponse::SetPlacementPolicy(result) => result,
+            response => bad_response("set placement policy", response),
+        }).await
+    }
+
+    pub async fn admin(&self, req: AdminRequest) -> Result<AdminResponse, VPFSError> {
+        if !self.protocol.speaks(req.version()) {
+            return Err(VPFSError::Other(format!("The local daemon speaks protocol version {}, which does not support {req:?}", self.protocol.version)));
+        }
+        self.request(ClientRequest::Admin(req), None, |response, _| match response {
+            ClientResponse::Admin(admin_response) => Ok(admin_response),
+            response => bad_response("admin request", response),
+        }).await
+    }
+
+    pub async fn fetch(&self, name: &str) -> Result<Vec<u8>, VPFSError> {
+        let dir_entry = self.find(name).await?;
+        self.read(dir_entry.location).await
+    }
+
+    pub async fn store(&self, name: &str, buf: &[u8]) -> Result<(), VPFSError> {
+        let location = match self.place(name, Placement::Auto).await {
+            Ok(location) => location,
+            Err(VPFSError::AlreadyExists(dir_entry)) => dir_entry.location,
+            Err(error) => return Err(error),
+        };
+        self.write(location, buf).await
+    }
+
+    // Sends the request, followed by data if given, over the connection with the fewest requests
+    // in flight. parse turns the response into the result, reading any data that follows it
+    async fn request<T: Send + 'static>(
+        &self,
+        message: ClientRequest,
+        data: Option<Vec<u8>>,
+        parse: impl FnOnce(ClientResponse, &mut Connection) -> Result<T, VPFSError> + Send + 'static,
+    ) -> Result<T, VPFSError> {
+        self.replace_lost_connections().await?;
+        let (requests, in_flight) = {
+            let connections = self.connections.lock().unwrap();
+            let pipeline = connections.iter().min_by_key(|pipeline| pipeline.in_flight.load(Ordering::SeqCst)).unwrap();
+            (pipeline.requests.clone(), InFlight::start(&pipeline.in_flight))
+        };
+        let (sender, reply) = reply();
+        let handler: ResponseHandler = Box::new(move |response, stream| {
+            drop(in_flight);
+            sender.send(match response {
+                Ok(ClientResponse::Error(error)) => Err(error),
+                Ok(response) => parse(response, stream),
+                Err(FrameError::Io(error)) => Err(lost_connection(&error)),
+                Err(error) => Err(VPFSError::BadFrame(error.to_string())),
+            });
+        });
+        requests.send(Request { message, data, handler }).map_err(|_| VPFSError::Other("Lost connection to the daemon".to_string()))?;
+        reply.await.unwrap_or_else(|| Err(VPFSError::Other("Lost connection to the daemon".to_string())))
+    }
+}
+
+fn bad_response<T>(request: &str, response: ClientResponse) -> Result<T, VPFSError> {
+    Err(VPFSError::Other(format!("Bad response to {request}: {}", response_name(&response))))
+}
+
+fn response_name(response: &ClientResponse) -> &'static str {
+    match response {
+        ClientResponse::Find(_) => "Find",
+        ClientResponse::Place(_) => "Place",
+        ClientResponse::Mkdir(_) => "Mkdir",
+        ClientResponse::Read(_) => "Read",
+        ClientResponse::Write(_) => "Write",
+        ClientResponse::Nodes(_) => "Nodes",
+        ClientResponse::Df(_) => "Df",
+        ClientResponse::SetPlacementPolicy(_) => "SetPlacementPolicy",
+        ClientResponse::Admin(_) => "Admin",
+        ClientResponse::Error(_) => "Error",
+    }
+}
+
+fn lost_connection(error: &io::Error) -> VPFSError {
+    VPFSError::Other(format!("Lost connection to the daemon: {error}"))
+}
+
+/* ----------------------------------- Pipelining ------------------------------------ */
+// Reads the response to a request, and any data following it, from the connection
+type ResponseHandler = Box<dyn FnOnce(Result<ClientResponse, FrameError>, &mut Connection) + Send>;
+
+struct Request {
+    message: ClientRequest,
+    data: Option<Vec<u8>>,
+    handler: ResponseHandler,
+}
+
+// One connection to the daemon. The daemon answers requests in the order they were sent, so the
+// writer hands each request's handler to the reader before sending it, and the reader runs them
+// in that order as the responses arrive
+struct Pipeline {
+    requests: Sender<Request>,
+    in_flight: Arc<AtomicUsize>,
+    // Cleared once the connection is lost
+    alive: Arc<AtomicBool>,
+}
+
+impl Pipeline {
+    fn start(writer: Connection, reader: Connection) -> Pipeline {
+        let (requests, pending_requests) = mpsc::channel();
+        let (handlers, pending_handlers) = mpsc::channel();
+        let alive = Arc::new(AtomicBool::new(true));
+        thread::spawn(move || write_requests(writer, pending_requests, handlers));
+        let reader_alive = alive.clone();
+        thread::spawn(move || {
+            read_responses(reader, pending_handlers);
+            reader_alive.store(false, Ordering::SeqCst);
+        });
+        Pipeline { requests, in_flight: Arc::new(AtomicUsize::new(0)), alive }
+    }
+}
+
+// Counts a request as in flight on its connection until it is answered, or dropped unanswered
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn start(in_flight: &Arc<AtomicUsize>) -> InFlight {
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(in_flight.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Ends when the pipeline is dropped, or when the connection is lost. Requests still waiting are then
+// dropped, which fails them. Shutting the stream down on the way out also ends the reader
+fn write_requests(mut stream: Connection, requests: Receiver<Request>, handlers: Sender<ResponseHandler>) {
+    for request in requests {
+        if handlers.send(request.handler).is_err() {
+            break;
+        }
+        let compress = stream.supports(COMPRESSION);
+        let sent = send_framed(&mut stream, &request.message).and_then(|_| match &request.data {
+            Some(data) => send_data(&mut stream, data, compress),
+            None => Ok(()),
+        });
+        if sent.is_err() {
+            // Fails the requests sent so far, whose responses will never arrive
+            break;
+        }
+    }
+    let _ = stream.shutdown();
+}
+
+// Waits on the connection even while no request is in flight, so a lost connection is noticed
+// before another request is sent over it. A handler is always queued before its request is sent,
+// so one is waiting by the time its response arrives
+fn read_responses(mut stream: Connection, handlers: Receiver<ResponseHandler>) {
+    loop {
+        let response = receive_framed(&mut stream);
+        let lost = matches!(response, Err(FrameError::Io(_)));
+        if lost {
+            // Fails the requests in flight
+            drop(handlers);
+            let _ = stream.shutdown();
+            break;
+        }
+        let Ok(handler) = handlers.recv() else {
+            break;
+        };
+        handler(response, &mut stream);
+    }
+}
+
+/* ------------------------------------ Replies -------------------------------------- */
+struct Slot<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+// Completes a Reply from another thread. Dropping it without sending resolves the reply to None
+struct ReplySender<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+// Resolves to the value sent, or None if the sender was dropped
+struct Reply<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+fn reply<T>() -> (ReplySender<T>, Reply<T>) {
+    let slot = Arc::new(Mutex::new(Slot { value: None, waker: None, closed: false }));
+    (ReplySender { slot: slot.clone() }, Reply { slot })
+}
+
+impl<T> ReplySender<T> {
+    fn send(self, value: T) {
+        self.slot.lock().unwrap().value = Some(value);
+    }
+}
+
+impl<T> Drop for ReplySender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut slot = self.slot.lock().unwrap();
+            slot.closed = true;
+            slot.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Future for Reply<T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut slot = self.slot.lock().unwrap();
+        if let Some(value) = slot.value.take() {
+            Poll::Ready(Some(value))
+        }
+        else if slot.closed {
+            Poll::Ready(None)
+        }
+        else {
+            slot.waker = Some(context.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+// Runs blocking work, such as connecting, on a thread of its own
+fn blocking<T: Send + 'static>(work: impl FnOnce() -> io::Result<T> + Send + 'static) -> impl Future<Output = io::Result<T>> {
+    let (sender, reply) = reply();
+    thread::spawn(move || sender.send(work()));
+    async move { reply.await.unwrap_or_else(|| Err(io::Error::other("Connecting thread panicked"))) }
+}
@@ -0,0 +1,30 @@
+use std::thread;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use vpfs::daemon::{Daemon, Opt};
+
+fn main() {
+    let opt = match Opt::from_args() {
+        Ok(opt) => opt,
+        Err(error) => {
+            println!("{error}");
+            return;
+        }
+    };
+    let daemon = match Daemon::builder().options(opt).spawn() {
+        Ok(daemon) => daemon,
+        Err(error) => {
+            println!("{error}");
+            return;
+        }
+    };
+    // Shut down gracefully on the first signal, so no directory or cache index is left half written
+    let mut signals = Signals::new([SIGTERM, SIGINT]).expect("Could not install signal handlers");
+    let shutdown_handle = daemon.shutdown_handle();
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            shutdown_handle.shutdown();
+        }
+    });
+    daemon.wait();
+}
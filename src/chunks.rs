@@ -0,0 +1,53 @@
+// Content defined chunking with a gear hash. Where a chunk ends depends only on the 64 bytes
+// before it, so an edit only changes the chunks around it, and the same data is split the same way
+// on every node
+const MIN_CHUNK_LEN: usize = 2 << 10;
+const MAX_CHUNK_LEN: usize = 64 << 10;
+// 13 bits cut chunks every 8 KiB past the minimum on average. The top bits of a gear hash depend
+// on the most bytes, so they are the ones checked
+const BOUNDARY_MASK: u64 = ((1 << 13) - 1) << 51;
+
+// Fixed pseudo random values for every byte, generated with splitmix64
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut seed: u64 = 0x5650_4653;
+    let mut i = 0;
+    while i < 256 {
+        seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut value = seed;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = value ^ (value >> 31);
+        i += 1;
+    }
+    table
+}
+
+const GEAR: [u64; 256] = gear_table();
+
+pub fn split(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = vec![];
+    let mut rest = data;
+    while !rest.is_empty() {
+        let len = next_boundary(rest);
+        let (chunk, remaining) = rest.split_at(len);
+        chunks.push(chunk);
+        rest = remaining;
+    }
+    chunks
+}
+
+fn next_boundary(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_LEN {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK_LEN);
+    let mut hash: u64 = 0;
+    for (i, byte) in data[..end].iter().enumerate().skip(MIN_CHUNK_LEN - 64) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        if i >= MIN_CHUNK_LEN && hash & BOUNDARY_MASK == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
@@ -1,28 +1,47 @@
 use std::collections::HashMap;
-use std::io::{Read, Write, BufReader, self};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex, MutexGuard, RwLock};
-use std::thread::{self, sleep};
+use std::ffi::OsString;
+use std::io::{Read, BufReader, self};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread::{self, sleep, JoinHandle};
 use std::fs;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use clap::Parser;
 use lru::LruCache;
 use serde::de::DeserializeOwned;
-use serde::ser::Error;
 use serde::{Deserialize, Serialize};
-use rand::Rng;
+use hmac::{Hmac, Mac};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use sha2::Sha256;
+use rand::seq::IndexedRandom;
 
-mod messages;
-use messages::*;
+use crate::messages::*;
+use crate::stream::*;
+use crate::frame::*;
+use crate::chunks;
+use crate::pool::WorkerPool;
+use crate::user_name;
+use crate::storage::*;
+use crate::clock::*;
+use crate::network::*;
 
 
-/// A simple example of StructOpt-based CLI parsing
-#[derive(Parser, Debug)]
+/// Options of the VPFS daemon, given on the command line or in the TOML file named by --config
+#[derive(Parser, Deserialize, Default, Debug)]
 #[command(name = "vpfs", about = "Virtual private file system prototype.")]
-struct Opt {
-    #[arg(short, long, default_value_t = 8080)]
-    port: u16,
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+// Options left out fall back to the config file, then to the values saved in the state file by the
+// previous run, and then to the defaults in DaemonConfig. The config file uses the long option names
+pub struct Opt {
+    #[arg(short, long)]
+    port: Option<u16>,
 
     #[arg(short, long)]
     root_addr: Option<String>,
@@ -31,273 +50,2310 @@ struct Opt {
     listening_addr: Option<String>,
 
     #[arg(short, long)]
-    name: String,
+    name: Option<String>,
 
     //Maximum cache size in bytes
-    #[arg(short, long, default_value_t = 1 << 16)]
-    cache_size: usize,
+    #[arg(short, long)]
+    cache_size: Option<usize>,
 
     // When non-zero, artificial latency added to each request
     // Latency specified in milliseconds
-    #[arg(short, long, default_value_t = 0)]
+    #[arg(short, long)]
+    artificial_latency: Option<u64>,
+
+    // Time between heartbeats sent to every known node, in milliseconds
+    #[arg(long)]
+    heartbeat_interval: Option<u64>,
+
+    // Register with the root as a standby that replicates the root directory
+    #[arg(short, long, overrides_with = "no_standby")]
+    standby: bool,
+
+    // Stop being a standby, if a previous run was one
+    #[arg(long)]
+    no_standby: bool,
+
+    // File holding the pre-shared key every daemon in the cluster must prove it knows
+    #[arg(short, long)]
+    key_file: Option<String>,
+
+    // Encrypt links between daemons with keys derived from the cluster key
+    #[arg(short, long, overrides_with = "no_encrypt")]
+    encrypt: bool,
+
+    #[arg(long)]
+    no_encrypt: bool,
+
+    // Unix domain socket that local user processes can connect through
+    #[arg(short = 'u', long)]
+    socket: Option<String>,
+
+    // Accept user processes over TCP again, if a previous run did not
+    #[arg(long, overrides_with = "no_tcp_clients")]
+    tcp_clients: bool,
+
+    // Only accept user processes through the Unix domain socket, never over TCP
+    #[arg(long)]
+    no_tcp_clients: bool,
+
+    // Maximum number of bytes of files and directories stored on this node
+    #[arg(short, long, overrides_with = "no_quota")]
+    quota: Option<u64>,
+
+    // Remove the quota a previous run had
+    #[arg(long)]
+    no_quota: bool,
+
+    // Store files written by users as content defined chunks, each distinct chunk stored once
+    #[arg(long, overrides_with = "no_chunked")]
+    chunked: bool,
+
+    // Store files written from now on whole, files already stored in chunks stay that way
+    #[arg(long)]
+    no_chunked: bool,
+
+    // Keep files and metadata in memory instead of the data directory, nothing survives a restart
+    #[arg(long)]
+    in_memory: bool,
+
+    // Directory files and state are kept in
+    #[arg(long)]
+    data_dir: Option<String>,
+
+    // How long to wait for a connection to another daemon, in milliseconds
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+
+    // Heartbeats in a row the root may miss before failing over to a standby
+    #[arg(long)]
+    failover_after: Option<u32>,
+
+    // User processes served at once, each one holds a thread for as long as it is open. Links from
+    // other daemons have a few threads of their own on top
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    // Connections waiting for a thread, further ones and those waiting too long are turned away as busy
+    #[arg(long)]
+    connection_queue: Option<usize>,
+
+    // TOML file with values for any of the other options
+    #[arg(long)]
+    #[serde(skip)]
+    config: Option<String>,
+}
+
+impl Opt {
+    // Parses the command line, together with the config file it names
+    pub fn from_args() -> Result<Opt, String> {
+        Opt::parse().with_config_file()
+    }
+
+    // Like from_args, for a command line that is not the process's own. The first argument is the
+    // program name
+    pub fn from_command_line<I: IntoIterator<Item = T>, T: Into<OsString> + Clone>(args: I) -> Result<Opt, String> {
+        Opt::try_parse_from(args).map_err(|error| error.to_string())?.with_config_file()
+    }
+
+    fn with_config_file(self) -> Result<Opt, String> {
+        match &self.config {
+            Some(config_file) => load_config_file(config_file).map(|file| self.or(file)),
+            None => Ok(self),
+        }
+    }
+
+    // Options not given on the command line are taken from the config file
+    fn or(self, file: Opt) -> Opt {
+        Opt {
+            port: self.port.or(file.port),
+            root_addr: self.root_addr.or(file.root_addr),
+            listening_addr: self.listening_addr.or(file.listening_addr),
+            name: self.name.or(file.name),
+            cache_size: self.cache_size.or(file.cache_size),
+            artificial_latency: self.artificial_latency.or(file.artificial_latency),
+            heartbeat_interval: self.heartbeat_interval.or(file.heartbeat_interval),
+            standby: self.standby || (file.standby && !self.no_standby),
+            no_standby: self.no_standby || (file.no_standby && !self.standby),
+            key_file: self.key_file.or(file.key_file),
+            encrypt: self.encrypt || (file.encrypt && !self.no_encrypt),
+            no_encrypt: self.no_encrypt || (file.no_encrypt && !self.encrypt),
+            socket: self.socket.or(file.socket),
+            tcp_clients: self.tcp_clients || (file.tcp_clients && !self.no_tcp_clients),
+            no_tcp_clients: self.no_tcp_clients || (file.no_tcp_clients && !self.tcp_clients),
+            quota: self.quota.or(file.quota.filter(|_| !self.no_quota)),
+            no_quota: self.no_quota || (file.no_quota && self.quota.is_none()),
+            chunked: self.chunked || (file.chunked && !self.no_chunked),
+            no_chunked: self.no_chunked || (file.no_chunked && !self.chunked),
+            in_memory: self.in_memory || file.in_memory,
+            data_dir: self.data_dir.or(file.data_dir),
+            connect_timeout: self.connect_timeout.or(file.connect_timeout),
+            failover_after: self.failover_after.or(file.failover_after),
+            max_connections: self.max_connections.or(file.max_connections),
+            connection_queue: self.connection_queue.or(file.connection_queue),
+            config: self.config,
+        }
+    }
+}
+
+// Relative paths in the config file are relative to the directory the file is in
+fn load_config_file(path: &str) -> Result<Opt, String> {
+    let contents = fs::read_to_string(path).map_err(|error| format!("Could not read config file {path}: {error}"))?;
+    let mut file: Opt = toml::from_str(&contents).map_err(|error| format!("Invalid config file {path}: {error}"))?;
+    let switches = [
+        ("standby", file.standby, file.no_standby),
+        ("encrypt", file.encrypt, file.no_encrypt),
+        ("tcp-clients", file.tcp_clients, file.no_tcp_clients),
+        ("quota", file.quota.is_some(), file.no_quota),
+        ("chunked", file.chunked, file.no_chunked),
+    ];
+    for (option, on, off) in switches {
+        if on && off {
+            return Err(format!("Invalid config file {path}: both {option} and no-{option} are set"));
+        }
+    }
+    let base = Path::new(path).parent().unwrap_or(Path::new("."));
+    let resolve = |relative_path: String| base.join(relative_path).to_string_lossy().into_owned();
+    file.key_file = file.key_file.map(resolve);
+    file.socket = file.socket.map(resolve);
+    file.data_dir = file.data_dir.map(resolve);
+    Ok(file)
+}
+
+// An option that is turned on with --option and off with --no-option, None if neither was given
+fn switch(on: bool, off: bool) -> Option<bool> {
+    if on {
+        Some(true)
+    }
+    else if off {
+        Some(false)
+    }
+    else {
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct DaemonConfig {
+    port: u16,
+    cache_size: usize,
     artificial_latency: u64,
+    heartbeat_interval: u64,
+    standby: bool,
+    key_file: Option<String>,
+    encrypt: bool,
+    socket: Option<String>,
+    tcp_clients: bool,
+    quota: Option<u64>,
+    chunked: bool,
+    connect_timeout: u64,
+    // Number of heartbeats the root may miss before daemons fail over to a standby
+    failover_after: u32,
+    max_connections: usize,
+    connection_queue: usize,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            port: 8080,
+            cache_size: 1 << 16,
+            artificial_latency: 0,
+            heartbeat_interval: 1000,
+            standby: false,
+            key_file: None,
+            encrypt: false,
+            socket: None,
+            tcp_clients: true,
+            quota: None,
+            chunked: false,
+            connect_timeout: 1000,
+            failover_after: 3,
+            max_connections: 256,
+            connection_queue: 64,
+        }
+    }
 }
 
 struct DaemonState {
-    root: Option<Node>,
+    root: RwLock<Option<Node>>,
     local: Node,
-    connections: Mutex<HashMap<Node, Arc<Mutex<TcpStream>>>>,
-    known_hosts: Mutex<Option<HashMap<Node, String>>>,
+    connections: Mutex<HashMap<Node, Arc<Mutex<Connection>>>>,
+    known_hosts: Mutex<HashMap<Node, HostEntry>>,
+    local_entry: Option<HostEntry>,
     cache: Mutex<LruCache<Location, CacheEntry>>,
     max_cache_size: usize,
     used_cache_bytes: RwLock<usize>,
     artificial_latency: Duration,
-    file_access_lock: RwLock<()>
+    file_access_lock: RwLock<()>,
+    node_status: Mutex<HashMap<Node, NodeStatus>>,
+    heartbeat_interval: Duration,
+    // Connecting to a host that is down can otherwise block for minutes
+    connect_timeout: Duration,
+    standbys: Mutex<Vec<Node>>,
+    root_missed_heartbeats: Mutex<u32>,
+    // Raised by every promotion, so that a root cut off from the cluster steps down once it hears
+    // of a newer one
+    root_epoch: Mutex<u64>,
+    config: DaemonConfig,
+    state_file_lock: Mutex<()>,
+    cluster_key: Option<Vec<u8>>,
+    permissions: Mutex<HashMap<String, Permissions>>,
+    used_bytes: Mutex<u64>,
+    placement_policies: Mutex<HashMap<String, PlacementPolicy>>,
+    next_round_robin: Mutex<usize>,
+    hashes: Mutex<HashMap<String, ContentHash>>,
+    chunks: Mutex<ChunkStore>,
+    storage: Box<dyn Storage>,
+    // Port the daemon accepts connections on, which the OS picks when configured as 0
+    port: u16,
+    shutting_down: AtomicBool,
+    open_sockets: Mutex<OpenSockets>,
+    // Requests being served, which shutting down waits for
+    in_flight: Mutex<usize>,
+    requests_done: Condvar,
+    // Threads serving connections
+    workers: Arc<WorkerPool>,
+    // Threads telling connections that found every worker busy to try again later
+    turning_away: Arc<WorkerPool>,
+    // User processes being served, which may hold at most max_connections of the workers
+    clients: Mutex<usize>,
+    faults: Mutex<FaultPlan>,
+    network: Arc<dyn Network>,
+    clock: Arc<dyn Clock>,
+    // Seeded by simulations, so the URIs of new files and the peers picked for gossip repeat
+    rng: Mutex<StdRng>,
 }
 
 /* ------------------------------ Helper functions --------------------------------- */
-fn establish_connecttion(node: &Node, connections: &mut MutexGuard<HashMap<Node, Arc<Mutex<TcpStream>>>>, addr: &str) -> Option<Arc<Mutex<TcpStream>>> {
-    if let Ok(mut stream) = TcpStream::connect(&addr) {
-        send_message(&mut stream, Hello::DaemonHello);
-        receive_message::<HelloResponse>(&mut stream).expect("Got bad hello response");
-        let stream_arc = Arc::new(Mutex::new(stream));
-        connections.insert(node.clone(), stream_arc.clone());
-        Some(stream_arc)
-    }
-    else {
-        None
+// Connects and says hello without holding any lock, since either can take as long as the connect
+// timeout. Another thread may have connected to the node in the meantime, in which case its
+// connection is kept and this one dropped
+fn establish_connecttion(node: &Node, addr: &str, state: &Arc<DaemonState>) -> Option<Arc<Mutex<Connection>>> {
+    let stream = Connection::new(state.network.connect(addr, state.connect_timeout).ok()?);
+    // A peer that accepts connections but never answers would otherwise hold this thread forever
+    let _ = stream.set_read_timeout(Some(state.connect_timeout));
+    let stream = match daemon_handshake(stream, Hello::DaemonHello(local_protocol(state)), state) {
+        Ok((stream, _)) => stream,
+        Err(error) => {
+            eprintln!("Could not connect to {}: {}", node.name, error);
+            return None;
+        }
+    };
+    let _ = stream.set_read_timeout(None);
+    let mut connections = state.connections.lock().unwrap();
+    Some(connections.entry(node.clone()).or_insert_with(|| Arc::new(Mutex::new(stream))).clone())
+}
+
+// Returns None without touching the network if heartbeats have found the node to be down
+fn stream_for(node: &Node, state: &Arc<DaemonState>) -> Option<Arc<Mutex<Connection>>> {
+    if is_known_down(node, state) {
+        return None;
     }
+    connect_to(node, state)
 }
 
-fn stream_for(node: &Node, state: &Arc<DaemonState>) -> Option<Arc<Mutex<TcpStream>>> {
-    let mut connections = state.connections.lock().unwrap();
-    if let Some(connection) = connections.get(&node) {
+fn connect_to(node: &Node, state: &Arc<DaemonState>) -> Option<Arc<Mutex<Connection>>> {
+    if is_partitioned(node, state) {
+        return None;
+    }
+    if let Some(connection) = state.connections.lock().unwrap().get(node) {
         return Some(connection.clone());
     }
-    let known_hosts = state.known_hosts.lock().unwrap();
-    if let Some(addr) = known_hosts.as_ref().unwrap().get(&node) {
-        return establish_connecttion(node, &mut connections, addr);
+    let addr = address_for(node, state)?;
+    establish_connecttion(node, &addr, state)
+}
+
+// Looks up where a node listens, asking the root about nodes this one has not heard of
+fn address_for(node: &Node, state: &Arc<DaemonState>) -> Option<String> {
+    if let Some(entry) = state.known_hosts.lock().unwrap().get(node) {
+        return (!entry.removed).then(|| entry.address.clone());
     }
-    if let Some(root_node) = &state.root {
-        if state.local == *root_node {
-            return None;
-        }
-        if let Some(root_connection) = connections.get(root_node) {
-            let mut root_connection = root_connection.lock().unwrap();
-            send_message(&mut root_connection, DaemonRequest::AddressFor(node.clone()));
-            match receive_message(&mut root_connection) {
-                Ok(DaemonResponse::AddressFor(Some(addr))) => {
-                    drop(root_connection);
-                    return establish_connecttion(node, &mut connections, &addr)
-                },
-                _ => return None
-            }
-        }
+    let root_node = current_root(state)?;
+    if state.local == root_node {
+        return None;
     }
-    None
+    let root_connection = state.connections.lock().unwrap().get(&root_node)?.clone();
+    let mut root_connection = lock_connection(&root_connection, &root_node, &["AddressFor"], state);
+    send_request(&mut root_connection, &root_node, DaemonRequest::AddressFor(node.clone()), state);
+    match receive_message(&mut root_connection) {
+        Ok(DaemonResponse::AddressFor(Some(addr))) => {
+            drop(root_connection);
+            // Remember the address so heartbeats also cover this node, unless gossip got here first
+            let mut known_hosts = state.known_hosts.lock().unwrap();
+            let entry = known_hosts.entry(node.clone()).or_insert(HostEntry { address: addr, generation: 0, removed: false });
+            (!entry.removed).then(|| entry.address.clone())
+        },
+        _ => None
+    }
+}
+
+// Forget a connection that failed, so the next request reconnects instead of reusing a dead stream
+fn drop_connection(node: &Node, state: &Arc<DaemonState>) {
+    state.connections.lock().unwrap().remove(node);
 }
 
-fn receive_message_with_latceny<T: DeserializeOwned>(stream: &mut TcpStream, artificial_latency: Duration) -> Result<T, serde_bare::error::Error> {
-    let request = serde_bare::from_reader(stream);
+fn receive_message_with_latceny<T: DeserializeOwned>(stream: &mut Connection, artificial_latency: Duration) -> Result<T, FrameError> {
+    let request = receive_framed(stream);
     if artificial_latency > Duration::from_millis(0) {
         sleep(artificial_latency);
     }
     request
 }
 
-fn receive_message<T: DeserializeOwned> (stream: &mut TcpStream) -> Result<T, serde_bare::error::Error> {
+fn receive_message<T: DeserializeOwned> (stream: &mut Connection) -> Result<T, FrameError> {
     receive_message_with_latceny(stream, Duration::from_millis(0))
 }
 
-fn send_message <T: Serialize>(stream: &mut TcpStream, message: T) {
-    serde_bare::to_writer(stream, &message).unwrap();
+// A failed write is not reported here, the closed connection surfaces on the next receive
+fn send_message <T: Serialize>(stream: &mut Connection, message: T) {
+    let _ = send_framed(stream, &message);
+}
+
+// The hello exchange is not framed, so peers can still read each other's protocol versions and
+// rejections even when they disagree on how the rest of the connection is encoded
+fn receive_unframed<T: DeserializeOwned>(stream: &mut Connection, artificial_latency: Duration) -> Result<T, serde_bare::error::Error> {
+    let message = serde_bare::from_reader(stream);
+    if artificial_latency > Duration::from_millis(0) {
+        sleep(artificial_latency);
+    }
+    message
+}
+
+fn send_unframed<T: Serialize>(stream: &mut Connection, message: T) {
+    let _ = serde_bare::to_writer(stream, &message);
+}
+
+// Sends the response announcing a file's length followed by its data. Files that do not fit in a
+// frame are refused before anything is announced
+fn send_file_data<T: Serialize>(stream: &mut Connection, data: &[u8], response: impl FnOnce(Result<usize, VPFSError>) -> T) {
+    if data.len() > MAX_FRAME_LEN {
+        send_message(stream, response(Err(VPFSError::TooLarge)));
+        return;
+    }
+    send_message(stream, response(Ok(data.len())));
+    let compress = stream.supports(COMPRESSION);
+    let _ = send_data(stream, data, compress);
 }
 
-fn send_and_recive <T: Serialize, U: DeserializeOwned> (node: &Node, message: T, state: &Arc<DaemonState>) -> Result<U, serde_bare::error::Error> {
+fn send_and_recive <U: DeserializeOwned> (node: &Node, request: DaemonRequest, state: &Arc<DaemonState>) -> Result<U, FrameError> {
     if let Some(node_connection_lock) = stream_for(node, state) {
-        let mut node_connection = node_connection_lock.lock().unwrap();
-        send_message(&mut node_connection, message);
+        let mut node_connection = lock_connection(&node_connection_lock, node, &[request.name()], state);
+        send_request(&mut node_connection, node, request, state);
         receive_message(&mut node_connection)
     }
     else {
-        Err(serde_bare::error::Error::custom("Could not connect"))
+        Err(FrameError::Io(io::Error::new(io::ErrorKind::NotConnected, "Could not connect")))
     }
 }
 
-fn read_local(uri: &str, fs_lock: &RwLock<()>) -> io::Result<Vec<u8>>{
-    fs_lock.read().unwrap();
-    fs::read(uri)
+// Like send_and_recive, for requests added by a later protocol version. Peers that negotiated an
+// older version are not sent the request, and None is returned
+fn send_if_spoken<U: DeserializeOwned>(node: &Node, version: u32, request: DaemonRequest, state: &Arc<DaemonState>) -> Option<Result<U, FrameError>> {
+    let Some(node_connection_lock) = stream_for(node, state) else {
+        return Some(Err(FrameError::Io(io::Error::new(io::ErrorKind::NotConnected, "Could not connect"))));
+    };
+    let mut node_connection = lock_connection(&node_connection_lock, node, &[request.name()], state);
+    if !node_connection.speaks(version) {
+        return None;
+    }
+    send_request(&mut node_connection, node, request, state);
+    Some(receive_message(&mut node_connection))
 }
 
-fn read_remote(location: &Location, state: &Arc<DaemonState>) -> Result<Vec<u8>, VPFSError> {
-    let mut cache = state.cache.lock().unwrap();
-    let cache_entry = cache.get(&location);
-    let _fs_lock = state.file_access_lock.write().unwrap();
-    let cache_last_update_time = if let Some(cache_entry) = cache_entry {
-        if let Ok(file_data) = fs::metadata(&cache_entry.uri) {
-            file_data.modified().ok()
+/* --------------------------------- Fault injection --------------------------------- */
+// Replaces the faults injected by this daemon, see FaultPlan
+fn inject_faults(plan: FaultPlan, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    for rule in &plan.rules {
+        if let Some(request) = &rule.request {
+            if !DAEMON_REQUEST_NAMES.contains(&request.as_str()) {
+                return Err(VPFSError::Other(format!("Unknown request {request}")));
+            }
+            if rule.fault == Fault::Partition {
+                return Err(VPFSError::Other("A partition applies to every request".to_string()));
+            }
         }
-        else {
-            None
+        if let Fault::Delay(min, max) = rule.fault {
+            if min > max {
+                return Err(VPFSError::Other(format!("Delay of {min} to {max} milliseconds is empty")));
+            }
         }
     }
-    else {
-        None
-    };
-    if let Some(file_owner_connection) = stream_for(&location.node, state) {
-        let mut file_owner_connection = file_owner_connection.lock().unwrap();
-        send_message(&mut file_owner_connection, DaemonRequest::Read(location.uri.clone(), cache_last_update_time));
+    println!("Injecting faults: {:?}", plan);
+    *state.faults.lock().unwrap() = plan;
+    // Connections made before a partition would otherwise keep working
+    let partitioned: Vec<Node> = state.connections.lock().unwrap().keys().filter(|node| is_partitioned(node, state)).cloned().collect();
+    for node in partitioned {
+        drop_connection(&node, state);
+    }
+    Ok(())
+}
 
-        match receive_message(&mut file_owner_connection) {
-            Ok(DaemonResponse::Read(Ok(file_len))) => {
-                let mut buf = vec![0u8; file_len];
-                file_owner_connection.read_exact(&mut buf);
+fn is_partitioned(node: &Node, state: &Arc<DaemonState>) -> bool {
+    state.faults.lock().unwrap().rules.iter().any(|rule| rule.fault == Fault::Partition && rule.peer.as_ref().is_none_or(|peer| peer == node))
+}
 
-                add_cache_entry(location, &buf, &mut cache, state);
+fn fault_for(node: &Node, request: &str, state: &Arc<DaemonState>) -> Option<Fault> {
+    let faults = state.faults.lock().unwrap();
+    faults.rules.iter().find(|rule| {
+        rule.peer.as_ref().is_none_or(|peer| peer == node) && rule.request.as_deref().is_none_or(|name| name == request)
+    }).map(|rule| rule.fault)
+}
 
-                Ok(buf)
-            },
-            Ok(DaemonResponse::Read(Err(VPFSError::NotModified))) => {
-                Ok(fs::read(&cache_entry.unwrap().uri).expect("Missing file for cache entry"))
-            }
-            Ok(DaemonResponse::Read(Err(error))) => {
-                Err(error)
-            },
-            Ok(_) => panic!("Bad responce"),
-            Err(_) => {
-                todo!("Check if error came from bad responce, or from connection closing")
+// Every exchange with another daemon takes the lock on its connection through here. A delay
+// injected into any of the exchange's requests is slept out before taking the lock, so it does
+// not hold up other requests to the same peer
+fn lock_connection<'a>(connection: &'a Mutex<Connection>, node: &Node, requests: &[&str], state: &Arc<DaemonState>) -> MutexGuard<'a, Connection> {
+    let delay = requests.iter().filter_map(|request| match fault_for(node, request, state) {
+        Some(Fault::Delay(min, max)) => Some(state.rng.lock().unwrap().random_range(min..=max)),
+        _ => None,
+    }).max();
+    if let Some(delay) = delay {
+        sleep(Duration::from_millis(delay));
+    }
+    connection.lock().unwrap()
+}
+
+// Every request to another daemon is sent through here. A connection closed by a fault is
+// noticed by the caller when it reads the response, the same way as a real network failure
+fn send_request(connection: &mut Connection, node: &Node, request: DaemonRequest, state: &Arc<DaemonState>) {
+    match fault_for(node, request.name(), state) {
+        Some(Fault::Partition | Fault::Disconnect) => {
+            let _ = connection.shutdown();
+        }
+        // Already slept out by lock_connection
+        Some(Fault::Delay(..)) | None => send_message(connection, request),
+    }
+}
+
+fn check_disk_write(state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if state.faults.lock().unwrap().fail_writes {
+        return Err(VPFSError::Other("Injected disk write failure".to_string()));
+    }
+    Ok(())
+}
+
+/* --------------------------------- Authentication --------------------------------- */
+// Mutual challenge-response over a pre-shared cluster key. The accepting daemon challenges the
+// connecting daemon with a nonce, and answers the connecting daemon's nonce in return, so
+// neither side learns anything it could replay on another connection
+const NONCE_LEN: usize = 32;
+
+pub(crate) fn new_nonce() -> Vec<u8> {
+    let mut nonce = vec![0u8; NONCE_LEN];
+    rand::rng().fill(&mut nonce[..]);
+    nonce
+}
+
+pub(crate) fn auth_mac(cluster_key: &[u8], role: &[u8], challenge: &[u8], nonce: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(cluster_key).expect("HMAC accepts keys of any length");
+    mac.update(role);
+    mac.update(challenge);
+    mac.update(nonce);
+    mac
+}
+
+// Key for one direction of an encrypted link, so the two directions never share nonces
+fn session_key(cluster_key: &[u8], direction: &[u8], challenge: &[u8], nonce: &[u8]) -> [u8; 32] {
+    auth_mac(cluster_key, direction, challenge, nonce).finalize().into_bytes().into()
+}
+
+pub(crate) fn accepting_role(encrypt: bool) -> &'static [u8] {
+    if encrypt {b"accepting encrypted"} else {b"accepting"}
+}
+
+// Checks that the final response to the hello picked a protocol version the local node speaks
+fn check_hello_response(hello: &Hello, response: HelloResponse) -> Result<HelloResponse, String> {
+    match response.negotiated() {
+        Some(negotiated) => hello.protocol().accepts(negotiated).map(|_| response),
+        None => Err("Got bad hello response".to_string()),
+    }
+}
+
+// Sends a hello to another daemon and receives its response, first answering the challenge if
+// the peer sends one. The returned connection is encrypted if the cluster encrypts its links
+fn daemon_handshake(mut stream: Connection, hello: Hello, state: &Arc<DaemonState>) -> Result<(Connection, HelloResponse), String> {
+    send_unframed(&mut stream, &hello);
+    let response = receive_unframed::<HelloResponse>(&mut stream, Duration::from_millis(0)).map_err(|_| "Could not understand hello response, the peer may speak an incompatible protocol version".to_string())?;
+    let (HelloResponse::Challenge(challenge), Some(cluster_key)) = (&response, &state.cluster_key) else {
+        return match response {
+            HelloResponse::Challenge(_) => Err("Peer requires a cluster key".to_string()),
+            HelloResponse::Rejected(reason) => Err(reason),
+            HelloResponse::Busy => Err("Peer is serving too many connections".to_string()),
+            _ if state.cluster_key.is_some() => Err("Peer did not authenticate".to_string()),
+            response => finish_handshake(stream, &hello, response),
+        };
+    };
+    let nonce = new_nonce();
+    let proof = auth_mac(cluster_key, b"connecting", challenge, &nonce).finalize().into_bytes().to_vec();
+    send_unframed(&mut stream, ChallengeResponse { nonce: nonce.clone(), proof });
+    match receive_unframed::<HelloResponse>(&mut stream, Duration::from_millis(0)) {
+        Ok(HelloResponse::Authenticated(peer_proof, encrypt)) => {
+            if encrypt != state.config.encrypt {
+                return Err("Peer disagrees on whether links are encrypted".to_string());
             }
+            auth_mac(cluster_key, accepting_role(encrypt), &nonce, challenge).verify_slice(&peer_proof).map_err(|_| "Peer does not hold the cluster key".to_string())?;
         }
+        Ok(HelloResponse::Rejected(reason)) => return Err(reason),
+        _ => return Err("Got bad authentication response".to_string()),
+    }
+    if state.config.encrypt {
+        let send_key = session_key(cluster_key, b"connecting key", challenge, &nonce);
+        let receive_key = session_key(cluster_key, b"accepting key", challenge, &nonce);
+        stream = Connection::new(EncryptedStream::new(stream, &send_key, &receive_key));
+    }
+    let response = receive_unframed::<HelloResponse>(&mut stream, Duration::from_millis(0)).map_err(|_| "Got bad hello response".to_string())?;
+    finish_handshake(stream, &hello, response)
+}
+
+// Applies the options negotiated in the hello to the connection
+fn finish_handshake(mut stream: Connection, hello: &Hello, response: HelloResponse) -> Result<(Connection, HelloResponse), String> {
+    let response = check_hello_response(hello, response)?;
+    stream.protocol = response.negotiated().cloned();
+    Ok((stream, response))
+}
+
+// Challenges a connecting daemon, or a user process holding the cluster key. Returns None, after
+// telling the peer why, if it is rejected
+fn authenticate_peer(mut stream: Connection, encrypt: bool, state: &Arc<DaemonState>) -> Option<Connection> {
+    let Some(cluster_key) = &state.cluster_key else {
+        return Some(stream);
+    };
+    let challenge = new_nonce();
+    send_unframed(&mut stream, HelloResponse::Challenge(challenge.clone()));
+    let response = receive_unframed::<ChallengeResponse>(&mut stream, Duration::from_millis(0)).ok()?;
+    if auth_mac(cluster_key, b"connecting", &challenge, &response.nonce).verify_slice(&response.proof).is_err() {
+        send_unframed(&mut stream, HelloResponse::Rejected("Wrong cluster key".to_string()));
+        return None;
+    }
+    let proof = auth_mac(cluster_key, accepting_role(encrypt), &response.nonce, &challenge).finalize().into_bytes().to_vec();
+    send_unframed(&mut stream, HelloResponse::Authenticated(proof, encrypt));
+    if encrypt {
+        let send_key = session_key(cluster_key, b"accepting key", &challenge, &response.nonce);
+        let receive_key = session_key(cluster_key, b"connecting key", &challenge, &response.nonce);
+        Some(Connection::new(EncryptedStream::new(stream, &send_key, &receive_key)))
     }
     else {
-        if let Some(cache_entry) =  cache_entry{
-            let cache_entry_location = Location {
-                node: state.local.clone(),
-                uri: cache_entry.uri.clone()
-            };
-            Err(VPFSError::OnlyInCache(cache_entry_location))
-        }
-        else {
-            Err(VPFSError::NotAccessible)
-        }
+        Some(stream)
+    }
+}
+
+/* ---------------------------- Membership and heartbeats -------------------------- */
+// Nodes that have not been heartbeated yet are assumed to be reachable
+fn new_node_status(node: &Node) -> NodeStatus {
+    NodeStatus {
+        node: node.clone(),
+        address: None,
+        reachable: true,
+        last_seen: None,
+        round_trip_time: None,
     }
 }
 
-fn write_local(uri: &str,  data: &Vec<u8>, fs_lock: &RwLock<()>) -> io::Result<()>{
-    fs_lock.write().unwrap();
-    if fs::exists(uri)? {
-        fs::write(uri, data)
+fn is_known_down(node: &Node, state: &Arc<DaemonState>) -> bool {
+    let node_status = state.node_status.lock().unwrap();
+    node_status.get(node).is_some_and(|status| !status.reachable)
+}
+
+fn mark_reachable(node: &Node, round_trip_time: Duration, state: &Arc<DaemonState>) {
+    let mut node_status = state.node_status.lock().unwrap();
+    let status = node_status.entry(node.clone()).or_insert_with(|| new_node_status(node));
+    status.reachable = true;
+    status.last_seen = Some(state.clock.now());
+    status.round_trip_time = Some(round_trip_time);
+}
+
+fn mark_unreachable(node: &Node, state: &Arc<DaemonState>) {
+    let mut node_status = state.node_status.lock().unwrap();
+    let status = node_status.entry(node.clone()).or_insert_with(|| new_node_status(node));
+    status.reachable = false;
+}
+
+// Send a heartbeat to a node, bypassing the fail fast check in stream_for so down nodes can come back
+fn probe_node(node: &Node, state: &Arc<DaemonState>) {
+    let start = Instant::now();
+    let alive = if let Some(connection) = connect_to(node, state) {
+        let mut connection = lock_connection(&connection, node, &["Heartbeat"], state);
+        let _ = connection.set_read_timeout(Some(state.heartbeat_interval.max(state.connect_timeout)));
+        send_request(&mut connection, node, DaemonRequest::Heartbeat, state);
+        let response = receive_message::<DaemonResponse>(&mut connection);
+        let _ = connection.set_read_timeout(None);
+        matches!(response, Ok(DaemonResponse::Heartbeat))
+    }
+    else {
+        false
+    };
+    if alive {
+        mark_reachable(node, start.elapsed(), state);
     }
     else {
-        Err(io::Error::from(io::ErrorKind::NotFound))
+        drop_connection(node, state);
+        mark_unreachable(node, state);
     }
 }
 
-fn create_file_with_random_uri() -> String {
-    let mut rng = rand::rng();
-    let mut uri = format!("{:x}", rng.random::<u64>());
-    loop {
-        if let Err(error) = fs::File::create_new(&uri) {
-            if error.kind() != io::ErrorKind::AlreadyExists {
-                panic!("Could not create file"); // TODO better error handleing
+fn heartbeat_round(state: &Arc<DaemonState>) {
+    for node in remote_nodes(state) {
+        probe_node(&node, state);
+    }
+    sync_with_root(state);
+    gossip(state);
+}
+
+// Sleeps by parking, so that shutting down can wake it
+fn heartbeat_loop(state: Arc<DaemonState>) {
+    while !is_shutting_down(&state) {
+        heartbeat_round(&state);
+        thread::park_timeout(state.heartbeat_interval);
+    }
+}
+
+fn start_heartbeats(state: &Arc<DaemonState>) -> JoinHandle<()> {
+    let state = state.clone();
+    thread::spawn(move || heartbeat_loop(state))
+}
+
+fn cluster_view(state: &Arc<DaemonState>) -> Vec<NodeStatus> {
+    let known_hosts = state.known_hosts.lock().unwrap().clone();
+    let node_status = state.node_status.lock().unwrap();
+    let mut nodes: Vec<NodeStatus> = known_hosts.iter()
+        .filter(|(node, entry)| **node != state.local && !entry.removed)
+        .map(|(node, entry)| {
+            let mut status = node_status.get(node).cloned().unwrap_or_else(|| new_node_status(node));
+            status.address = Some(entry.address.clone());
+            status
+        })
+        .collect();
+    nodes.push(NodeStatus {
+        node: state.local.clone(),
+        address: state.local_entry.as_ref().map(|entry| entry.address.clone()),
+        reachable: true,
+        last_seen: Some(state.clock.now()),
+        round_trip_time: Some(Duration::from_millis(0)),
+    });
+    nodes.sort_by(|a, b| a.node.name.cmp(&b.node.name));
+    nodes
+}
+
+/* -------------------------------- Peer discovery ---------------------------------- */
+// Number of peers each daemon exchanges its known hosts with after every round of heartbeats
+const GOSSIP_FANOUT: usize = 2;
+
+fn remote_nodes(state: &Arc<DaemonState>) -> Vec<Node> {
+    let known_hosts = state.known_hosts.lock().unwrap();
+    let mut nodes: Vec<Node> = known_hosts.iter().filter(|(node, entry)| **node != state.local && !entry.removed).map(|(node, _)| node.clone()).collect();
+    // In a stable order, so a seeded daemon picks the same peers every run
+    nodes.sort_by(|a, b| a.name.cmp(&b.name));
+    nodes
+}
+
+// Generation for the local node's entry. Restarting the daemon always issues a newer one
+fn new_generation(clock: &dyn Clock) -> u64 {
+    clock.now().duration_since(UNIX_EPOCH).expect("System clock before unix epoch").as_millis() as u64
+}
+
+// Known hosts as shared with peers, including the local node's own entry
+fn known_hosts_with_local(state: &Arc<DaemonState>) -> HashMap<Node, HostEntry> {
+    let mut known_hosts = state.known_hosts.lock().unwrap().clone();
+    if let Some(local_entry) = &state.local_entry {
+        known_hosts.insert(state.local.clone(), local_entry.clone());
+    }
+    known_hosts
+}
+
+// Take every entry that is newer than the one already known. Cached connections to nodes whose
+// address changed are dropped, so the next request connects to the new address
+fn merge_known_hosts(hosts: HashMap<Node, HostEntry>, state: &Arc<DaemonState>) {
+    let mut moved_nodes = vec![];
+    let mut changed = false;
+    {
+        let mut known_hosts = state.known_hosts.lock().unwrap();
+        for (node, entry) in hosts {
+            if node == state.local {
+                continue;
+            }
+            match known_hosts.get(&node) {
+                Some(known_entry) if known_entry.generation >= entry.generation => {}
+                known_entry => {
+                    if entry.removed {
+                        println!("{} has been removed from the cluster", node.name);
+                        moved_nodes.push(node.clone());
+                    }
+                    else if known_entry.is_some_and(|known_entry| known_entry.address != entry.address) {
+                        println!("{} is now listening on {}", node.name, entry.address);
+                        moved_nodes.push(node.clone());
+                    }
+                    known_hosts.insert(node, entry);
+                    changed = true;
+                }
             }
-            uri = format!("{:x}", rng.random::<u64>());
-        }
-        else {
-            break;
         }
     }
-    uri
+    for node in moved_nodes {
+        drop_connection(&node, state);
+        state.node_status.lock().unwrap().remove(&node);
+    }
+    if changed {
+        save_state(state);
+    }
 }
 
-fn search_directory_with_reader<T: Read>(file_name: &str, directory_reader: &mut T) -> Result<DirectoryEntry, VPFSError> {
-    let mut read_result: Result<DirectoryEntry, serde_bare::error::Error> = serde_bare::from_reader(&mut *directory_reader);
-    let mut dir_entry = Err(VPFSError::DoesNotExist);
-    while let Ok(entry) = read_result {
-        if entry.name == file_name{
-            dir_entry = Ok(entry);
-            break;
+// Exchange known hosts with a few random reachable peers, so daemons keep finding each
+// other when the root is unreachable
+fn gossip(state: &Arc<DaemonState>) {
+    let peers: Vec<Node> = remote_nodes(state).into_iter().filter(|node| !is_known_down(node, state)).collect();
+    let peers: Vec<Node> = peers.choose_multiple(&mut *state.rng.lock().unwrap(), GOSSIP_FANOUT).cloned().collect();
+    for peer in peers {
+        match send_and_recive(&peer, DaemonRequest::Gossip(known_hosts_with_local(state)), state) {
+            Ok(DaemonResponse::Gossip(peer_known_hosts)) => merge_known_hosts(peer_known_hosts, state),
+            Ok(_) => {}
+            Err(_) => {
+                drop_connection(&peer, state);
+                continue;
+            }
         }
-        read_result = serde_bare::from_reader(&mut *directory_reader);
+        exchange_root_epoch(&peer, state);
     }
-    dir_entry
 }
 
-//Assumes caller hold file lock
-fn search_directory_with_lock(file_name: &str, directory_uri: &str) -> Result<DirectoryEntry, VPFSError> {
-    let mut directory_file = fs::File::open(directory_uri).unwrap();
-    search_directory_with_reader(file_name, &mut directory_file)
+/* --------------------------------- Standby roots ---------------------------------- */
+fn current_root(state: &Arc<DaemonState>) -> Option<Node> {
+    state.root.read().unwrap().clone()
 }
 
-fn search_directory(file_name: &str, directory_uri: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
-    let _file_access_lock = state.file_access_lock.read().unwrap();
-    search_directory_with_lock(file_name, directory_uri)
+// Takes a root with a higher epoch than the current one. A root that hears of a newer root was cut
+// off while the rest of the cluster failed over, so it steps down. Two standbys promoted apart from
+// each other can reach the same epoch, then the one with the greater name wins on every node
+fn adopt_root(root_node: Node, epoch: u64, state: &Arc<DaemonState>) {
+    {
+        let mut root = state.root.write().unwrap();
+        let mut root_epoch = state.root_epoch.lock().unwrap();
+        if (epoch, Some(&root_node.name)) <= (*root_epoch, root.as_ref().map(|root| &root.name)) {
+            return;
+        }
+        if root.as_ref() == Some(&state.local) && root_node != state.local {
+            println!("{} became the root in epoch {epoch}, {} steps down", root_node.name, state.local.name);
+        }
+        else if root.as_ref() != Some(&root_node) {
+            println!("{} is now the root node", root_node.name);
+        }
+        *root = Some(root_node);
+        *root_epoch = epoch;
+    }
+    *state.root_missed_heartbeats.lock().unwrap() = 0;
+    save_state(state);
 }
 
-fn append_dir_entry(directory: &str, new_entry: &DirectoryEntry, state: &Arc<DaemonState>) -> Result<(), VPFSError>{
-    // Check if the directory entry already exists
-    let _fs_lock = state.file_access_lock.write().unwrap();
-    if let Ok(existing_dir_entry) = search_directory_with_lock(&new_entry.name, &directory) {
-        Err(VPFSError::AlreadyExists(existing_dir_entry))
+// Tell a peer which root this node follows and take the peer's if it is newer
+fn exchange_root_epoch(peer: &Node, state: &Arc<DaemonState>) {
+    let Some(root_node) = current_root(state) else {
+        return;
+    };
+    let epoch = *state.root_epoch.lock().unwrap();
+    if let Some(Ok(DaemonResponse::RootEpoch(Some(peer_root), peer_epoch))) = send_if_spoken(peer, ROOT_EPOCH_VERSION, DaemonRequest::RootEpoch(root_node, epoch), state) {
+        adopt_root(peer_root, peer_epoch, state);
     }
-    else {
-        let dir_file = fs::OpenOptions::new().append(true).open(directory).unwrap();
-        serde_bare::to_writer(dir_file, &new_entry).unwrap();
-        Ok(())
+}
+
+// Only fail over when a majority of the other nodes, counting this one, sees the root as down.
+// Otherwise a node cut off from the root alone would promote a second root. The root itself can not
+// vote, so a standby on its own with the root always fails over. Peers older than
+// ROOT_EPOCH_VERSION can not be asked and count against failing over
+fn majority_sees_root_down(root_node: &Node, state: &Arc<DaemonState>) -> bool {
+    let peers = remote_nodes(state);
+    let cluster_size = peers.iter().filter(|peer| *peer != root_node).count() + 1;
+    let mut votes = 1;
+    for peer in peers {
+        if peer == *root_node || is_known_down(&peer, state) {
+            continue;
+        }
+        if let Some(Ok(DaemonResponse::RootDown(true))) = send_if_spoken(&peer, ROOT_EPOCH_VERSION, DaemonRequest::RootDown(root_node.clone()), state) {
+            votes += 1;
+        }
     }
+    votes > cluster_size / 2
+}
+
+fn is_standby(state: &Arc<DaemonState>) -> bool {
+    current_root(state).as_ref() != Some(&state.local) && state.standbys.lock().unwrap().contains(&state.local)
 }
 
-fn add_cache_entry(location: &Location, data: &[u8], cache: &mut MutexGuard<LruCache<Location, CacheEntry>>, state: &Arc<DaemonState>) {
-    if let Some(cache_entry) = cache.get(&location) {
-        fs::write(&cache_entry.uri, &data);
+// The root directory always has the uri "root", but the node holding it changes after a fail over
+fn redirect_root(mut dir_entry: DirectoryEntry, state: &Arc<DaemonState>) -> DirectoryEntry {
+    if dir_entry.location.uri == "root" {
+        if let Some(root_node) = current_root(state) {
+            dir_entry.location.node = root_node;
+        }
     }
-    else {
-        let new_cache_entry = CacheEntry {
-            uri: create_file_with_random_uri(),
-        };
-        fs::write(&new_cache_entry.uri, &data);
-        cache.put(location.clone(), new_cache_entry);
+    dir_entry
+}
+
+// Called after every round of heartbeats. Refreshes the standby list from the root, keeps the
+// replica of the root directory up to date on standbys, and fails over once the root is down
+fn sync_with_root(state: &Arc<DaemonState>) {
+    let Some(root_node) = current_root(state) else {
+        return;
     };
-    let mut used_cache = state.used_cache_bytes.write().unwrap();
-    *used_cache += data.len();
-    // Evict elements to make room in cache
-    while *used_cache > state.max_cache_size {
-        if let Some((_, lru_entry)) = cache.pop_lru() {
-            let file_size = fs::metadata(&lru_entry.uri).expect("Cache entry missing backing file").len();
-            fs::remove_file(&lru_entry.uri).unwrap();
-            *used_cache -= file_size as usize;
+    if root_node == state.local {
+        return;
+    }
+    if is_known_down(&root_node, state) {
+        let mut missed_heartbeats = state.root_missed_heartbeats.lock().unwrap();
+        *missed_heartbeats += 1;
+        if *missed_heartbeats >= state.config.failover_after {
+            *missed_heartbeats = 0;
+            drop(missed_heartbeats);
+            if majority_sees_root_down(&root_node, state) {
+                fail_over(&root_node, state);
+            }
+            else {
+                eprintln!("Root {} is down, but not for a majority of the cluster", root_node.name);
+            }
         }
-        else {
-            break;
+        return;
+    }
+    *state.root_missed_heartbeats.lock().unwrap() = 0;
+    match send_and_recive(&root_node, DaemonRequest::ClusterInfo, state) {
+        Ok(DaemonResponse::ClusterInfo(root_known_hosts, standbys)) => {
+            merge_known_hosts(root_known_hosts, state);
+            let mut known_standbys = state.standbys.lock().unwrap();
+            if *known_standbys != standbys {
+                *known_standbys = standbys;
+                drop(known_standbys);
+                save_state(state);
+            }
         }
+        _ => return,
     }
-    let cache_file = fs::File::create("cache").expect("Failed to create cache file");
-    serde_bare::to_writer(&cache_file, &state.root).expect("Failed to save root node to file");
-    serde_bare::to_writer(&cache_file, &*used_cache).expect("Failed to save cahce size to file");
-    for (key, value) in cache.iter() {
-        serde_bare::to_writer(&cache_file, key).expect("Could not write cache entry to file");
-        serde_bare::to_writer(&cache_file, value).expect("Could not write cache entry to file");
+    if is_standby(state) {
+        replicate_root_directory(&root_node, state);
     }
 }
 
-fn restore_cache(state: &mut DaemonState) {
-    if let Ok(cache_file) = fs::File::open("cache") {
-        let mut cache = state.cache.lock().unwrap();
-        state.root = serde_bare::from_reader(&cache_file).expect("Failed to readed from cache file");
-        state.used_cache_bytes = serde_bare::from_reader(&cache_file).expect("Failed to readed from cache file");
-        while let Ok(key) = serde_bare::from_reader::<_, Location>(&cache_file) {
-            let value = serde_bare::from_reader(&cache_file).unwrap();
-            cache.put(key.clone(), value);
-            cache.demote(&key);
+// Copy the root directory from the root node into the local file "root", if it has changed. The
+// replica is fetched before taking the file lock, and with a timeout like a heartbeat, so a root
+// that stalls holds up neither the heartbeats nor the standby's own reads and writes
+fn replicate_root_directory(root_node: &Node, state: &Arc<DaemonState>) {
+    let Some(root_connection) = stream_for(root_node, state) else {
+        return;
+    };
+    let replica_last_modified = state.storage.modified("root").ok();
+    let mut root_connection = lock_connection(&root_connection, root_node, &["Read"], state);
+    let _ = root_connection.set_read_timeout(Some(state.heartbeat_interval.max(state.connect_timeout)));
+    send_request(&mut root_connection, root_node, DaemonRequest::Read("root".to_string(), replica_last_modified, None), state);
+    let replica = match receive_message(&mut root_connection) {
+        Ok(DaemonResponse::Read(Ok((file_len, _, hash)))) => receive_data(&mut root_connection, file_len).map(|buf| Some((buf, hash))),
+        Ok(_) => Ok(None),
+        Err(error) => Err(error),
+    };
+    let _ = root_connection.set_read_timeout(None);
+    drop(root_connection);
+    match replica {
+        Ok(Some((buf, hash))) if content_hash(&buf) == hash => {
+            let _fs_lock = state.file_access_lock.write().unwrap();
+            match state.storage.write("root", &buf) {
+                Ok(()) => set_content_hash("root", hash, state),
+                Err(error) => eprintln!("Could not write root directory replica: {error}"),
+            }
         }
+        Ok(Some(_)) => eprintln!("Root directory replica from {} is corrupted, keeping the old one", root_node.name),
+        Ok(None) => {}
+        Err(_) => drop_connection(root_node, state),
     }
 }
 
-/* ------------------- User process connection handler functions ------------------- */
-fn recursive_find(file: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
+// Hand the root role to the first standby that is still reachable. Every daemon walks the
+// standby list in the same order, so they all agree on the new root without coordinating
+fn fail_over(failed_root: &Node, state: &Arc<DaemonState>) {
+    let standbys = state.standbys.lock().unwrap().clone();
+    for standby in standbys {
+        if standby == *failed_root {
+            continue;
+        }
+        if standby == state.local {
+            if promote_to_root(state).is_ok() {
+                return;
+            }
+            continue;
+        }
+        if is_known_down(&standby, state) {
+            continue;
+        }
+        if let Ok(DaemonResponse::PromoteToRoot(Ok(()))) = send_and_recive(&standby, DaemonRequest::PromoteToRoot, state) {
+            println!("Root {} is down, failed over to {}", failed_root.name, standby.name);
+            // Standbys announce themselves along with their epoch, this is only needed for those
+            // older than ROOT_EPOCH_VERSION
+            let mut root = state.root.write().unwrap();
+            if root.as_ref() == Some(failed_root) {
+                *root = Some(standby);
+                drop(root);
+                save_state(state);
+            }
+            return;
+        }
+    }
+    eprintln!("Root {} is down and no standby could take over", failed_root.name);
+}
+
+fn promote_to_root(state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if current_root(state).as_ref() == Some(&state.local) {
+        return Ok(());
+    }
+    if !is_standby(state) {
+        return Err(VPFSError::Other("Node is not a standby root".to_string()));
+    }
+    let epoch = *state.root_epoch.lock().unwrap() + 1;
+    println!("Promoting {} to root in epoch {epoch}", state.local.name);
+    state.standbys.lock().unwrap().retain(|node| *node != state.local);
+    adopt_root(state.local.clone(), epoch, state);
+    // A standby that never completed a sync starts with an empty root directory
+    create_root_directory(state);
+    save_state(state);
+
+    for node in remote_nodes(state) {
+        let announced = send_if_spoken::<DaemonResponse>(&node, ROOT_EPOCH_VERSION, DaemonRequest::RootEpoch(state.local.clone(), epoch), state);
+        if announced.is_none() {
+            let _ = send_and_recive::<DaemonResponse>(&node, DaemonRequest::NewRoot(state.local.clone()), state);
+        }
+    }
+    Ok(())
+}
+
+fn read_local(uri: &str, state: &Arc<DaemonState>) -> io::Result<Vec<u8>>{
+    let _fs_lock = state.file_access_lock.read().unwrap();
+    read_stored(uri, state)
+}
+
+// Reads on behalf of a user are checked against the file's permissions, both by the owner and
+// before falling back to a cached copy. No lock is held while waiting on the owner, which may be
+// reading a file from this node at the same time
+fn read_remote(location: &Location, user: Option<&str>, state: &Arc<DaemonState>) -> Result<Vec<u8>, VPFSError> {
+    let (cached_data, cache_last_update_time) = {
+        let mut cache = state.cache.lock().unwrap();
+        let _fs_lock = state.file_access_lock.write().unwrap();
+        let cached_data = intact_cached_copy(location, &mut cache, state);
+        let cache_last_update_time = if let (Some(_), Some(cache_entry)) = (&cached_data, cache.peek(location)) {
+            state.storage.modified(&cache_entry.uri).ok()
+        }
+        else {
+            None
+        };
+        (cached_data, cache_last_update_time)
+    };
+    if let Some(file_owner_connection) = stream_for(&location.node, state) {
+        let fetch_result = {
+            let mut file_owner_connection = lock_connection(&file_owner_connection, &location.node, &["Read", "ReadChunks", "FetchChunks"], state);
+            if file_owner_connection.supports(CHUNKS) {
+                fetch_chunked(&mut file_owner_connection, location, cache_last_update_time, user, cached_data.as_deref(), state)
+            }
+            else {
+                fetch_whole(&mut file_owner_connection, location, cache_last_update_time, user, state)
+            }
+        };
+
+        match fetch_result {
+            Ok(Ok((buf, permissions))) => {
+                let mut cache = state.cache.lock().unwrap();
+                let _fs_lock = state.file_access_lock.write().unwrap();
+                add_cache_entry(location, &buf, permissions, &mut cache, state);
+                return Ok(buf);
+            },
+            Ok(Err(VPFSError::NotModified)) => {
+                return Ok(cached_data.expect("Missing file for cache entry"));
+            }
+            Ok(Err(error)) => {
+                return Err(error);
+            },
+            Err(FrameError::Io(_)) => {
+                // Connection was lost mid request, treat the owner as down until the next heartbeat
+                drop_connection(&location.node, state);
+                mark_unreachable(&location.node, state);
+            }
+            Err(error) => return Err(VPFSError::BadFrame(error.to_string())),
+        }
+    }
+    let cache = state.cache.lock().unwrap();
+    if let Some(cache_entry) =  cache.peek(location) {
+        if let Some(user) = user {
+            check_access(&cache_entry.uri, user, Access::Read, state)?;
+        }
+        let cache_entry_location = Location {
+            node: state.local.clone(),
+            uri: cache_entry.uri.clone()
+        };
+        Err(VPFSError::OnlyInCache(cache_entry_location))
+    }
+    else {
+        Err(VPFSError::NotAccessible)
+    }
+}
+
+fn remove_local(uri: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let _fs_lock = state.file_access_lock.write().unwrap();
+    let file_len = stored_len(uri, state);
+    if state.storage.remove(uri).is_ok() {
+        release_space(file_len, state);
+        set_manifest(uri, None, state);
+        remove_permissions(uri, state);
+        remove_content_hash(uri, state);
+        let mut placement_policies = state.placement_policies.lock().unwrap();
+        if placement_policies.remove(uri).is_some() {
+            save_metadata_change(PLACEMENT_FILE, uri, &placement_policies, &*state.storage);
+        }
+        Ok(())
+    }
+    else {
+        Err(VPFSError::DoesNotExist)
+    }
+}
+
+fn write_local(uri: &str,  data: &[u8], state: &Arc<DaemonState>) -> Result<(), VPFSError>{
+    check_disk_write(state)?;
+    let _fs_lock = state.file_access_lock.write().unwrap();
+    replace_contents(uri, data.len() as u64, || {
+        if state.config.chunked {
+            let chunks = split_into_chunks(data);
+            store_chunks(chunks.iter().map(|(chunk, chunk_data)| (chunk, *chunk_data)), state)?;
+            state.storage.write(uri, &[])?;
+            Ok(Some(chunks.into_iter().map(|(chunk, _)| chunk).collect()))
+        }
+        else {
+            state.storage.write(uri, data)?;
+            Ok(None)
+        }
+    }, state)?;
+    set_content_hash(uri, content_hash(data), state);
+    Ok(())
+}
+
+// Replaces the contents of an existing file with new_len bytes written by store, which returns the
+// file's manifest if it stored the file in chunks. Assumes caller holds the file access lock
+fn replace_contents(uri: &str, new_len: u64, store: impl FnOnce() -> io::Result<Option<Vec<ChunkRef>>>, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if state.storage.len(uri).is_err() {
+        return Err(VPFSError::DoesNotExist);
+    }
+    let old_len = stored_len(uri, state);
+    if new_len > old_len {
+        reserve_space(new_len - old_len, state)?;
+    }
+    if let Ok(manifest) = store() {
+        set_manifest(uri, manifest, state);
+        if new_len < old_len {
+            release_space(old_len - new_len, state);
+        }
+        Ok(())
+    }
+    else {
+        if new_len > old_len {
+            release_space(new_len - old_len, state);
+        }
+        Err(VPFSError::DoesNotExist)
+    }
+}
+
+fn create_file_with_random_uri(state: &Arc<DaemonState>) -> String {
+    let mut rng = state.rng.lock().unwrap();
+    let mut uri = format!("{:x}", rng.random::<u64>());
+    while let Err(error) = state.storage.create_new(&uri) {
+        if error.kind() != io::ErrorKind::AlreadyExists {
+            panic!("Could not create file"); // TODO better error handleing
+        }
+        uri = format!("{:x}", rng.random::<u64>());
+    }
+    drop(rng);
+    set_content_hash(&uri, content_hash(&[]), state);
+    uri
+}
+
+fn search_directory_with_reader<T: Read>(file_name: &str, directory_reader: &mut T) -> Result<DirectoryEntry, VPFSError> {
+    let mut read_result: Result<DirectoryEntry, serde_bare::error::Error> = serde_bare::from_reader(&mut *directory_reader);
+    let mut dir_entry = Err(VPFSError::DoesNotExist);
+    while let Ok(entry) = read_result {
+        if entry.name == file_name{
+            dir_entry = Ok(entry);
+            break;
+        }
+        read_result = serde_bare::from_reader(&mut *directory_reader);
+    }
+    dir_entry
+}
+
+//Assumes caller hold file lock
+fn search_directory_with_lock(file_name: &str, directory_uri: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
+    let directory = state.storage.read(directory_uri).unwrap();
+    search_directory_with_reader(file_name, &mut directory.as_slice())
+}
+
+fn search_directory(file_name: &str, directory_uri: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
+    let _file_access_lock = state.file_access_lock.read().unwrap();
+    search_directory_with_lock(file_name, directory_uri, state)
+}
+
+fn append_dir_entry(directory: &str, new_entry: &DirectoryEntry, state: &Arc<DaemonState>) -> Result<(), VPFSError>{
+    check_disk_write(state)?;
+    // Check if the directory entry already exists
+    let _fs_lock = state.file_access_lock.write().unwrap();
+    let mut directory_data = state.storage.read(directory).unwrap();
+    if let Ok(existing_dir_entry) = search_directory_with_reader(&new_entry.name, &mut directory_data.as_slice()) {
+        Err(VPFSError::AlreadyExists(existing_dir_entry))
+    }
+    else {
+        let entry_bytes = serde_bare::to_vec(new_entry).unwrap();
+        reserve_space(entry_bytes.len() as u64, state)?;
+        state.storage.append(directory, &entry_bytes).unwrap();
+        directory_data.extend(entry_bytes);
+        set_content_hash(directory, content_hash(&directory_data), state);
+        Ok(())
+    }
+}
+
+// Cached copies keep the permissions of the original, so they are enforced while the owner is unreachable
+fn add_cache_entry(location: &Location, data: &[u8], permissions: Option<Permissions>, cache: &mut MutexGuard<LruCache<Location, CacheEntry>>, state: &Arc<DaemonState>) {
+    let cache_uri = if let Some(cache_entry) = cache.get(location) {
+        let _ = state.storage.write(&cache_entry.uri, data);
+        cache_entry.uri.clone()
+    }
+    else {
+        let new_cache_entry = CacheEntry {
+            uri: create_file_with_random_uri(state),
+        };
+        let _ = state.storage.write(&new_cache_entry.uri, data);
+        let cache_uri = new_cache_entry.uri.clone();
+        cache.put(location.clone(), new_cache_entry);
+        cache_uri
+    };
+    match permissions {
+        Some(permissions) => set_permissions(&cache_uri, permissions, state),
+        None => remove_permissions(&cache_uri, state),
+    }
+    set_content_hash(&cache_uri, content_hash(data), state);
+    let mut used_cache = state.used_cache_bytes.write().unwrap();
+    *used_cache += data.len();
+    // Evict elements to make room in cache
+    while *used_cache > state.max_cache_size {
+        if let Some((_, lru_entry)) = cache.pop_lru() {
+            let file_size = state.storage.len(&lru_entry.uri).expect("Cache entry missing backing file");
+            state.storage.remove(&lru_entry.uri).unwrap();
+            remove_permissions(&lru_entry.uri, state);
+            remove_content_hash(&lru_entry.uri, state);
+            *used_cache -= file_size as usize;
+        }
+        else {
+            break;
+        }
+    }
+    save_cache_index(cache, *used_cache, state);
+}
+
+fn save_cache_index(cache: &LruCache<Location, CacheEntry>, used_cache: usize, state: &Arc<DaemonState>) {
+    let mut cache_file = vec![];
+    serde_bare::to_writer(&mut cache_file, &*state.root.read().unwrap()).expect("Failed to save root node to file");
+    serde_bare::to_writer(&mut cache_file, &used_cache).expect("Failed to save cahce size to file");
+    for (key, value) in cache.iter() {
+        serde_bare::to_writer(&mut cache_file, key).expect("Could not write cache entry to file");
+        serde_bare::to_writer(&mut cache_file, value).expect("Could not write cache entry to file");
+    }
+    state.storage.write("cache", &cache_file).expect("Failed to create cache file");
+}
+
+// A cached copy that no longer matches the hash it was stored with is dropped, so that it is fetched
+// again from the owner
+fn intact_cached_copy(location: &Location, cache: &mut MutexGuard<LruCache<Location, CacheEntry>>, state: &Arc<DaemonState>) -> Option<Vec<u8>> {
+    let cache_uri = cache.get(location)?.uri.clone();
+    let data = state.storage.read(&cache_uri).ok()?;
+    if content_hash_for(&cache_uri, &data, state) == content_hash(&data) {
+        return Some(data);
+    }
+    eprintln!("Cached copy of {} on {} is corrupted, fetching it again", location.uri, location.node.name);
+    cache.pop(location);
+    let _ = state.storage.remove(&cache_uri);
+    remove_permissions(&cache_uri, state);
+    remove_content_hash(&cache_uri, state);
+    let mut used_cache = state.used_cache_bytes.write().unwrap();
+    *used_cache = used_cache.saturating_sub(data.len());
+    None
+}
+
+fn restore_cache(state: &mut DaemonState) {
+    if let Ok(cache_data) = state.storage.read("cache") {
+        let mut cache_file = cache_data.as_slice();
+        let mut cache = state.cache.lock().unwrap();
+        let cached_root: Option<Node> = serde_bare::from_reader(&mut cache_file).expect("Failed to readed from cache file");
+        // The state file is more up to date about the root, only fall back to the cache index
+        let root = state.root.get_mut().unwrap();
+        if root.is_none() {
+            *root = cached_root;
+        }
+        state.used_cache_bytes = serde_bare::from_reader(&mut cache_file).expect("Failed to readed from cache file");
+        while let Ok(key) = serde_bare::from_reader::<_, Location>(&mut cache_file) {
+            let value = serde_bare::from_reader(&mut cache_file).unwrap();
+            cache.put(key.clone(), value);
+            cache.demote(&key);
+        }
+    }
+}
+
+/* --------------------------------- Storage quota ---------------------------------- */
+// Files and directories stored on this node, cached copies of remote files are bounded by the
+// cache size instead
+fn stored_bytes(state: &DaemonState) -> u64 {
+    let cache = state.cache.lock().unwrap();
+    let cache_uris: Vec<&str> = cache.iter().map(|(_, cache_entry)| cache_entry.uri.as_str()).collect();
+    let mut used_bytes = 0;
+    for file_name in state.storage.list().expect("Could not list stored files") {
+        if cache_uris.contains(&file_name.as_str()) || file_name.starts_with(STATE_FILE) || file_name.starts_with(PERMISSIONS_FILE) || file_name.starts_with(PLACEMENT_FILE) || file_name.starts_with(HASHES_FILE) || file_name.starts_with(MANIFESTS_FILE) || file_name == "cache" {
+            continue;
+        }
+        used_bytes += state.storage.len(&file_name).unwrap_or(0);
+    }
+    // Files stored in chunks count with their full length, however many of their chunks are shared
+    let chunk_store = state.chunks.lock().unwrap();
+    used_bytes + chunk_store.manifests.values().map(|manifest| manifest_len(manifest)).sum::<u64>()
+}
+
+fn local_capacity(state: &Arc<DaemonState>) -> Capacity {
+    Capacity {
+        used: *state.used_bytes.lock().unwrap(),
+        quota: state.config.quota,
+    }
+}
+
+fn check_free_space(state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if local_capacity(state).free() == Some(0) {
+        Err(VPFSError::QuotaExceeded)
+    }
+    else {
+        Ok(())
+    }
+}
+
+fn reserve_space(bytes: u64, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let mut used_bytes = state.used_bytes.lock().unwrap();
+    if state.config.quota.is_some_and(|quota| *used_bytes + bytes > quota) {
+        return Err(VPFSError::QuotaExceeded);
+    }
+    *used_bytes += bytes;
+    Ok(())
+}
+
+fn release_space(bytes: u64, state: &Arc<DaemonState>) {
+    let mut used_bytes = state.used_bytes.lock().unwrap();
+    *used_bytes = used_bytes.saturating_sub(bytes);
+}
+
+// Capacity of every node in the cluster, nodes that can not be reached have no capacity
+fn cluster_capacity(state: &Arc<DaemonState>) -> Vec<NodeCapacity> {
+    cluster_view(state).into_iter().map(|status| {
+        let capacity = if status.node == state.local {
+            Some(local_capacity(state))
+        }
+        else if let Ok(DaemonResponse::Capacity(capacity)) = send_and_recive(&status.node, DaemonRequest::Capacity, state) {
+            Some(capacity)
+        }
+        else {
+            None
+        };
+        NodeCapacity { node: status.node, capacity }
+    }).collect()
+}
+
+/* ----------------------------------- Placement ------------------------------------ */
+// Placement policies of the directories stored on this node, by uri
+const PLACEMENT_FILE: &str = "placement";
+
+fn placement_policy_of(directory: &Location, state: &Arc<DaemonState>) -> Result<Option<PlacementPolicy>, VPFSError> {
+    if directory.node == state.local {
+        Ok(state.placement_policies.lock().unwrap().get(&directory.uri).cloned())
+    }
+    else {
+        match send_and_recive(&directory.node, DaemonRequest::PlacementPolicy(directory.uri.clone()), state) {
+            Ok(DaemonResponse::PlacementPolicy(policy)) => Ok(policy),
+            _ => Err(VPFSError::NotAccessible),
+        }
+    }
+}
+
+// Walks up from the parent directory to the root until a directory with a policy is found
+fn resolve_placement_policy(parent_path: Option<&str>, parent_directory: &Location, state: &Arc<DaemonState>) -> Result<PlacementPolicy, VPFSError> {
+    if let Some(policy) = placement_policy_of(parent_directory, state)? {
+        return Ok(policy);
+    }
+    let Some(parent_path) = parent_path else {
+        return Ok(PlacementPolicy::SameAsParent);
+    };
+    match parent_path.rsplit_once('/') {
+        Some((grandparent_path, _)) => {
+            let grandparent_directory = redirect_root(recursive_find(grandparent_path, state)?, state).location;
+            resolve_placement_policy(Some(grandparent_path), &grandparent_directory, state)
+        }
+        None => {
+            let root_directory = Location {
+                node: current_root(state).ok_or(VPFSError::NotAccessible)?,
+                uri: "root".to_string(),
+            };
+            resolve_placement_policy(None, &root_directory, state)
+        }
+    }
+}
+
+fn most_free_space(eligible: &dyn Fn(&Node) -> bool, state: &Arc<DaemonState>) -> Option<Node> {
+    cluster_capacity(state).into_iter()
+        .filter(|node_capacity| eligible(&node_capacity.node))
+        .filter_map(|node_capacity| node_capacity.capacity.map(|capacity| (node_capacity.node, capacity)))
+        // Nodes without a quota have unlimited free space, ties go to the node storing the least
+        .max_by_key(|(_, capacity)| (capacity.free().unwrap_or(u64::MAX), std::cmp::Reverse(capacity.used)))
+        .map(|(node, _)| node)
+}
+
+fn next_round_robin(eligible: &dyn Fn(&Node) -> bool, state: &Arc<DaemonState>) -> Option<Node> {
+    let reachable_nodes: Vec<Node> = cluster_view(state).into_iter()
+        .filter(|status| status.reachable && eligible(&status.node))
+        .map(|status| status.node)
+        .collect();
+    if reachable_nodes.is_empty() {
+        return None;
+    }
+    let mut next_round_robin = state.next_round_robin.lock().unwrap();
+    let node = reachable_nodes[*next_round_robin % reachable_nodes.len()].clone();
+    *next_round_robin = next_round_robin.wrapping_add(1);
+    Some(node)
+}
+
+fn matching_rule<'a>(path: &str, rules: &'a [PlacementRule]) -> Option<&'a PlacementRule> {
+    rules.iter()
+        .filter(|rule| path == rule.prefix || path.starts_with(&format!("{}/", rule.prefix.trim_end_matches('/'))))
+        .max_by_key(|rule| rule.prefix.len())
+}
+
+fn any_node(_node: &Node) -> bool {
+    true
+}
+
+// Only eligible nodes are chosen, when the policy picks another one the eligible node with the most
+// free space is used instead
+fn choose_node(path: &str, parent_path: Option<&str>, parent_directory: &Location, eligible: &dyn Fn(&Node) -> bool, state: &Arc<DaemonState>) -> Result<Node, VPFSError> {
+    let node = match resolve_placement_policy(parent_path, parent_directory, state)? {
+        PlacementPolicy::SameAsParent => None,
+        PlacementPolicy::MostFreeSpace => most_free_space(eligible, state),
+        PlacementPolicy::RoundRobin => next_round_robin(eligible, state),
+        PlacementPolicy::Rules(rules) => matching_rule(path, &rules).map(|rule| rule.node.clone()),
+    };
+    let node = node.unwrap_or_else(|| parent_directory.node.clone());
+    if !eligible(&node) {
+        most_free_space(eligible, state).ok_or(VPFSError::NotAccessible)
+    }
+    else {
+        Ok(node)
+    }
+}
+
+// Whether a rule of the placement policy puts the file on the node
+fn placed_by_rule(path: &str, parent_path: Option<&str>, parent_directory: &Location, node: &Node, state: &Arc<DaemonState>) -> bool {
+    match resolve_placement_policy(parent_path, parent_directory, state) {
+        Ok(PlacementPolicy::Rules(rules)) => matching_rule(path, &rules).is_some_and(|rule| rule.node == *node),
+        _ => false,
+    }
+}
+
+// Changing a directory's placement policy needs write permission on the directory
+fn set_placement_policy(directory: &str, policy: Option<PlacementPolicy>, user: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    check_access(directory, user, Access::Write, state)?;
+    let mut placement_policies = state.placement_policies.lock().unwrap();
+    match policy {
+        Some(policy) => {placement_policies.insert(directory.to_string(), policy);},
+        None => {placement_policies.remove(directory);},
+    }
+    save_metadata_change(PLACEMENT_FILE, directory, &placement_policies, &*state.storage);
+    Ok(())
+}
+
+fn set_placement_policy_at(path: &str, policy: Option<PlacementPolicy>, user: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let directory = if path.is_empty() || path == "." {
+        Location {
+            node: current_root(state).ok_or(VPFSError::NotAccessible)?,
+            uri: "root".to_string(),
+        }
+    }
+    else {
+        let dir_entry = redirect_root(recursive_find(path, state)?, state);
+        if !dir_entry.is_dir {
+            return Err(VPFSError::NotADirectory);
+        }
+        dir_entry.location
+    };
+    if directory.node == state.local {
+        set_placement_policy(&directory.uri, policy, user, state)
+    }
+    else {
+        match send_and_recive(&directory.node, DaemonRequest::SetPlacementPolicy(directory.uri, policy, user.to_string()), state) {
+            Ok(DaemonResponse::SetPlacementPolicy(result)) => result,
+            _ => Err(VPFSError::NotAccessible),
+        }
+    }
+}
+
+/* ------------------------- Decommissioning and rebalancing ------------------------ */
+fn read_file(location: &Location, state: &Arc<DaemonState>) -> Result<Vec<u8>, VPFSError> {
+    if location.node == state.local {
+        read_local(&location.uri, state).map_err(|_| VPFSError::DoesNotExist)
+    }
+    else {
+        read_remote(location, None, state)
+    }
+}
+
+fn read_directory(location: &Location, state: &Arc<DaemonState>) -> Result<Vec<DirectoryEntry>, VPFSError> {
+    let directory = read_file(location, state)?;
+    let mut directory_reader = BufReader::new(&*directory);
+    let mut entries = vec![];
+    while let Ok(entry) = serde_bare::from_reader(&mut directory_reader) {
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Store a copy of a file that is moving to this node, together with its metadata
+fn store_local_copy(data: &[u8], permissions: Permissions, policy: Option<PlacementPolicy>, state: &Arc<DaemonState>) -> Result<String, VPFSError> {
+    check_disk_write(state)?;
+    reserve_space(data.len() as u64, state)?;
+    let uri = create_file_with_random_uri(state);
+    state.storage.write(&uri, data).expect("Could not write migrated file");
+    set_permissions(&uri, permissions, state);
+    set_content_hash(&uri, content_hash(data), state);
+    if let Some(policy) = policy {
+        let mut placement_policies = state.placement_policies.lock().unwrap();
+        placement_policies.insert(uri.clone(), policy);
+        save_metadata_change(PLACEMENT_FILE, &uri, &placement_policies, &*state.storage);
+    }
+    Ok(uri)
+}
+
+fn store_copy(at: &Node, data: &[u8], permissions: Permissions, policy: Option<PlacementPolicy>, state: &Arc<DaemonState>) -> Result<Location, VPFSError> {
+    let uri = if *at == state.local {
+        store_local_copy(data, permissions, policy, state)?
+    }
+    else if let Some(connection) = stream_for(at, state) {
+        let mut connection = lock_connection(&connection, at, &["Migrate"], state);
+        send_request(&mut connection, at, DaemonRequest::Migrate(permissions, policy, data.len()), state);
+        let compress = connection.supports(COMPRESSION);
+        let _ = send_data(&mut *connection, data, compress);
+        match receive_message(&mut connection) {
+            Ok(DaemonResponse::Migrate(result)) => result?,
+            _ => return Err(VPFSError::NotAccessible),
+        }
+    }
+    else {
+        return Err(VPFSError::NotAccessible);
+    };
+    Ok(Location { node: at.clone(), uri })
+}
+
+fn remove_file_at(location: &Location, owner: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if location.node == state.local {
+        remove_local(&location.uri, state)
+    }
+    else {
+        match send_and_recive(&location.node, DaemonRequest::Remove(location.uri.clone(), owner.to_string()), state) {
+            Ok(DaemonResponse::Remove(result)) => result,
+            _ => Err(VPFSError::NotAccessible),
+        }
+    }
+}
+
+// Replace the entry with the same name as the new entry
+fn replace_local_dir_entry(directory: &str, new_entry: &DirectoryEntry, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    check_disk_write(state)?;
+    let _fs_lock = state.file_access_lock.write().unwrap();
+    let old_directory = state.storage.read(directory).map_err(|_| VPFSError::DoesNotExist)?;
+    let mut directory_reader = BufReader::new(&*old_directory);
+    let mut new_directory = vec![];
+    let mut replaced = false;
+    while let Ok(entry) = serde_bare::from_reader::<_, DirectoryEntry>(&mut directory_reader) {
+        let entry = if entry.name == new_entry.name {
+            replaced = true;
+            new_entry.clone()
+        }
+        else {
+            entry
+        };
+        new_directory.extend(serde_bare::to_vec(&entry).unwrap());
+    }
+    if !replaced {
+        return Err(VPFSError::DoesNotExist);
+    }
+    let old_len = old_directory.len() as u64;
+    let new_len = new_directory.len() as u64;
+    if new_len > old_len {
+        reserve_space(new_len - old_len, state)?;
+    }
+    else {
+        release_space(old_len - new_len, state);
+    }
+    state.storage.write(directory, &new_directory).expect("Could not rewrite directory");
+    set_content_hash(directory, content_hash(&new_directory), state);
+    Ok(())
+}
+
+fn replace_dir_entry(directory: &Location, new_entry: &DirectoryEntry, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if directory.node == state.local {
+        replace_local_dir_entry(&directory.uri, new_entry, state)
+    }
+    else {
+        match send_and_recive(&directory.node, DaemonRequest::ReplaceDirectoryEntry(directory.uri.clone(), new_entry.clone()), state) {
+            Ok(DaemonResponse::ReplaceDirectoryEntry(result)) => result,
+            _ => Err(VPFSError::NotAccessible),
+        }
+    }
+}
+
+// Copy the file or directory to its new node, point the entry in its directory at the copy and
+// then remove the original. Returns the new location and the number of bytes moved
+fn migrate(entry: &DirectoryEntry, directory: &Location, to: &Node, state: &Arc<DaemonState>) -> Result<(Location, u64), VPFSError> {
+    let data = read_file(&entry.location, state)?;
+    let policy = if entry.is_dir {placement_policy_of(&entry.location, state)?} else {None};
+    let new_location = store_copy(to, &data, entry.permissions.clone(), policy, state)?;
+    let mut new_entry = entry.clone();
+    new_entry.location = new_location.clone();
+    if let Err(error) = replace_dir_entry(directory, &new_entry, state) {
+        let _ = remove_file_at(&new_location, &entry.permissions.owner, state);
+        return Err(error);
+    }
+    let _ = remove_file_at(&entry.location, &entry.permissions.owner, state);
+    Ok((new_location, data.len() as u64))
+}
+
+// Decides which entries move while walking the directory tree, and where to
+trait Relocation {
+    fn destination(&mut self, path: &str, parent_path: Option<&str>, directory: &Location, entry: &DirectoryEntry, state: &Arc<DaemonState>) -> Option<Node>;
+    fn moved(&mut self, _entry: &DirectoryEntry, _to: &Node, _bytes: u64) {}
+    fn failed(&mut self, _path: &str, _error: VPFSError) {}
+}
+
+// Walks the directory tree from the root, moving entries to the nodes the relocation picks.
+// Directories are moved before their contents, and the . and .. entries of every directory
+// are fixed up to point at wherever the directory and its parent ended up
+fn relocate_directory(relocation: &mut dyn Relocation, path: Option<&str>, directory: &Location, parent_directory: &Location, state: &Arc<DaemonState>) -> Result<usize, VPFSError> {
+    let mut moved = 0;
+    for mut entry in read_directory(directory, state)? {
+        if entry.name == "." || entry.name == ".." {
+            let link = if entry.name == "." {directory} else {parent_directory};
+            if entry.location != *link {
+                entry.location = link.clone();
+                replace_dir_entry(directory, &entry, state)?;
+            }
+            continue;
+        }
+        let entry_path = match path {
+            Some(path) => format!("{path}/{}", entry.name),
+            None => entry.name.clone(),
+        };
+        if let Some(to) = relocation.destination(&entry_path, path, directory, &entry, state) {
+            match migrate(&entry, directory, &to, state) {
+                Ok((new_location, bytes)) => {
+                    println!("Moved {entry_path} to {}", to.name);
+                    relocation.moved(&entry, &to, bytes);
+                    entry.location = new_location;
+                    moved += 1;
+                }
+                Err(error) => relocation.failed(&entry_path, error),
+            }
+        }
+        if entry.is_dir {
+            moved += relocate_directory(relocation, Some(&entry_path), &entry.location, directory, state)?;
+        }
+    }
+    Ok(moved)
+}
+
+fn relocate_tree(relocation: &mut dyn Relocation, state: &Arc<DaemonState>) -> Result<usize, VPFSError> {
+    let root_directory = Location {
+        node: current_root(state).ok_or(VPFSError::NotAccessible)?,
+        uri: "root".to_string(),
+    };
+    relocate_directory(relocation, None, &root_directory, &root_directory, state)
+}
+
+struct Drain {
+    node: Node,
+    failed: Vec<String>,
+}
+
+impl Relocation for Drain {
+    fn destination(&mut self, path: &str, parent_path: Option<&str>, directory: &Location, entry: &DirectoryEntry, state: &Arc<DaemonState>) -> Option<Node> {
+        if entry.location.node != self.node {
+            return None;
+        }
+        match choose_node(path, parent_path, directory, &|node| *node != self.node, state) {
+            Ok(node) => Some(node),
+            Err(error) => {
+                self.failed(path, error);
+                None
+            }
+        }
+    }
+
+    fn failed(&mut self, path: &str, error: VPFSError) {
+        eprintln!("Could not move {path}: {:?}", error);
+        self.failed.push(path.to_string());
+    }
+}
+
+// Move everything stored on the node elsewhere, then remove it from the cluster
+fn drain(node: &Node, state: &Arc<DaemonState>) -> Result<usize, VPFSError> {
+    if current_root(state).as_ref() == Some(node) {
+        return Err(VPFSError::Other("Can not drain the root node, promote a standby first".to_string()));
+    }
+    if *node == state.local {
+        return Err(VPFSError::Other("Can not drain the node the request was sent to".to_string()));
+    }
+    println!("Draining {}", node.name);
+    let mut drain = Drain { node: node.clone(), failed: vec![] };
+    let moved = relocate_tree(&mut drain, state)?;
+    if !drain.failed.is_empty() {
+        return Err(VPFSError::Other(format!("Could not move {} entries off {}: {}", drain.failed.len(), node.name, drain.failed.join(", "))));
+    }
+    remove_node(node, state);
+    // Tell every node right away instead of waiting for gossip
+    for peer in remote_nodes(state) {
+        let _ = send_and_recive::<DaemonResponse>(&peer, DaemonRequest::RemoveNode(node.clone()), state);
+    }
+    Ok(moved)
+}
+
+// Replace the node's entry in the known hosts with a tombstone, which gossip spreads to the
+// rest of the cluster. If the node is restarted it issues a newer entry and rejoins
+fn remove_node(node: &Node, state: &Arc<DaemonState>) {
+    {
+        let mut known_hosts = state.known_hosts.lock().unwrap();
+        if let Some(entry) = known_hosts.get_mut(node) {
+            if entry.removed {
+                return;
+            }
+            entry.generation += 1;
+            entry.removed = true;
+        }
+    }
+    println!("{} has been removed from the cluster", node.name);
+    state.standbys.lock().unwrap().retain(|standby| standby != node);
+    drop_connection(node, state);
+    state.node_status.lock().unwrap().remove(node);
+    save_state(state);
+}
+
+// Moves files from nodes storing more than the average to nodes storing less, wherever the
+// placement policy of their directory puts them among those. Files a rule puts on their node stay
+struct Rebalance {
+    capacity: HashMap<Node, Capacity>,
+    average: u64,
+}
+
+impl Relocation for Rebalance {
+    fn destination(&mut self, path: &str, parent_path: Option<&str>, directory: &Location, entry: &DirectoryEntry, state: &Arc<DaemonState>) -> Option<Node> {
+        if entry.is_dir || self.capacity.get(&entry.location.node).is_none_or(|capacity| capacity.used <= self.average) {
+            return None;
+        }
+        if placed_by_rule(path, parent_path, directory, &entry.location.node, state) {
+            return None;
+        }
+        let size = read_file(&entry.location, state).ok()?.len() as u64;
+        let below_average = |node: &Node| self.capacity.get(node).is_some_and(|capacity| {
+            capacity.used < self.average && capacity.free().is_none_or(|free| free >= size)
+        });
+        choose_node(path, parent_path, directory, &below_average, state).ok()
+    }
+
+    fn moved(&mut self, entry: &DirectoryEntry, to: &Node, bytes: u64) {
+        if let Some(capacity) = self.capacity.get_mut(&entry.location.node) {
+            capacity.used = capacity.used.saturating_sub(bytes);
+        }
+        if let Some(capacity) = self.capacity.get_mut(to) {
+            capacity.used += bytes;
+        }
+    }
+
+    fn failed(&mut self, path: &str, error: VPFSError) {
+        eprintln!("Could not move {path}: {:?}", error);
+    }
+}
+
+fn rebalance(state: &Arc<DaemonState>) -> Result<usize, VPFSError> {
+    let capacity: HashMap<Node, Capacity> = cluster_capacity(state).into_iter()
+        .filter_map(|node_capacity| node_capacity.capacity.map(|capacity| (node_capacity.node, capacity)))
+        .collect();
+    if capacity.len() < 2 {
+        return Ok(0);
+    }
+    let average = capacity.values().map(|capacity| capacity.used).sum::<u64>() / capacity.len() as u64;
+    println!("Rebalancing to about {average} bytes per node");
+    relocate_tree(&mut Rebalance { capacity, average }, state)
+}
+
+/* ---------------------------------- Permissions ----------------------------------- */
+// Permissions of the files stored on this node, by uri
+const PERMISSIONS_FILE: &str = "permissions";
+
+// Anyone may list the root directory and create entries in it
+fn root_permissions() -> Permissions {
+    Permissions { owner: "root".to_string(), mode: 0o666 }
+}
+
+fn set_permissions(uri: &str, permissions: Permissions, state: &Arc<DaemonState>) {
+    let mut all_permissions = state.permissions.lock().unwrap();
+    all_permissions.insert(uri.to_string(), permissions);
+    save_metadata_change(PERMISSIONS_FILE, uri, &all_permissions, &*state.storage);
+}
+
+fn remove_permissions(uri: &str, state: &Arc<DaemonState>) {
+    let mut all_permissions = state.permissions.lock().unwrap();
+    if all_permissions.remove(uri).is_some() {
+        save_metadata_change(PERMISSIONS_FILE, uri, &all_permissions, &*state.storage);
+    }
+}
+
+fn permissions_for(uri: &str, state: &Arc<DaemonState>) -> Option<Permissions> {
+    state.permissions.lock().unwrap().get(uri).cloned()
+}
+
+// Files without permissions were stored before permissions were tracked, anyone may access them
+fn check_access(uri: &str, user: &str, access: Access, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    match state.permissions.lock().unwrap().get(uri) {
+        Some(permissions) if !permissions.allows(user, access) => Err(VPFSError::PermissionDenied),
+        _ => Ok(()),
+    }
+}
+
+// The . and .. entries are added by the owner right after creating a directory, even if the
+// directory's mode does not let the owner write to it
+fn check_append(directory: &str, entry: &DirectoryEntry, user: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if entry.name == "." || entry.name == ".." {
+        match permissions_for(directory, state) {
+            Some(permissions) if permissions.owner != user => Err(VPFSError::PermissionDenied),
+            _ => Ok(()),
+        }
+    }
+    else {
+        check_access(directory, user, Access::Write, state)
+    }
+}
+
+// Only the owner may remove a file
+fn check_remove(uri: &str, user: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    match permissions_for(uri, state) {
+        Some(permissions) if permissions.owner != user => Err(VPFSError::PermissionDenied),
+        _ => Ok(()),
+    }
+}
+
+/* --------------------------------- Content hashes --------------------------------- */
+// Hashes of the files and directories stored on this node, by uri. Files get theirs when created and
+// whenever they are written, directories whenever an entry is added or replaced
+const HASHES_FILE: &str = "hashes";
+
+fn set_content_hash(uri: &str, hash: ContentHash, state: &Arc<DaemonState>) {
+    let mut hashes = state.hashes.lock().unwrap();
+    hashes.insert(uri.to_string(), hash);
+    save_metadata_change(HASHES_FILE, uri, &hashes, &*state.storage);
+}
+
+fn remove_content_hash(uri: &str, state: &Arc<DaemonState>) {
+    let mut hashes = state.hashes.lock().unwrap();
+    if hashes.remove(uri).is_some() {
+        save_metadata_change(HASHES_FILE, uri, &hashes, &*state.storage);
+    }
+}
+
+// The hash the file was stored with, so a copy that was corrupted on disk fails verification.
+// Only files stored by daemons that did not keep hashes for every file have none, those can not
+// be verified and are trusted as they are
+fn content_hash_for(uri: &str, data: &[u8], state: &Arc<DaemonState>) -> ContentHash {
+    state.hashes.lock().unwrap().get(uri).copied().unwrap_or_else(|| content_hash(data))
+}
+
+/* --------------------------------- Chunk storage ---------------------------------- */
+// With --chunked, files written by users are split into content defined chunks, stored by their
+// hash under CHUNKS_DIR so that every distinct chunk is stored once. The file itself is left empty,
+// its modification time still tells when it last changed, and MANIFESTS_FILE lists its chunks.
+// Directories and copies of files moving to or cached on this node are always stored whole
+const CHUNKS_DIR: &str = "chunks";
+const MANIFESTS_FILE: &str = "manifests";
+// Chunks can be dropped by another write between the owner asking for them and getting them
+const WRITE_CHUNKS_ATTEMPTS: usize = 3;
+
+struct ChunkStore {
+    // Chunks of the files stored in chunks, by uri
+    manifests: HashMap<String, Vec<ChunkRef>>,
+    // How many times each stored chunk appears in a manifest
+    references: HashMap<ContentHash, usize>,
+}
+
+impl ChunkStore {
+    fn load(storage: &dyn Storage) -> ChunkStore {
+        let manifests: HashMap<String, Vec<ChunkRef>> = load_metadata(MANIFESTS_FILE, storage);
+        let mut references = HashMap::new();
+        for chunk in manifests.values().flatten() {
+            *references.entry(chunk.hash).or_insert(0) += 1;
+        }
+        ChunkStore { manifests, references }
+    }
+}
+
+// Chunk transfers are only offered by daemons that store files in chunks
+fn local_protocol(state: &Arc<DaemonState>) -> Protocol {
+    let mut protocol = Protocol::current();
+    if state.config.chunked {
+        protocol.capabilities.push(CHUNKS.to_string());
+    }
+    protocol
+}
+
+fn chunk_path(hash: &ContentHash) -> String {
+    let name: String = hash.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("{CHUNKS_DIR}/{name}")
+}
+
+fn split_into_chunks(data: &[u8]) -> Vec<(ChunkRef, &[u8])> {
+    chunks::split(data).into_iter().map(|chunk| (ChunkRef { hash: content_hash(chunk), len: chunk.len() as u32 }, chunk)).collect()
+}
+
+fn manifest_len(manifest: &[ChunkRef]) -> u64 {
+    manifest.iter().map(|chunk| chunk.len as u64).sum()
+}
+
+fn has_chunk(hash: &ContentHash, state: &Arc<DaemonState>) -> bool {
+    state.chunks.lock().unwrap().references.contains_key(hash)
+}
+
+// Chunks are not verified here, the hash of the whole file is checked by whoever reads it
+fn read_chunks(manifest: &[ChunkRef], state: &Arc<DaemonState>) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(manifest_len(manifest) as usize);
+    for chunk in manifest {
+        data.extend(state.storage.read(&chunk_path(&chunk.hash))?);
+    }
+    Ok(data)
+}
+
+// Writes the chunks that are not stored on this node yet
+fn store_chunks<'a>(chunks: impl IntoIterator<Item = (&'a ChunkRef, &'a [u8])>, state: &Arc<DaemonState>) -> io::Result<()> {
+    for (chunk, data) in chunks {
+        if !has_chunk(&chunk.hash, state) {
+            state.storage.write(&chunk_path(&chunk.hash), data)?;
+        }
+    }
+    Ok(())
+}
+
+// Replaces the manifest of a file, and deletes the chunks no manifest refers to anymore. The chunks
+// of the new manifest must already be stored
+fn set_manifest(uri: &str, manifest: Option<Vec<ChunkRef>>, state: &Arc<DaemonState>) {
+    let mut chunk_store = state.chunks.lock().unwrap();
+    let chunk_store = &mut *chunk_store;
+    for chunk in manifest.iter().flatten() {
+        *chunk_store.references.entry(chunk.hash).or_insert(0) += 1;
+    }
+    let changed = manifest.is_some();
+    let old_manifest = match manifest {
+        Some(manifest) => chunk_store.manifests.insert(uri.to_string(), manifest),
+        None => chunk_store.manifests.remove(uri),
+    };
+    if !changed && old_manifest.is_none() {
+        return;
+    }
+    for chunk in old_manifest.into_iter().flatten() {
+        if let Some(references) = chunk_store.references.get_mut(&chunk.hash) {
+            *references -= 1;
+            if *references == 0 {
+                chunk_store.references.remove(&chunk.hash);
+                let _ = state.storage.remove(&chunk_path(&chunk.hash));
+            }
+        }
+    }
+    save_metadata_change(MANIFESTS_FILE, uri, &chunk_store.manifests, &*state.storage);
+}
+
+//Assumes caller holds the file access lock
+fn read_stored(uri: &str, state: &Arc<DaemonState>) -> io::Result<Vec<u8>> {
+    let manifest = state.chunks.lock().unwrap().manifests.get(uri).cloned();
+    match manifest {
+        Some(manifest) => read_chunks(&manifest, state),
+        None => state.storage.read(uri),
+    }
+}
+
+//Assumes caller holds the file access lock
+fn stored_len(uri: &str, state: &Arc<DaemonState>) -> u64 {
+    if let Some(manifest) = state.chunks.lock().unwrap().manifests.get(uri) {
+        return manifest_len(manifest);
+    }
+    state.storage.len(uri).unwrap_or(0)
+}
+
+// Stores a file sent as chunks. Chunks that were not included must already be stored on this node,
+// otherwise nothing is written and the owner answers with the ones it is missing
+fn write_local_chunks(uri: &str, manifest: Vec<ChunkRef>, included: HashMap<ContentHash, Vec<u8>>, state: &Arc<DaemonState>) -> Result<usize, VPFSError> {
+    check_disk_write(state)?;
+    let new_len = manifest_len(&manifest);
+    if new_len > MAX_FRAME_LEN as u64 {
+        return Err(VPFSError::TooLarge);
+    }
+    let _fs_lock = state.file_access_lock.write().unwrap();
+    let mut missing: Vec<ContentHash> = manifest.iter().map(|chunk| chunk.hash).filter(|hash| !included.contains_key(hash) && !has_chunk(hash, state)).collect();
+    if !missing.is_empty() {
+        missing.sort();
+        missing.dedup();
+        return Err(VPFSError::MissingChunks(missing));
+    }
+    let data = replace_contents(uri, new_len, || {
+        store_chunks(manifest.iter().filter_map(|chunk| included.get(&chunk.hash).map(|data| (chunk, data.as_slice()))), state)?;
+        state.storage.write(uri, &[])?;
+        Ok(Some(manifest.clone()))
+    }, state).and_then(|_| read_chunks(&manifest, state).map_err(|_| VPFSError::DoesNotExist))?;
+    set_content_hash(uri, content_hash(&data), state);
+    Ok(data.len())
+}
+
+// Checks a read from another daemon, and whether the copy it has cached is still up to date
+fn check_read(uri: &str, last_modified: Option<SystemTime>, user: Option<&str>, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if let Some(user) = user {
+        check_access(uri, user, Access::Read, state)?;
+    }
+    if let Some(remote_last_modified) = last_modified {
+        let _fs_lock = state.file_access_lock.read().unwrap();
+        if let Ok(local_last_modified) = state.storage.modified(uri) {
+            if local_last_modified < remote_last_modified {
+                return Err(VPFSError::NotModified);
+            }
+        }
+    }
+    Ok(())
+}
+
+// The file is split again rather than using its manifest, so files stored whole can be read in
+// chunks too. Splitting is deterministic, so the chunks are the same either way
+fn read_local_chunks(uri: &str, last_modified: Option<SystemTime>, user: Option<&str>, state: &Arc<DaemonState>) -> Result<(Vec<ChunkRef>, Option<Permissions>, ContentHash), VPFSError> {
+    check_read(uri, last_modified, user, state)?;
+    let data = read_local(uri, state).map_err(|_| VPFSError::DoesNotExist)?;
+    let manifest = split_into_chunks(&data).into_iter().map(|(chunk, _)| chunk).collect();
+    Ok((manifest, permissions_for(uri, state), content_hash_for(uri, &data, state)))
+}
+
+// Only chunks of the file's current contents are sent, so a request can not name chunks of files
+// the user may not read
+fn send_chunks(stream: &mut Connection, uri: &str, hashes: &[ContentHash], user: Option<&str>, state: &Arc<DaemonState>) {
+    let data = check_read(uri, None, user, state).and_then(|_| read_local(uri, state).map_err(|_| VPFSError::DoesNotExist));
+    let data = match data {
+        Ok(data) => data,
+        Err(error) => {
+            send_message(stream, DaemonResponse::FetchChunks(Err(error)));
+            return;
+        }
+    };
+    let chunks: HashMap<ContentHash, &[u8]> = split_into_chunks(&data).into_iter().map(|(chunk, chunk_data)| (chunk.hash, chunk_data)).collect();
+    let Some(requested) = hashes.iter().map(|hash| chunks.get(hash).copied()).collect::<Option<Vec<_>>>() else {
+        // The file changed since its chunks were listed
+        send_message(stream, DaemonResponse::FetchChunks(Err(VPFSError::NotFound)));
+        return;
+    };
+    send_message(stream, DaemonResponse::FetchChunks(Ok(())));
+    let compress = stream.supports(COMPRESSION);
+    for chunk_data in requested {
+        let _ = send_data(&mut *stream, chunk_data, compress);
+    }
+}
+
+// Reads the data frames of the chunks included with a write, each checked against its hash
+fn receive_chunks(stream: &mut Connection, manifest: &[ChunkRef], included: &[ContentHash]) -> Result<HashMap<ContentHash, Vec<u8>>, VPFSError> {
+    let lengths: HashMap<ContentHash, usize> = manifest.iter().map(|chunk| (chunk.hash, chunk.len as usize)).collect();
+    let mut chunks = HashMap::new();
+    for hash in included {
+        let Some(len) = lengths.get(hash) else {
+            return Err(VPFSError::BadFrame("Included chunk is not part of the file".to_string()));
+        };
+        let data = receive_data(stream, *len).map_err(|error| VPFSError::BadFrame(error.to_string()))?;
+        if content_hash(&data) != *hash {
+            return Err(VPFSError::Corrupted);
+        }
+        chunks.insert(*hash, data);
+    }
+    Ok(chunks)
+}
+
+// Errors reading the file are kept apart from errors on the connection, which mean the owner may be down
+type FetchResult = Result<Result<(Vec<u8>, Option<Permissions>), VPFSError>, FrameError>;
+
+// Fetches a whole file from its owner
+fn fetch_whole(connection: &mut Connection, location: &Location, last_modified: Option<SystemTime>, user: Option<&str>, state: &Arc<DaemonState>) -> FetchResult {
+    send_request(connection, &location.node, DaemonRequest::Read(location.uri.clone(), last_modified, user.map(str::to_string)), state);
+    match receive_message(connection)? {
+        DaemonResponse::Read(Ok((file_len, permissions, hash))) => {
+            let buf = receive_data(connection, file_len)?;
+            if content_hash(&buf) != hash {
+                return Ok(Err(VPFSError::Corrupted));
+            }
+            Ok(Ok((buf, permissions)))
+        }
+        DaemonResponse::Read(Err(error)) | DaemonResponse::Error(error) => Ok(Err(error)),
+        _ => panic!("Bad responce"),
+    }
+}
+
+// Fetches a file from an owner that negotiated chunks. Only the chunks found in neither the cached
+// copy of the file, even an outdated one, nor this node's chunk store are transferred.
+// Runs without the file access lock, chunks read from the store are only used if they are intact
+fn fetch_chunked(connection: &mut Connection, location: &Location, last_modified: Option<SystemTime>, user: Option<&str>, cached_data: Option<&[u8]>, state: &Arc<DaemonState>) -> FetchResult {
+    send_request(connection, &location.node, DaemonRequest::ReadChunks(location.uri.clone(), last_modified, user.map(str::to_string)), state);
+    let (manifest, permissions, hash) = match receive_message(connection)? {
+        DaemonResponse::ReadChunks(Ok(chunks)) => chunks,
+        DaemonResponse::ReadChunks(Err(error)) | DaemonResponse::Error(error) => return Ok(Err(error)),
+        _ => panic!("Bad responce"),
+    };
+    if manifest_len(&manifest) > MAX_FRAME_LEN as u64 {
+        return Ok(Err(VPFSError::TooLarge));
+    }
+    let mut available: HashMap<ContentHash, Vec<u8>> = cached_data.map(|cached_data| {
+        split_into_chunks(cached_data).into_iter().map(|(chunk, chunk_data)| (chunk.hash, chunk_data.to_vec())).collect()
+    }).unwrap_or_default();
+    let mut missing = vec![];
+    for chunk in &manifest {
+        if available.contains_key(&chunk.hash) {
+            continue;
+        }
+        // Chunks stored here may be deleted at any time, so they are only used if they read back intact
+        let stored = has_chunk(&chunk.hash, state).then(|| state.storage.read(&chunk_path(&chunk.hash)).ok()).flatten();
+        match stored.filter(|chunk_data| content_hash(chunk_data) == chunk.hash) {
+            Some(chunk_data) => {
+                available.insert(chunk.hash, chunk_data);
+            }
+            None => missing.push(*chunk),
+        }
+    }
+    missing.sort_by_key(|chunk| chunk.hash);
+    missing.dedup();
+    if !missing.is_empty() {
+        let hashes = missing.iter().map(|chunk| chunk.hash).collect();
+        send_request(connection, &location.node, DaemonRequest::FetchChunks(location.uri.clone(), hashes, user.map(str::to_string)), state);
+        match receive_message(connection)? {
+            DaemonResponse::FetchChunks(Ok(())) => {}
+            DaemonResponse::FetchChunks(Err(error)) | DaemonResponse::Error(error) => return Ok(Err(error)),
+            _ => panic!("Bad responce"),
+        }
+        for chunk in missing {
+            let chunk_data = receive_data(connection, chunk.len as usize)?;
+            if content_hash(&chunk_data) != chunk.hash {
+                return Ok(Err(VPFSError::Corrupted));
+            }
+            available.insert(chunk.hash, chunk_data);
+        }
+    }
+    let mut buf = Vec::with_capacity(manifest_len(&manifest) as usize);
+    for chunk in &manifest {
+        buf.extend_from_slice(&available[&chunk.hash]);
+    }
+    if content_hash(&buf) != hash {
+        return Ok(Err(VPFSError::Corrupted));
+    }
+    Ok(Ok((buf, permissions)))
+}
+
+// Sends only the chunks the owner does not have yet. The first attempt includes none, the owner
+// answers with the ones it is missing and those are included in the next
+fn write_remote_chunks(connection: &mut Connection, location: &Location, data: &[u8], user: &str, state: &Arc<DaemonState>) -> Result<usize, VPFSError> {
+    let chunks = split_into_chunks(data);
+    let manifest: Vec<ChunkRef> = chunks.iter().map(|(chunk, _)| *chunk).collect();
+    let chunk_data: HashMap<ContentHash, &[u8]> = chunks.iter().map(|(chunk, chunk_data)| (chunk.hash, *chunk_data)).collect();
+    let mut included: Vec<ContentHash> = vec![];
+    for _ in 0..WRITE_CHUNKS_ATTEMPTS {
+        send_request(connection, &location.node, DaemonRequest::WriteChunks(location.uri.clone(), manifest.clone(), included.clone(), user.to_string()), state);
+        let compress = connection.supports(COMPRESSION);
+        for hash in &included {
+            let _ = send_data(&mut *connection, chunk_data[hash], compress);
+        }
+        match receive_message(connection) {
+            Ok(DaemonResponse::WriteChunks(Err(VPFSError::MissingChunks(missing)))) => {
+                included = missing.into_iter().filter(|hash| chunk_data.contains_key(hash)).collect();
+            }
+            Ok(DaemonResponse::WriteChunks(write_result)) => return write_result,
+            Ok(DaemonResponse::Error(error)) => return Err(error),
+            _ => return Err(VPFSError::NotAccessible),
+        }
+    }
+    Err(VPFSError::NotAccessible)
+}
+
+/* ------------------------------- Persistent state --------------------------------- */
+// Bump whenever PersistedState changes, and teach load_older_state to read the previous layout.
+// State files from newer versions are ignored
+const STATE_FILE_VERSION: u32 = 9;
+const STATE_FILE: &str = "state";
+
+// Everything a daemon needs to rejoin the cluster after a restart, without the root
+#[derive(Serialize, Deserialize)]
+struct PersistedState {
+    local: Node,
+    root: Option<Node>,
+    root_epoch: u64,
+    local_entry: Option<HostEntry>,
+    known_hosts: HashMap<Node, HostEntry>,
+    standbys: Vec<Node>,
+    config: DaemonConfig,
+}
+
+fn load_state(storage: &dyn Storage) -> Option<PersistedState> {
+    let state_data = storage.read(STATE_FILE).ok()?;
+    let mut state_file = state_data.as_slice();
+    match serde_bare::from_reader::<_, u32>(&mut state_file) {
+        Ok(STATE_FILE_VERSION) => {
+            serde_bare::from_reader(&mut state_file).map_err(|_| eprintln!("State file is corrupt, ignoring it")).ok()
+        }
+        Ok(version @ 1..STATE_FILE_VERSION) => {
+            load_older_state(version, &mut state_file).map_err(|_| eprintln!("State file is corrupt, ignoring it")).ok()
+        }
+        Ok(version) => {
+            eprintln!("Ignoring state file with unsupported version {version}");
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+// Host entries before version 5, which could not be removed
+#[derive(Deserialize)]
+struct HostEntryV4 {
+    address: String,
+    generation: u64,
+}
+
+impl From<HostEntryV4> for HostEntry {
+    fn from(entry: HostEntryV4) -> Self {
+        HostEntry { address: entry.address, generation: entry.generation, removed: false }
+    }
+}
+
+// Every earlier layout is the current one without the fields added since. Fields are read one at a
+// time, in order, and the ones the file's version did not have yet keep their defaults
+fn load_older_state(version: u32, state_file: &mut &[u8]) -> Result<PersistedState, serde_bare::error::Error> {
+    fn next<T: DeserializeOwned>(state_file: &mut &[u8]) -> Result<T, serde_bare::error::Error> {
+        serde_bare::from_reader(state_file)
+    }
+    let local = next(state_file)?;
+    let root = next(state_file)?;
+    let (local_entry, known_hosts) = if version >= 5 {
+        (next(state_file)?, next(state_file)?)
+    }
+    else {
+        let local_entry: Option<HostEntryV4> = next(state_file)?;
+        let known_hosts: HashMap<Node, HostEntryV4> = next(state_file)?;
+        (local_entry.map(HostEntry::from), known_hosts.into_iter().map(|(node, entry)| (node, entry.into())).collect())
+    };
+    let standbys = next(state_file)?;
+    let mut config = DaemonConfig {
+        port: next(state_file)?,
+        cache_size: next(state_file)?,
+        artificial_latency: next(state_file)?,
+        heartbeat_interval: next(state_file)?,
+        standby: next(state_file)?,
+        ..DaemonConfig::default()
+    };
+    if version >= 2 {
+        config.key_file = next(state_file)?;
+        config.encrypt = next(state_file)?;
+    }
+    if version >= 3 {
+        config.socket = next(state_file)?;
+        config.tcp_clients = next(state_file)?;
+    }
+    if version >= 4 {
+        config.quota = next(state_file)?;
+    }
+    if version >= 6 {
+        config.chunked = next(state_file)?;
+    }
+    if version >= 7 {
+        config.connect_timeout = next(state_file)?;
+        config.failover_after = next(state_file)?;
+    }
+    if version >= 8 {
+        config.max_connections = next(state_file)?;
+        config.connection_queue = next(state_file)?;
+    }
+    println!("Upgrading state file from version {version}");
+    Ok(PersistedState { local, root, root_epoch: 0, local_entry, known_hosts, standbys, config })
+}
+
+// Metadata kept about the files stored on this node, by uri. Each change is appended to a journal
+// next to the file, which is folded back into the file once it grows larger than the file
+const METADATA_JOURNAL_MIN_LEN: u64 = 64 << 10;
+
+fn metadata_journal(file_name: &str) -> String {
+    format!("{file_name}.journal")
+}
+
+// The journal is folded into the file straight away, so changes appended later never follow one
+// that a crash cut short
+fn load_metadata<T: Serialize + DeserializeOwned>(file_name: &str, storage: &dyn Storage) -> HashMap<String, T> {
+    let mut metadata: HashMap<String, T> = storage.read(file_name).ok().and_then(|metadata| serde_bare::from_slice(&metadata).ok()).unwrap_or_default();
+    let journal_name = metadata_journal(file_name);
+    let Ok(journal) = storage.read(&journal_name) else {
+        return metadata;
+    };
+    let mut journal_reader = journal.as_slice();
+    while let Ok((uri, value)) = serde_bare::from_reader::<_, (String, Option<T>)>(&mut journal_reader) {
+        match value {
+            Some(value) => metadata.insert(uri, value),
+            None => metadata.remove(&uri),
+        };
+    }
+    save_metadata(file_name, &metadata, storage);
+    storage.remove(&journal_name).expect("Failed to remove metadata journal");
+    metadata
+}
+
+//Assumes caller holds the lock of the metadata being changed
+fn save_metadata_change<T: Serialize>(file_name: &str, uri: &str, metadata: &HashMap<String, T>, storage: &dyn Storage) {
+    let journal_name = metadata_journal(file_name);
+    let change = serde_bare::to_vec(&(uri, metadata.get(uri))).expect("Failed to write metadata change");
+    match storage.append(&journal_name, &change) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => storage.write(&journal_name, &change),
+        appended => appended,
+    }.expect("Failed to append to metadata journal");
+    let journal_len = storage.len(&journal_name).unwrap_or(0);
+    if journal_len > METADATA_JOURNAL_MIN_LEN.max(storage.len(file_name).unwrap_or(0)) {
+        save_metadata(file_name, metadata, storage);
+        storage.remove(&journal_name).expect("Failed to remove metadata journal");
+    }
+}
+
+//Assumes caller holds the lock of the metadata being saved
+fn save_metadata<T: Serialize>(file_name: &str, metadata: &HashMap<String, T>, storage: &dyn Storage) {
+    let temp_file_name = format!("{file_name}.tmp");
+    let metadata = serde_bare::to_vec(metadata).expect("Failed to write metadata file");
+    storage.write(&temp_file_name, &metadata).expect("Failed to create metadata file");
+    storage.rename(&temp_file_name, file_name).expect("Failed to replace metadata file");
+}
+
+// Written to a temporary file first, so a crash never leaves a half written state file
+fn save_state(state: &Arc<DaemonState>) {
+    let persisted_state = PersistedState {
+        local: state.local.clone(),
+        root: current_root(state),
+        root_epoch: *state.root_epoch.lock().unwrap(),
+        local_entry: state.local_entry.clone(),
+        known_hosts: state.known_hosts.lock().unwrap().clone(),
+        standbys: state.standbys.lock().unwrap().clone(),
+        config: state.config.clone(),
+    };
+    let _state_file_lock = state.state_file_lock.lock().unwrap();
+    let temp_file_name = format!("{STATE_FILE}.tmp");
+    let mut state_file = vec![];
+    serde_bare::to_writer(&mut state_file, &STATE_FILE_VERSION).expect("Failed to write state file");
+    serde_bare::to_writer(&mut state_file, &persisted_state).expect("Failed to write state file");
+    state.storage.write(&temp_file_name, &state_file).expect("Failed to create state file");
+    state.storage.rename(&temp_file_name, STATE_FILE).expect("Failed to replace state file");
+}
+
+/* ------------------- User process connection handler functions ------------------- */
+fn recursive_find(file: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
     if let Some((parent_directory, file_name)) = file.rsplit_once('/') 
     {
-        match recursive_find(parent_directory, state) {
+        match recursive_find(parent_directory, state).map(|entry| redirect_root(entry, state)) {
             Ok(parent_dir_entry) => {
                 if !parent_dir_entry.is_dir {
                     return Err(VPFSError::NotADirectory);
@@ -306,7 +2362,7 @@ fn recursive_find(file: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry
                     search_directory(file_name, &parent_dir_entry.location.uri, state)
                 }
                 else {
-                    match read_remote(&parent_dir_entry.location, state) {
+                    match read_remote(&parent_dir_entry.location, None, state) {
                         Ok(directory) => search_directory_with_reader(file_name, &mut BufReader::new(&*directory)),
                         Err(VPFSError::OnlyInCache(cache_location)) => {
                             let dir_entry = search_directory(file_name, &cache_location.uri, state);
@@ -335,7 +2391,7 @@ fn recursive_find(file: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry
                     }
                 }
                 else {
-                    match read_remote(&parent_dir_entry.location, state) {
+                    match read_remote(&parent_dir_entry.location, None, state) {
                         Ok(directory) => {
                             let dir_entry = search_directory_with_reader(file_name, &mut BufReader::new(&*directory));
                             if let Ok(dir_entry) = dir_entry {
@@ -362,8 +2418,8 @@ fn recursive_find(file: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry
         }
     }
     // Base case, file is located in the root directory
-    else if let Some(root_node) = &state.root{
-        if *root_node == state.local {
+    else if let Some(root_node) = current_root(state) {
+        if root_node == state.local {
             search_directory(file, "root", state)
         }
         else {
@@ -371,7 +2427,7 @@ fn recursive_find(file: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry
                 node: root_node.clone(),
                 uri: "root".to_string()
             };
-            match read_remote(&root_location, state) {
+            match read_remote(&root_location, None, state) {
                 Ok(root_dir) => search_directory_with_reader(file, &mut BufReader::new(&*root_dir)),
                 Err(VPFSError::OnlyInCache(cache_location)) => {
                     let dir_entry = search_directory(file, &cache_location.uri, state);
@@ -391,110 +2447,142 @@ fn recursive_find(file: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry
     }
 }
 
-fn handle_client_find(stream: &mut TcpStream, file: &str, state: &Arc<DaemonState>) {
-    send_message(stream, ClientResponse::Find(recursive_find(file, state)));
+fn handle_client_find(stream: &mut Connection, file: &str, state: &Arc<DaemonState>) {
+    let find_result = match recursive_find(file, state) {
+        Ok(dir_entry) => Ok(redirect_root(dir_entry, state)),
+        Err(VPFSError::CacheNeededForTraversal(dir_entry)) => Err(VPFSError::CacheNeededForTraversal(redirect_root(dir_entry, state))),
+        error => error,
+    };
+    send_message(stream, ClientResponse::Find(find_result));
 }
 
-fn place_file(path: &str, at: &Node, is_dir: bool, state: &Arc<DaemonState>) -> Result<Location, VPFSError>{
-    let uri = if *at == state.local {
-        create_file_with_random_uri()
-    }
-    else if let Ok(DaemonResponse::Place(uri)) = send_and_recive(at, DaemonRequest::Place, state) {
-        uri
-    }
-    else {
-        return Err(VPFSError::NotAccessible);
-    };
-    let new_file_location = Location {
-        node: at.clone(),
-        uri: uri
+fn place_file(path: &str, placement: Placement, is_dir: bool, mode: u16, user: &str, state: &Arc<DaemonState>) -> Result<Location, VPFSError>{
+    let permissions = Permissions {
+        owner: user.to_string(),
+        mode,
     };
+    let parent_path;
     let parent_directory_loaction;
+    let parent_directory_permissions;
     let file_name;
     if let Some((parent_directory, _file_name)) = path.rsplit_once('/') {
         let parent_directory_entry = recursive_find(parent_directory, state)?;
+        parent_path = Some(parent_directory);
         parent_directory_loaction = parent_directory_entry.location;
+        parent_directory_permissions = parent_directory_entry.permissions;
         file_name = _file_name;
     } 
-    else if let Some(root_node) = &state.root{
+    else if let Some(root_node) = current_root(state) {
+        parent_path = None;
         parent_directory_loaction = Location {
-            node: root_node.clone(),
+            node: root_node,
             uri: "root".to_string()
         };
+        parent_directory_permissions = root_permissions();
         file_name = path
     }
     else {
         return Err(VPFSError::NotAccessible);
     }
-    let mut dir_entry = DirectoryEntry {
-        location: new_file_location.clone(),
-        name: file_name.to_string(),
-        is_dir: is_dir
+    let node = match placement {
+        Placement::Node(node) => node,
+        Placement::Auto => choose_node(path, parent_path, &parent_directory_loaction, &any_node, state)?,
     };
-
-    let success= if parent_directory_loaction.node == state.local {
-        append_dir_entry(&parent_directory_loaction.uri, &dir_entry, state)
+    let at = &node;
+    let uri = if *at == state.local {
+        check_free_space(state)?;
+        let uri = create_file_with_random_uri(state);
+        set_permissions(&uri, permissions.clone(), state);
+        uri
+    }
+    else if let Ok(DaemonResponse::Place(place_result)) = send_and_recive(at, DaemonRequest::Place(permissions.clone()), state) {
+        place_result?
     }
     else {
-        match send_and_recive(&parent_directory_loaction.node, DaemonRequest::AppendDirectoryEntry(parent_directory_loaction.uri.clone(), dir_entry.clone()), state){
-            Ok(DaemonResponse::AppendDirectoryEntry(result)) => result,
-            Ok(_) => Err(VPFSError::Other("Bad response".to_string())),
-            Err(error) => Err(VPFSError::Other("Connection closed".to_string()))
-        }
+        return Err(VPFSError::NotAccessible);
+    };
+    let new_file_location = Location {
+        node: at.clone(),
+        uri,
+    };
+    let dir_entry = DirectoryEntry {
+        location: new_file_location.clone(),
+        name: file_name.to_string(),
+        is_dir,
+        permissions,
     };
-    // Add . and .. directory entries if new file is a directory
-    if success.is_ok() && is_dir {
+
+    // A directory gets its . and .. entries before it is linked into its parent, so a failure
+    // never leaves a half created directory in the tree
+    let created = if is_dir {
+        let self_entry = DirectoryEntry { name: ".".to_string(), ..dir_entry.clone() };
         let dot_dot_entry = DirectoryEntry {
             location: parent_directory_loaction.clone(),
             name: "..".to_string(),
             is_dir: true,
+            permissions: parent_directory_permissions,
         };
-        dir_entry.name = ".".to_string();
-        if *at == state.local {
-            let _ = append_dir_entry(&new_file_location.uri, &dir_entry, state);
-            let _ = append_dir_entry(&new_file_location.uri, &dot_dot_entry, state);
-        }
-        else {
-            send_and_recive::<_, DaemonResponse>(at, DaemonRequest::AppendDirectoryEntry(new_file_location.uri.clone(), dir_entry), state);
-            send_and_recive::<_, DaemonResponse>(at, DaemonRequest::AppendDirectoryEntry(new_file_location.uri.clone(), dot_dot_entry), state);
-        }
+        add_dir_entry(&new_file_location, self_entry, user, state).and_then(|_| add_dir_entry(&new_file_location, dot_dot_entry, user, state))
     }
-    else if let Err(error) = success {
+    else {
+        Ok(())
+    };
+    if let Err(error) = created.and_then(|_| add_dir_entry(&parent_directory_loaction, dir_entry, user, state)) {
         if *at == state.local {
-            fs::remove_file(&new_file_location.uri);
+            let _ = remove_local(&new_file_location.uri, state);
         }
         else {
-            send_and_recive::<_, DaemonResponse>(at, DaemonRequest::Remove(new_file_location.uri), state);
+            let _ = send_and_recive::<DaemonResponse>(at, DaemonRequest::Remove(new_file_location.uri, user.to_string()), state);
         }
         return Err(error);
     }
-    
     Ok(new_file_location)
 }
 
-fn handle_client_place(stream: &mut TcpStream, file: &str, node: Node, state: &Arc<DaemonState>) {
-    send_message(stream, ClientResponse::Place(place_file(file, &node, false, state)));
+// Appends an entry to a directory on any node, with the same permission checks either way
+fn add_dir_entry(directory: &Location, entry: DirectoryEntry, user: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if directory.node == state.local {
+        check_append(&directory.uri, &entry, user, state).and_then(|_| append_dir_entry(&directory.uri, &entry, state))
+    }
+    else {
+        match send_and_recive(&directory.node, DaemonRequest::AppendDirectoryEntry(directory.uri.clone(), entry, user.to_string()), state) {
+            Ok(DaemonResponse::AppendDirectoryEntry(result)) => result,
+            Ok(_) => Err(VPFSError::Other("Bad response".to_string())),
+            Err(_) => Err(VPFSError::NotAccessible),
+        }
+    }
+}
+
+fn handle_client_place(stream: &mut Connection, file: &str, placement: Placement, mode: u16, user: &str, state: &Arc<DaemonState>) {
+    send_message(stream, ClientResponse::Place(place_file(file, placement, false, mode, user, state)));
+}
+
+fn handle_client_mkdir(stream: &mut Connection, directory: &str, placement: Placement, mode: u16, user: &str, state: &Arc<DaemonState>) {
+    send_message(stream, ClientResponse::Mkdir(place_file(directory, placement, true, mode, user, state)));
 }
 
-fn handle_client_mkdir(stream: &mut TcpStream, directory: &str, node: Node, state: &Arc<DaemonState>) {
-    send_message(stream, ClientResponse::Mkdir(place_file(directory, &node, true, state)));
+fn handle_client_set_placement_policy(stream: &mut Connection, directory: &str, policy: Option<PlacementPolicy>, user: &str, state: &Arc<DaemonState>) {
+    send_message(stream, ClientResponse::SetPlacementPolicy(set_placement_policy_at(directory, policy, user, state)));
 }
 
-fn handle_client_read(stream: &mut TcpStream, location: Location, state: &Arc<DaemonState>) {
+fn handle_client_read(stream: &mut Connection, location: Location, user: &str, state: &Arc<DaemonState>) {
     if location.node == state.local {
-        if let Ok(buf) = read_local(&location.uri, &state.file_access_lock) {
-            send_message(stream, ClientResponse::Read(Ok(buf.len())));                    
-            stream.write_all(&buf);
+        if let Err(error) = check_access(&location.uri, user, Access::Read, state) {
+            send_message(stream, ClientResponse::Read(Err(error)));
+        }
+        else if let Ok(buf) = read_local(&location.uri, state) {
+            let hash = content_hash_for(&location.uri, &buf, state);
+            send_file_data(stream, &buf, |len| ClientResponse::Read(len.map(|len| (len, hash))));
         }
         else {
             send_message(stream, ClientResponse::Read(Err(VPFSError::DoesNotExist)));
         }
     }
     else  { 
-        match read_remote(&location, state) {
+        match read_remote(&location, Some(user), state) {
             Ok(buf) => {
-                send_message(stream, ClientResponse::Read(Ok(buf.len())));                    
-                stream.write_all(&buf);
+                let hash = content_hash(&buf);
+                send_file_data(stream, &buf, |len| ClientResponse::Read(len.map(|len| (len, hash))));
             }
             Err(error) => {
                 send_message(stream, ClientResponse::Read(Err(error)));
@@ -503,162 +2591,414 @@ fn handle_client_read(stream: &mut TcpStream, location: Location, state: &Arc<Da
     }
 }
 
-fn handle_client_write(stream: &mut TcpStream, location: Location, file_len: usize, state: &Arc<DaemonState>) {
+fn handle_client_write(stream: &mut Connection, location: Location, file_len: usize, user: &str, state: &Arc<DaemonState>) {
+    let buf = match receive_data(stream, file_len) {
+        Ok(buf) => buf,
+        Err(error) => {
+            send_message(stream, ClientResponse::Write(Err(VPFSError::BadFrame(error.to_string()))));
+            return;
+        }
+    };
     if location.node == state.local {
-        let mut buf = vec![0u8;file_len];
-        stream.read_exact(buf.as_mut()).unwrap();
-        if write_local(&location.uri, &buf, &state.file_access_lock).is_ok() {
-            send_message(stream, ClientResponse::Write(Ok(file_len)));
+        if let Err(error) = check_access(&location.uri, user, Access::Write, state) {
+            send_message(stream, ClientResponse::Write(Err(error)));
         }
         else {
-            send_message(stream, ClientResponse::Write(Err(VPFSError::DoesNotExist)));
+            send_message(stream, ClientResponse::Write(write_local(&location.uri, &buf, state).map(|_| file_len)));
         }
     }
-    else if let Some(file_owner_connection) = stream_for(&location.node, &state) {
-        let mut file_owner_connection = file_owner_connection.lock().unwrap();
-        let mut buf = vec![0u8; file_len];
-        stream.read_exact(&mut buf);
-        send_message(&mut file_owner_connection, DaemonRequest::Write(location.uri, file_len));
-        file_owner_connection.write_all(&buf);
-        if let Ok(DaemonResponse::Write(write_result)) = receive_message(&mut file_owner_connection) {
-            drop(file_owner_connection);
-            send_message(stream, ClientResponse::Write(write_result));
+    else if let Some(file_owner_connection) = stream_for(&location.node, state) {
+        let mut file_owner_connection = lock_connection(&file_owner_connection, &location.node, &["Write", "WriteChunks"], state);
+        let write_result = if file_owner_connection.supports(CHUNKS) {
+            write_remote_chunks(&mut file_owner_connection, &location, &buf, user, state)
         }
+        else {
+            send_request(&mut file_owner_connection, &location.node, DaemonRequest::Write(location.uri.clone(), file_len, user.to_string()), state);
+            let compress = file_owner_connection.supports(COMPRESSION);
+            let _ = send_data(&mut *file_owner_connection, &buf, compress);
+            match receive_message(&mut file_owner_connection) {
+                Ok(DaemonResponse::Write(write_result)) => write_result,
+                Ok(DaemonResponse::Error(error)) => Err(error),
+                _ => Err(VPFSError::NotAccessible),
+            }
+        };
+        drop(file_owner_connection);
+        send_message(stream, ClientResponse::Write(write_result));
     }
     else {
         send_message(stream, ClientResponse::Write(Err(VPFSError::NotAccessible)));
     }
 }
 
-fn handle_client(mut stream: TcpStream, state: Arc<DaemonState>) {
+fn handle_client_nodes(stream: &mut Connection, state: &Arc<DaemonState>) {
+    send_message(stream, ClientResponse::Nodes(cluster_view(state)));
+}
+
+fn handle_client_df(stream: &mut Connection, state: &Arc<DaemonState>) {
+    send_message(stream, ClientResponse::Df(cluster_capacity(state)));
+}
+
+fn handle_client_admin(stream: &mut Connection, request: AdminRequest, state: &Arc<DaemonState>) {
+    let response = match request {
+        AdminRequest::PromoteToRoot => AdminResponse::PromoteToRoot(promote_to_root(state)),
+        AdminRequest::Drain(node) => AdminResponse::Drain(drain(&node, state)),
+        AdminRequest::Rebalance => AdminResponse::Rebalance(rebalance(state)),
+        AdminRequest::InjectFaults(plan) => AdminResponse::InjectFaults(inject_faults(plan, state)),
+        AdminRequest::Shutdown => {
+            send_message(stream, ClientResponse::Admin(AdminResponse::Shutdown));
+            // Shutting down waits for this request to finish, so it can not happen on this thread
+            let state = state.clone();
+            thread::spawn(move || shutdown_daemon(&state, true));
+            return;
+        }
+    };
+    send_message(stream, ClientResponse::Admin(response));
+}
+
+// Only administrators may send admin requests, which act on the whole daemon or cluster
+fn handle_client(mut stream: Connection, user: String, administrator: bool, state: Arc<DaemonState>) {
     loop {
-        match receive_message(&mut stream) {
+        let request = receive_message(&mut stream);
+        let Some(_in_flight) = InFlight::begin(&state) else {
+            break;
+        };
+        match request {
             Ok(ClientRequest::Find(file)) => {
                 handle_client_find(&mut stream, &file, &state);
             },
-            Ok(ClientRequest::Place(file, node )) => {
-                handle_client_place(&mut stream, &file, node,  &state);
+            Ok(ClientRequest::Place(file, placement, mode)) => {
+                handle_client_place(&mut stream, &file, placement, mode, &user, &state);
+            }
+            Ok(ClientRequest::Mkdir(directory, placement, mode)) => {
+                handle_client_mkdir(&mut stream, &directory, placement, mode, &user, &state);
             }
-            Ok(ClientRequest::Mkdir(directory, node )) => {
-                handle_client_mkdir(&mut stream, &directory, node, &state);
+            Ok(ClientRequest::SetPlacementPolicy(directory, policy)) => {
+                handle_client_set_placement_policy(&mut stream, &directory, policy, &user, &state);
             }
             Ok(ClientRequest::Read(location)) => {
-                handle_client_read(&mut stream, location, &state);
+                handle_client_read(&mut stream, location, &user, &state);
             }
             Ok(ClientRequest::Write(location,len)) => {
-                handle_client_write(&mut stream, location, len, &state);
+                handle_client_write(&mut stream, location, len, &user, &state);
             }
-            Err(_) => {
+            Ok(ClientRequest::Nodes) => {
+                handle_client_nodes(&mut stream, &state);
+            }
+            Ok(ClientRequest::Df) => {
+                handle_client_df(&mut stream, &state);
+            }
+            Ok(ClientRequest::Admin(_)) if !administrator => {
+                eprintln!("Refused admin request from {user}");
+                send_message(&mut stream, ClientResponse::Error(VPFSError::PermissionDenied));
+            }
+            Ok(ClientRequest::Admin(request)) => {
+                handle_client_admin(&mut stream, request, &state);
+            }
+            Err(FrameError::Io(_)) => {
                 println!("Client diconnected");
                 break;
             }
+            Err(error) => {
+                eprintln!("Bad request from client: {error}");
+                send_message(&mut stream, ClientResponse::Error(VPFSError::BadFrame(error.to_string())));
+            }
         }
     }
 }
 
 /* ---------------------- Daemon connection handler functions ---------------------- */
-fn handle_daemon(mut stream: TcpStream, state: Arc<DaemonState>) {
-    loop {        
-        match receive_message_with_latceny(&mut stream, state.artificial_latency) {
-            Ok(DaemonRequest::Place)  => {
-                let response = DaemonResponse::Place(create_file_with_random_uri());
-                send_message(&mut stream, response);
-            }
-            Ok(DaemonRequest::Read( uri, last_modified )) => {
-                if let Some(remote_last_modified) = last_modified {
-                    let fs_lock = state.file_access_lock.read().unwrap();
-                    if let Ok(file_data) = fs::metadata(&uri) {
-                        if let Ok(local_last_modified) = file_data.modified() {
-                            if local_last_modified < remote_last_modified {
-                                drop(fs_lock);
-                                send_message(&mut stream, DaemonResponse::Read(Err(VPFSError::NotModified)));
-                                continue;
-                            }
-                        }
-                    }
+fn handle_daemon(mut stream: Connection, state: Arc<DaemonState>) {
+    loop {
+        let request = receive_message_with_latceny(&mut stream, state.artificial_latency);
+        let Some(_in_flight) = InFlight::begin(&state) else {
+            break;
+        };
+        match request {
+            Ok(DaemonRequest::Place(permissions))  => {
+                let place_result = check_free_space(&state).map(|_| {
+                    let uri = create_file_with_random_uri(&state);
+                    set_permissions(&uri, permissions, &state);
+                    uri
+                });
+                send_message(&mut stream, DaemonResponse::Place(place_result));
+            }
+            Ok(DaemonRequest::Read( uri, last_modified, user )) => {
+                if let Err(error) = check_read(&uri, last_modified, user.as_deref(), &state) {
+                    send_message(&mut stream, DaemonResponse::Read(Err(error)));
                 }
-                if let Ok(buf) = read_local(&uri, &state.file_access_lock) {
-                    send_message(&mut stream, DaemonResponse::Read(Ok(buf.len())));                    
-                    stream.write_all(&buf);
+                else if let Ok(buf) = read_local(&uri, &state) {
+                    let permissions = permissions_for(&uri, &state);
+                    let hash = content_hash_for(&uri, &buf, &state);
+                    send_file_data(&mut stream, &buf, |len| DaemonResponse::Read(len.map(|len| (len, permissions, hash))));
                 }
                 else {
                     send_message(&mut stream, DaemonResponse::Read(Err(VPFSError::DoesNotExist)));
                 }
             }
-            Ok(DaemonRequest::Write( uri, len)) => {
-                let mut buf = vec![0u8;len];
-                stream.read_exact(buf.as_mut()).unwrap();
-                if write_local(&uri, &buf, &state.file_access_lock).is_ok() {
-                    send_message(&mut stream, DaemonResponse::Write(Ok(len)));
+            Ok(DaemonRequest::Write( uri, len, user)) => {
+                let buf = match receive_data(&mut stream, len) {
+                    Ok(buf) => buf,
+                    Err(error) => {
+                        send_message(&mut stream, DaemonResponse::Write(Err(VPFSError::BadFrame(error.to_string()))));
+                        continue;
+                    }
+                };
+                if let Err(error) = check_access(&uri, &user, Access::Write, &state) {
+                    send_message(&mut stream, DaemonResponse::Write(Err(error)));
                 }
                 else {
-                    send_message(&mut stream, DaemonResponse::Write(Err(VPFSError::DoesNotExist)));
+                    send_message(&mut stream, DaemonResponse::Write(write_local(&uri, &buf, &state).map(|_| len)));
                 }
             }
-            Ok(DaemonRequest::AppendDirectoryEntry(directory,new_entry )) => {
-                send_message(&mut stream, DaemonResponse::AppendDirectoryEntry(append_dir_entry(&directory, &new_entry, &state)));
+            Ok(DaemonRequest::AppendDirectoryEntry(directory,new_entry, user)) => {
+                let append_result = check_append(&directory, &new_entry, &user, &state).and_then(|_| append_dir_entry(&directory, &new_entry, &state));
+                send_message(&mut stream, DaemonResponse::AppendDirectoryEntry(append_result));
             }
-            Ok(DaemonRequest::Remove(uri)) => {
-                let _fs_lock = state.file_access_lock.write().unwrap();
-                if fs::remove_file(uri).is_ok() {
-                    send_message(&mut stream, DaemonResponse::Remove(Ok(())));
-                } else {
-                    send_message(&mut stream, DaemonResponse::Remove(Err(VPFSError::DoesNotExist)));
-                }
+            Ok(DaemonRequest::Remove(uri, user)) => {
+                let remove_result = check_remove(&uri, &user, &state).and_then(|_| remove_local(&uri, &state));
+                send_message(&mut stream, DaemonResponse::Remove(remove_result));
+            }
+            Ok(DaemonRequest::Migrate(permissions, policy, len)) => {
+                let migrate_result = receive_data(&mut stream, len)
+                    .map_err(|error| VPFSError::BadFrame(error.to_string()))
+                    .and_then(|buf| store_local_copy(&buf, permissions, policy, &state));
+                send_message(&mut stream, DaemonResponse::Migrate(migrate_result));
+            }
+            Ok(DaemonRequest::ReplaceDirectoryEntry(directory, new_entry)) => {
+                send_message(&mut stream, DaemonResponse::ReplaceDirectoryEntry(replace_local_dir_entry(&directory, &new_entry, &state)));
+            }
+            Ok(DaemonRequest::RemoveNode(node)) => {
+                remove_node(&node, &state);
+                send_message(&mut stream, DaemonResponse::RemoveNode);
+            }
+            Ok(DaemonRequest::Leaving(node)) => {
+                println!("{} is shutting down", node.name);
+                drop_connection(&node, &state);
+                mark_unreachable(&node, &state);
+                send_message(&mut stream, DaemonResponse::Leaving);
+            }
+            Ok(DaemonRequest::ReadChunks(uri, last_modified, user)) => {
+                send_message(&mut stream, DaemonResponse::ReadChunks(read_local_chunks(&uri, last_modified, user.as_deref(), &state)));
+            }
+            Ok(DaemonRequest::FetchChunks(uri, hashes, user)) => {
+                send_chunks(&mut stream, &uri, &hashes, user.as_deref(), &state);
+            }
+            Ok(DaemonRequest::WriteChunks(uri, manifest, included, user)) => {
+                let write_result = receive_chunks(&mut stream, &manifest, &included)
+                    .and_then(|chunks| check_access(&uri, &user, Access::Write, &state).map(|_| chunks))
+                    .and_then(|chunks| write_local_chunks(&uri, manifest, chunks, &state));
+                send_message(&mut stream, DaemonResponse::WriteChunks(write_result));
             }
             Ok(DaemonRequest::AddressFor(node)) => {
-                let known_hosts_lock = state.known_hosts.lock().unwrap();
-                if let Some(known_hosts) = known_hosts_lock.as_ref() {
-                    send_message(&mut stream, DaemonResponse::AddressFor(known_hosts.get(&node).map(|entry| -> String {entry.clone()})));
-                }
-                send_message(&mut stream, DaemonResponse::AddressFor(None));
+                let known_hosts = state.known_hosts.lock().unwrap();
+                let address = known_hosts.get(&node).filter(|entry| !entry.removed).map(|entry| entry.address.clone());
+                send_message(&mut stream, DaemonResponse::AddressFor(address));
+            }
+            Ok(DaemonRequest::Capacity) => {
+                send_message(&mut stream, DaemonResponse::Capacity(local_capacity(&state)));
+            }
+            Ok(DaemonRequest::PlacementPolicy(directory)) => {
+                let policy = state.placement_policies.lock().unwrap().get(&directory).cloned();
+                send_message(&mut stream, DaemonResponse::PlacementPolicy(policy));
+            }
+            Ok(DaemonRequest::SetPlacementPolicy(directory, policy, user)) => {
+                send_message(&mut stream, DaemonResponse::SetPlacementPolicy(set_placement_policy(&directory, policy, &user, &state)));
+            }
+            Ok(DaemonRequest::Heartbeat) => {
+                send_message(&mut stream, DaemonResponse::Heartbeat);
+            }
+            Ok(DaemonRequest::ClusterInfo) => {
+                let standbys = state.standbys.lock().unwrap().clone();
+                send_message(&mut stream, DaemonResponse::ClusterInfo(known_hosts_with_local(&state), standbys));
+            }
+            Ok(DaemonRequest::Gossip(peer_known_hosts)) => {
+                merge_known_hosts(peer_known_hosts, &state);
+                send_message(&mut stream, DaemonResponse::Gossip(known_hosts_with_local(&state)));
+            }
+            Ok(DaemonRequest::PromoteToRoot) => {
+                send_message(&mut stream, DaemonResponse::PromoteToRoot(promote_to_root(&state)));
+            }
+            Ok(DaemonRequest::NewRoot(root_node)) => {
+                println!("{} is now the root node", root_node.name);
+                *state.root.write().unwrap() = Some(root_node);
+                *state.root_missed_heartbeats.lock().unwrap() = 0;
+                save_state(&state);
+                send_message(&mut stream, DaemonResponse::NewRoot);
+            }
+            Ok(DaemonRequest::RootEpoch(root_node, epoch)) => {
+                adopt_root(root_node, epoch, &state);
+                let root_epoch = *state.root_epoch.lock().unwrap();
+                send_message(&mut stream, DaemonResponse::RootEpoch(current_root(&state), root_epoch));
+            }
+            Ok(DaemonRequest::RootDown(root_node)) => {
+                let root_down = current_root(&state).as_ref() == Some(&root_node) && is_known_down(&root_node, &state);
+                send_message(&mut stream, DaemonResponse::RootDown(root_down));
             }
-            Err(_) => {
+            Err(FrameError::Io(_)) => {
                 println!("Daemon dissconnected");
                 break;
             }
+            Err(error) => {
+                eprintln!("Bad request from daemon: {error}");
+                send_message(&mut stream, DaemonResponse::Error(VPFSError::BadFrame(error.to_string())));
+            }
         }
     }
 }
 
 /* ------------------------------- Set up functions -------------------------------- */
-fn handle_connection(mut stream: TcpStream, state: Arc<DaemonState>) {
-    match receive_message_with_latceny(&mut stream, state.artificial_latency) {
-        Ok(Hello::ClientHello) => {
-            println!("User process connected");
-            send_message(&mut stream, HelloResponse::ClientHello(state.local.clone()));
-            handle_client(stream, state);
+fn accept_client(mut stream: Connection, user: String, administrator: bool, negotiated: NegotiatedProtocol, state: Arc<DaemonState>) {
+    let Some(_client_slot) = ClientSlot::take(&state) else {
+        eprintln!("Too many user processes, turning one away");
+        send_unframed(&mut stream, busy_response(negotiated.version));
+        return;
+    };
+    println!("User process connected as {user}");
+    let _ = stream.set_read_timeout(None);
+    stream.protocol = Some(negotiated.clone());
+    send_unframed(&mut stream, HelloResponse::ClientHello(negotiated, state.local.clone()));
+    handle_client(stream, user, administrator, state);
+}
+
+// Peers without a protocol version in common are told why before the connection is closed
+fn receive_hello(stream: &mut Connection, state: &Arc<DaemonState>) -> Option<(Hello, NegotiatedProtocol)> {
+    let Ok(hello) = receive_unframed::<Hello>(stream, state.artificial_latency) else {
+        eprintln!("Did not recive proper hello message");
+        send_unframed(stream, HelloResponse::Rejected("Could not understand hello, the peer may speak an incompatible protocol version".to_string()));
+        return None;
+    };
+    match local_protocol(state).negotiate(hello.protocol()) {
+        Ok(negotiated) => Some((hello, negotiated)),
+        Err(reason) => {
+            eprintln!("Rejected peer: {reason}");
+            send_unframed(stream, HelloResponse::Rejected(reason));
+            None
+        }
+    }
+}
+
+fn handle_connection(mut stream: Connection, state: Arc<DaemonState>) {
+    let _ = stream.set_read_timeout(Some(HELLO_TIMEOUT));
+    match receive_hello(&mut stream, &state) {
+        Some((Hello::ClientHello(..) | Hello::AuthenticatedClientHello(..), _)) if !state.config.tcp_clients => {
+            eprintln!("Rejected user process connecting over TCP");
+            send_unframed(&mut stream, HelloResponse::Rejected("User processes must connect through the daemon's Unix domain socket".to_string()));
+        },
+        Some((Hello::ClientHello(_, user), _)) if user != NOBODY => {
+            eprintln!("Rejected user process claiming to be {user} without the cluster key");
+            send_unframed(&mut stream, HelloResponse::Rejected(format!("Only user processes holding the cluster key may act as a user over TCP, others act as {NOBODY}")));
+        },
+        Some((Hello::ClientHello(_, user), negotiated)) => {
+            accept_client(stream, user, false, negotiated, state);
+        },
+        Some((Hello::AuthenticatedClientHello(..), _)) if state.cluster_key.is_none() => {
+            send_unframed(&mut stream, HelloResponse::Rejected("The daemon has no cluster key to authenticate user processes with".to_string()));
+        },
+        Some((Hello::AuthenticatedClientHello(_, user), negotiated)) => {
+            // Holding the cluster key is as good as being a daemon
+            if let Some(stream) = authenticate_peer(stream, false, &state) {
+                accept_client(stream, user, true, negotiated, state);
+            }
+            else {
+                eprintln!("Rejected user process that could not prove it holds the cluster key");
+            }
+        },
+        Some((hello, negotiated)) => {
+            if let Some(stream) = authenticate_peer(stream, state.config.encrypt, &state) {
+                handle_daemon_hello(stream, hello, negotiated, state);
+            }
+            else {
+                eprintln!("Rejected daemon that could not prove it holds the cluster key");
+            }
+        }
+        None => {}
+    }
+}
+
+// Processes connecting through the Unix domain socket act as the user running them, whose uid the
+// socket reports. Those run by root or by the daemon's own user are administrators
+fn handle_local_connection(mut stream: Connection, uid: u32, state: Arc<DaemonState>) {
+    let _ = stream.set_read_timeout(Some(HELLO_TIMEOUT));
+    let local_user = user_name(uid);
+    let administrator = uid == 0 || uid == unsafe { libc::geteuid() };
+    match receive_hello(&mut stream, &state) {
+        Some((Hello::ClientHello(_, user), negotiated)) if user == local_user => {
+            accept_client(stream, user, administrator, negotiated, state);
+        },
+        Some((Hello::ClientHello(_, user), _)) => {
+            eprintln!("Rejected local process run by {local_user} claiming to be {user}");
+            send_unframed(&mut stream, HelloResponse::Rejected(format!("Processes connecting through the Unix domain socket act as the user running them, {local_user}")));
         },
-        Ok(Hello::DaemonHello) => {
+        Some(_) => {
+            send_unframed(&mut stream, HelloResponse::Rejected("Only user processes may connect through the Unix domain socket".to_string()));
+        }
+        None => {}
+    }
+}
+
+fn handle_daemon_hello(mut stream: Connection, hello: Hello, negotiated: NegotiatedProtocol, state: Arc<DaemonState>) {
+    let _ = stream.set_read_timeout(None);
+    stream.protocol = Some(negotiated.clone());
+    match hello {
+        Hello::DaemonHello(_) => {
             println!("Daemon process connected");
-            send_message(&mut stream, HelloResponse::DaemonHello);
+            send_unframed(&mut stream, HelloResponse::DaemonHello(negotiated));
             handle_daemon(stream, state);
         }
-        Ok(Hello::RootHello(connecting_node, connecting_address)) => {
-            println!("Daemon process connected to root, is listening on {connecting_address}");
-            {
-                let mut known_hosts = state.known_hosts.lock().unwrap();
-                known_hosts.as_mut().unwrap().insert(connecting_node, connecting_address);
-                send_message(&mut stream, HelloResponse::RootHello(state.root.clone().unwrap(), known_hosts.clone().unwrap()));
+        Hello::RootHello(_, connecting_node, connecting_entry) => {
+            println!("Daemon process connected to root, is listening on {}", connecting_entry.address);
+            merge_known_hosts(HashMap::from([(connecting_node.clone(), connecting_entry)]), &state);
+            // A standby restarted with --no-standby is not one anymore
+            let (standbys, was_standby) = {
+                let mut standbys = state.standbys.lock().unwrap();
+                let count = standbys.len();
+                standbys.retain(|standby| *standby != connecting_node);
+                (standbys.clone(), standbys.len() != count)
+            };
+            if was_standby {
+                save_state(&state);
             }
+            send_unframed(&mut stream, HelloResponse::RootHello(negotiated, current_root(&state).unwrap(), known_hosts_with_local(&state), standbys));
+            handle_daemon(stream, state);
+        },
+        Hello::StandbyHello(_, connecting_node, connecting_entry) => {
+            println!("Standby root connected, is listening on {}", connecting_entry.address);
+            merge_known_hosts(HashMap::from([(connecting_node.clone(), connecting_entry)]), &state);
+            let standbys = {
+                let mut standbys = state.standbys.lock().unwrap();
+                if !standbys.contains(&connecting_node) {
+                    standbys.push(connecting_node);
+                }
+                standbys.clone()
+            };
+            save_state(&state);
+            send_unframed(&mut stream, HelloResponse::RootHello(negotiated, current_root(&state).unwrap(), known_hosts_with_local(&state), standbys));
             handle_daemon(stream, state);
         },
-        Err(_) => {eprintln!("Did not recive proper hello message")},
+        Hello::ClientHello(..) | Hello::AuthenticatedClientHello(..) => unreachable!("Client hellos are handled before authentication"),
     }
 }
 
 // Create a TCP listener to accept incoming connections
-fn start_server(address: &str, state: Arc<DaemonState>) {
-    let listener = TcpListener::bind(address).unwrap();
+// Accepts connections until the daemon shuts down
+fn serve(listener: TcpListener, state: Arc<DaemonState>) {
     println!("Listening for connections");
     for stream in listener.incoming() {
+        if is_shutting_down(&state) {
+            break;
+        }
         match stream {
             Ok(stream) => {
                 println!("Incomming connections");
-                let state_clone = state.clone();
-                thread::spawn(move || {
-                    handle_connection(stream, state_clone); 
-                });
+                let (state_clone, expired_state) = (state.clone(), state.clone());
+                let served = state.workers.execute(stream, move |stream| {
+                    let socket_id = track_socket(stream.try_clone().map(OpenSocket::Tcp), &state_clone);
+                    handle_connection(Connection::new(stream), state_clone.clone());
+                    untrack_socket(socket_id, &state_clone);
+                }, move |stream| turn_away(Connection::new(stream), &expired_state));
+                if let Err(stream) = served {
+                    turn_away(Connection::new(stream), &state);
+                }
             }
             Err(e) => {
                 eprintln!("Connection failed: {}", e);
@@ -667,83 +3007,763 @@ fn start_server(address: &str, state: Arc<DaemonState>) {
     }
 }
 
-fn setup_files_dir() {
-    if let Err(err) = fs::create_dir("./files") {
-        if err.kind() != std::io::ErrorKind::AlreadyExists {
-            panic!("Could not create directory for storing files");
+// Connections over a MemoryNetwork, which hands them over instead of the daemon accepting them
+fn accept_memory_stream(stream: MemoryStream, state: &Arc<DaemonState>) {
+    let (state_clone, expired_state) = (state.clone(), state.clone());
+    let served = state.workers.execute(stream, move |stream| {
+        let socket_id = track_socket(Ok(OpenSocket::Memory(stream.closer())), &state_clone);
+        if socket_id.is_some() {
+            handle_connection(Connection::new(stream), state_clone.clone());
         }
+        untrack_socket(socket_id, &state_clone);
+    }, move |stream| turn_away(Connection::new(stream), &expired_state));
+    if let Err(stream) = served {
+        turn_away(Connection::new(stream), state);
     }
-    std::env::set_current_dir("./files").expect("Could not cd into ./files directory");
 }
 
-fn create_root(listen_port: u16, mut state: DaemonState) {
-    setup_files_dir();
-    restore_cache(&mut state);
-    *state.known_hosts.lock().unwrap() = Some(HashMap::new());
-    let state_arc = Arc::new(state);
-    if let Err(create_error) = fs::File::create_new("root") {
+// How long a connection turned away as busy has to send its hello, so that a flood of
+// connections can only slow down turning away others
+const BUSY_HELLO_TIMEOUT: Duration = Duration::from_millis(100);
+// Connections are turned away on threads of their own, so accepting is never held up by them
+const TURNING_AWAY_THREADS: usize = 2;
+// Workers on top of max_connections, which only links from other daemons may use. User processes
+// can then not crowd out the daemons the cluster needs to serve their requests
+const RESERVED_DAEMON_WORKERS: usize = 16;
+// Longest a connection waits for a worker before it is turned away as busy
+const MAX_CONNECTION_WAIT: Duration = Duration::from_secs(2);
+// How long a peer has to complete its hello, and a connecting daemon its authentication
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Connections that even the threads turning connections away have no room for are just closed
+fn turn_away(stream: Connection, state: &Arc<DaemonState>) {
+    let _ = state.turning_away.execute(stream, reject_busy, drop);
+}
+
+// Reads the hello before answering, since closing a connection with unread data may reset it
+// before the peer reads the answer
+fn reject_busy(mut stream: Connection) {
+    eprintln!("Too many connections, turning one away");
+    let _ = stream.set_read_timeout(Some(BUSY_HELLO_TIMEOUT));
+    if let Ok(hello) = receive_unframed::<Hello>(&mut stream, Duration::from_millis(0)) {
+        send_unframed(&mut stream, busy_response(hello.protocol().max_version));
+    }
+}
+
+fn busy_response(version: u32) -> HelloResponse {
+    if version >= BUSY_VERSION {
+        HelloResponse::Busy
+    }
+    else {
+        HelloResponse::Rejected("The daemon is serving too many connections, try again later".to_string())
+    }
+}
+
+// Counts a user process against max_connections for as long as it is served
+struct ClientSlot {
+    state: Arc<DaemonState>,
+}
+
+impl ClientSlot {
+    fn take(state: &Arc<DaemonState>) -> Option<ClientSlot> {
+        let mut clients = state.clients.lock().unwrap();
+        if *clients >= state.config.max_connections {
+            return None;
+        }
+        *clients += 1;
+        Some(ClientSlot { state: state.clone() })
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        *self.state.clients.lock().unwrap() -= 1;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut credentials: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut credentials as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if result == 0 {Ok(credentials.uid)} else {Err(io::Error::last_os_error())}
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut uid = 0;
+    let mut gid = 0;
+    let result = unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+    if result == 0 {Ok(uid)} else {Err(io::Error::last_os_error())}
+}
+
+// Create a Unix domain socket listener for user processes on the local machine. Processes of any
+// user may connect, and act as the user running them
+fn start_local_server(socket_path: &str, state: &Arc<DaemonState>) -> Result<JoinHandle<()>, String> {
+    // A socket file left behind by a previous run would make bind fail
+    let _ = fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path).map_err(|error| format!("Could not create Unix domain socket: {error}"))?;
+    fs::set_permissions(socket_path, fs::Permissions::from_mode(0o666)).map_err(|error| format!("Could not open up Unix domain socket permissions: {error}"))?;
+    let state = state.clone();
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            if is_shutting_down(&state) {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    match peer_uid(&stream) {
+                        Ok(uid) => {
+                            let (state_clone, expired_state) = (state.clone(), state.clone());
+                            let served = state.workers.execute(stream, move |stream| {
+                                let socket_id = track_socket(stream.try_clone().map(OpenSocket::Unix), &state_clone);
+                                handle_local_connection(Connection::new(stream), uid, state_clone.clone());
+                                untrack_socket(socket_id, &state_clone);
+                            }, move |stream| turn_away(Connection::new(stream), &expired_state));
+                            if let Err(stream) = served {
+                                turn_away(Connection::new(stream), &state);
+                            }
+                        }
+                        Err(error) => eprintln!("Rejected local connection whose user could not be told: {error}"),
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Connection failed: {}", e);
+                }
+            }
+        }
+    }))
+}
+
+// Default data directory, relative to where the daemon is started
+const FILES_DIR: &str = "files";
+
+fn create_root_directory(state: &Arc<DaemonState>) {
+    if let Err(create_error) = state.storage.create_new("root") {
         if create_error.kind() != io::ErrorKind::AlreadyExists {
             panic!("Could not create root directory");
         }
     }
     else {
+        set_permissions("root", root_permissions(), state);
         let mut self_link = DirectoryEntry {
-            location: Location { node: state_arc.local.clone(), uri: "root".to_string() },
+            location: Location { node: state.local.clone(), uri: "root".to_string() },
             name: ".".to_string(),
-            is_dir: true
+            is_dir: true,
+            permissions: root_permissions(),
         };
-        let _ = append_dir_entry("root", &self_link, &state_arc);
+        let _ = append_dir_entry("root", &self_link, state);
         self_link.name = "..".to_string();
-        let _ = append_dir_entry("root", &self_link, &state_arc);
+        let _ = append_dir_entry("root", &self_link, state);
     }
-    start_server(&format!("0.0.0.0:{listen_port}"), state_arc);
 }
 
-fn create(listen_port: u16, mut state: DaemonState, root_addr: String, listening_addr: String) {
-    setup_files_dir();
+fn create_root(mut state: DaemonState) -> Arc<DaemonState> {
     restore_cache(&mut state);
-    if let Ok(root_connection) = TcpStream::connect(&root_addr) {
-        serde_bare::to_writer(&root_connection, &Hello::RootHello(state.local.clone(), listening_addr)).unwrap();
-        if let Ok(HelloResponse::RootHello(root_node, host_names)) = serde_bare::from_reader(&root_connection,) {
-            let mut known_hosts = state.known_hosts.lock().unwrap();
-            *known_hosts = Some(host_names);
-            known_hosts.as_mut().unwrap().insert(root_node.clone(), root_addr);
-            state.root = Some(root_node);
+    state.used_bytes = Mutex::new(stored_bytes(&state));
+    let state_arc = Arc::new(state);
+    create_root_directory(&state_arc);
+    save_state(&state_arc);
+    state_arc
+}
+
+// Registers with the root if it is reachable. Otherwise the daemon carries on with the root and
+// known hosts saved by its previous run, and finds the rest of the cluster through gossip
+fn create(mut state: DaemonState, root_addr: String) -> Result<Arc<DaemonState>, String> {
+    restore_cache(&mut state);
+    state.used_bytes = Mutex::new(stored_bytes(&state));
+    let state = Arc::new(state);
+    if let Ok(root_connection) = state.network.connect(&root_addr, state.connect_timeout) {
+        let local_entry = state.local_entry.clone().unwrap();
+        let hello = if state.config.standby {
+            Hello::StandbyHello(local_protocol(&state), state.local.clone(), local_entry)
+        }
+        else {
+            Hello::RootHello(local_protocol(&state), state.local.clone(), local_entry)
+        };
+        let hello_response = daemon_handshake(Connection::new(root_connection), hello, &state).map(|(_, response)| response);
+        if let Ok(HelloResponse::RootHello(_, root_node, host_names, standbys)) = hello_response {
+            merge_known_hosts(host_names, &state);
+            // Generation 0 so the root's own entry, if it advertises one, takes precedence
+            state.known_hosts.lock().unwrap().entry(root_node.clone()).or_insert(HostEntry { address: root_addr, generation: 0, removed: false });
+            *state.root.write().unwrap() = Some(root_node.clone());
+            *state.standbys.lock().unwrap() = standbys;
+            // The hello predates root epochs, so ask the root for its epoch
+            exchange_root_epoch(&root_node, &state);
+        }
+        else if let Err(error) = hello_response {
+            return Err(format!("Could not register with root: {error}"));
         }
         else {
-            panic!("Bad hello reponce");
+            return Err("Bad hello reponce".to_string());
         }
     }
-    start_server(&format!("0.0.0.0:{listen_port}"), Arc::new(state));
+    else {
+        println!("Could not reach root at {root_addr}, using saved cluster state");
+    }
+    save_state(&state);
+    Ok(state)
 }
 
-fn main() {
+/* ----------------------------------- Embedding ------------------------------------ */
+// Sockets of the connections being served, so that shutting down can close them
+#[derive(Default)]
+struct OpenSockets {
+    next_id: u64,
+    sockets: HashMap<u64, OpenSocket>,
+}
 
-    let opt = Opt::parse();
+enum OpenSocket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Memory(MemoryStreamCloser),
+}
 
-    let state = DaemonState {
-        root: if opt.root_addr.is_some() {None} else {Some(Node{name: opt.name.clone()})},
-        local: Node{name: opt.name},
-        connections: Mutex::new(HashMap::new()),
-        known_hosts: Mutex::new(None),
-        cache: Mutex::new(LruCache::unbounded()),
-        max_cache_size: opt.cache_size,
-        used_cache_bytes: RwLock::new(0),
-        artificial_latency: Duration::from_millis(opt.artificial_latency),
-        file_access_lock: RwLock::new(()),
-    };
+impl OpenSocket {
+    fn shutdown(&self) {
+        let _ = match self {
+            OpenSocket::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            OpenSocket::Unix(stream) => stream.shutdown(Shutdown::Both),
+            OpenSocket::Memory(closer) => {
+                closer.close();
+                Ok(())
+            }
+        };
+    }
+}
+
+fn is_shutting_down(state: &Arc<DaemonState>) -> bool {
+    state.shutting_down.load(Ordering::SeqCst)
+}
+
+// A connection accepted while shutting down is closed straight away
+fn track_socket(socket: io::Result<OpenSocket>, state: &Arc<DaemonState>) -> Option<u64> {
+    let socket = socket.ok()?;
+    let mut open_sockets = state.open_sockets.lock().unwrap();
+    if is_shutting_down(state) {
+        socket.shutdown();
+        return None;
+    }
+    let socket_id = open_sockets.next_id;
+    open_sockets.next_id += 1;
+    open_sockets.sockets.insert(socket_id, socket);
+    Some(socket_id)
+}
+
+fn untrack_socket(socket_id: Option<u64>, state: &Arc<DaemonState>) {
+    if let Some(socket_id) = socket_id {
+        state.open_sockets.lock().unwrap().sockets.remove(&socket_id);
+    }
+}
+
+// Requests that take longer than this are cut off by shutting down
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+// Counts a request as in flight until dropped. Requests that arrive after shutting down has
+// started are not served, and their connection is closed
+struct InFlight<'a> {
+    state: &'a Arc<DaemonState>,
+}
+
+impl InFlight<'_> {
+    fn begin(state: &Arc<DaemonState>) -> Option<InFlight<'_>> {
+        let mut in_flight = state.in_flight.lock().unwrap();
+        if is_shutting_down(state) {
+            return None;
+        }
+        *in_flight += 1;
+        Some(InFlight { state })
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        *self.state.in_flight.lock().unwrap() -= 1;
+        self.state.requests_done.notify_all();
+    }
+}
+
+// Waits for the requests being served, then saves everything that is only written now and then
+// and tells the rest of the cluster the node is leaving, so they stop sending it requests
+fn leave_cluster(state: &Arc<DaemonState>) {
+    let in_flight = state.in_flight.lock().unwrap();
+    let (in_flight, _) = state.requests_done.wait_timeout_while(in_flight, SHUTDOWN_GRACE_PERIOD, |in_flight| *in_flight > 0).unwrap();
+    if *in_flight > 0 {
+        eprintln!("Gave up waiting for {} requests", *in_flight);
+    }
+    drop(in_flight);
+    {
+        let cache = state.cache.lock().unwrap();
+        save_cache_index(&cache, *state.used_cache_bytes.read().unwrap(), state);
+    }
+    save_state(state);
+    for peer in remote_nodes(state) {
+        let _ = send_if_spoken::<DaemonResponse>(&peer, LEAVING_VERSION, DaemonRequest::Leaving(state.local.clone()), state);
+    }
+}
+
+// Stops accepting connections and closes every open one. Background threads notice on their own.
+// A graceful shutdown first finishes the requests being served and leaves the cluster, otherwise
+// the daemon stops as if it crashed
+fn shutdown_daemon(state: &Arc<DaemonState>, graceful: bool) {
+    if state.shutting_down.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    println!("Shutting down");
+    if graceful {
+        leave_cluster(state);
+    }
+    // The accept loops check for shutdown after every connection they accept
+    if state.port != 0 {
+        let _ = TcpStream::connect(("127.0.0.1", state.port));
+    }
+    if let Some(socket_path) = &state.config.socket {
+        let _ = UnixStream::connect(socket_path);
+    }
+    state.workers.close();
+    state.turning_away.close();
+    for socket in state.open_sockets.lock().unwrap().sockets.values() {
+        socket.shutdown();
+    }
+    state.connections.lock().unwrap().clear();
+}
+
+// A daemon running in this process. Dropping the handle shuts the daemon down
+pub struct Daemon {
+    state: Arc<DaemonState>,
+    addr: Option<SocketAddr>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Daemon {
+    pub fn builder() -> DaemonBuilder {
+        DaemonBuilder::default()
+    }
+
+    // Address the daemon accepts connections from other daemons and user processes on. None if
+    // it was given a network to use instead of TCP
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    // 0 if the daemon does not listen on TCP
+    pub fn port(&self) -> u16 {
+        self.addr.map_or(0, |addr| addr.port())
+    }
+
+    pub fn node(&self) -> &Node {
+        &self.state.local
+    }
+
+    // Blocks until the daemon has shut down
+    pub fn wait(mut self) {
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+
+    // Serves a connection handed over by a MemoryNetwork, on a thread of its own
+    pub fn accept(&self, stream: MemoryStream) {
+        accept_memory_stream(stream, &self.state);
+    }
+
+    // Lets a MemoryNetwork hand connections to the daemon. Keeps the daemon's state alive until
+    // the network stops listening
+    pub fn acceptor(&self) -> impl Fn(MemoryStream) + Send + Sync + 'static {
+        let state = self.state.clone();
+        move |stream| accept_memory_stream(stream, &state)
+    }
+
+    // Sends one round of heartbeats, syncs with the root and gossips, all on the calling thread.
+    // Daemons built with manual_heartbeats only do this when told to
+    pub fn heartbeat(&self) {
+        heartbeat_round(&self.state);
+    }
+
+    // Lets another thread shut the daemon down, for example on a signal, while this one waits
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle { state: self.state.clone() }
+    }
+
+    // Files and state stay in the daemon's storage, so a daemon built on the same data directory
+    // later carries on where this one stopped
+    pub fn shutdown(self) {
+        drop(self);
+    }
+
+    // Stops the daemon without finishing its requests or telling its peers, as if it crashed
+    pub fn kill(self) {
+        shutdown_daemon(&self.state, false);
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        shutdown_daemon(&self.state, true);
+        for thread in self.threads.drain(..) {
+            // The heartbeat thread sleeps by parking, so it does not hold up the shutdown
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+        if let Some(socket_path) = &self.state.config.socket {
+            let _ = fs::remove_file(socket_path);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<DaemonState>,
+}
+
+impl ShutdownHandle {
+    // Shuts the daemon down gracefully and returns once it has stopped serving requests. Threads
+    // waiting on the daemon return soon after
+    pub fn shutdown(&self) {
+        shutdown_daemon(&self.state, true);
+    }
+}
+
+// Starts a daemon in this process. Anything left unset falls back to the values saved in the data
+// directory by a previous run, and then to the defaults, just like the daemon's command line options
+#[derive(Default)]
+pub struct DaemonBuilder {
+    opt: Opt,
+    storage: Option<Box<dyn Storage>>,
+    network: Option<Arc<dyn Network>>,
+    clock: Option<Arc<dyn Clock>>,
+    seed: Option<u64>,
+    manual_heartbeats: bool,
+}
+
+impl DaemonBuilder {
+    // Replaces every option set so far
+    pub fn options(mut self, opt: Opt) -> Self {
+        self.opt = opt;
+        self
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.opt.name = Some(name.to_string());
+        self
+    }
+
+    // Port 0 lets the OS pick a free port, see Daemon::port
+    pub fn port(mut self, port: u16) -> Self {
+        self.opt.port = Some(port);
+        self
+    }
+
+    pub fn root_addr(mut self, root_addr: &str) -> Self {
+        self.opt.root_addr = Some(root_addr.to_string());
+        self
+    }
+
+    // A port of 0 in the address is replaced with the port the daemon listens on
+    pub fn listening_addr(mut self, listening_addr: &str) -> Self {
+        self.opt.listening_addr = Some(listening_addr.to_string());
+        self
+    }
+
+    pub fn cache_size(mut self, cache_size: usize) -> Self {
+        self.opt.cache_size = Some(cache_size);
+        self
+    }
+
+    pub fn artificial_latency(mut self, artificial_latency: Duration) -> Self {
+        self.opt.artificial_latency = Some(artificial_latency.as_millis() as u64);
+        self
+    }
+
+    pub fn heartbeat_interval(mut self, heartbeat_interval: Duration) -> Self {
+        self.opt.heartbeat_interval = Some(heartbeat_interval.as_millis() as u64);
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.opt.connect_timeout = Some(connect_timeout.as_millis() as u64);
+        self
+    }
+
+    pub fn failover_after(mut self, missed_heartbeats: u32) -> Self {
+        self.opt.failover_after = Some(missed_heartbeats);
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.opt.max_connections = Some(max_connections);
+        self
+    }
+
+    pub fn connection_queue(mut self, connection_queue: usize) -> Self {
+        self.opt.connection_queue = Some(connection_queue);
+        self
+    }
 
-    if let Some(root_addr) = opt.root_addr{
-        println!("running");
-        if let Some(listening_addr) = opt.listening_addr {
-            create(opt.port, state, root_addr, listening_addr);
+    pub fn standby(mut self) -> Self {
+        self.opt.standby = true;
+        self.opt.no_standby = false;
+        self
+    }
+
+    pub fn no_standby(mut self) -> Self {
+        self.opt.no_standby = true;
+        self.opt.standby = false;
+        self
+    }
+
+    pub fn key_file(mut self, key_file: &str) -> Self {
+        self.opt.key_file = Some(key_file.to_string());
+        self
+    }
+
+    pub fn encrypt(mut self) -> Self {
+        self.opt.encrypt = true;
+        self.opt.no_encrypt = false;
+        self
+    }
+
+    pub fn no_encrypt(mut self) -> Self {
+        self.opt.no_encrypt = true;
+        self.opt.encrypt = false;
+        self
+    }
+
+    pub fn socket(mut self, socket_path: &str) -> Self {
+        self.opt.socket = Some(socket_path.to_string());
+        self
+    }
+
+    pub fn tcp_clients(mut self) -> Self {
+        self.opt.tcp_clients = true;
+        self.opt.no_tcp_clients = false;
+        self
+    }
+
+    pub fn no_tcp_clients(mut self) -> Self {
+        self.opt.no_tcp_clients = true;
+        self.opt.tcp_clients = false;
+        self
+    }
+
+    pub fn quota(mut self, quota: u64) -> Self {
+        self.opt.quota = Some(quota);
+        self.opt.no_quota = false;
+        self
+    }
+
+    pub fn no_quota(mut self) -> Self {
+        self.opt.no_quota = true;
+        self.opt.quota = None;
+        self
+    }
+
+    pub fn chunked(mut self) -> Self {
+        self.opt.chunked = true;
+        self.opt.no_chunked = false;
+        self
+    }
+
+    pub fn no_chunked(mut self) -> Self {
+        self.opt.no_chunked = true;
+        self.opt.chunked = false;
+        self
+    }
+
+    pub fn data_dir(mut self, data_dir: &str) -> Self {
+        self.opt.data_dir = Some(data_dir.to_string());
+        self
+    }
+
+    pub fn in_memory(mut self) -> Self {
+        self.opt.in_memory = true;
+        self
+    }
+
+    // Takes precedence over data_dir and in_memory
+    pub fn storage(mut self, storage: Box<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    // Other daemons are reached through the network instead of TCP, and the daemon does not
+    // listen on a port. Connections to it must be handed to Daemon::accept
+    pub fn network(mut self, network: Arc<dyn Network>) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    // Seeds the random choices the daemon makes, such as the URIs of new files
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    // No heartbeat thread is started, heartbeats are only sent by Daemon::heartbeat
+    pub fn manual_heartbeats(mut self) -> Self {
+        self.manual_heartbeats = true;
+        self
+    }
+
+    // Returns once the daemon is listening and, unless it is the root, has registered with the root
+    pub fn spawn(self) -> Result<Daemon, String> {
+        let mut opt = self.opt;
+        // Resolve relative paths, so they still point to the same files from a restart in another directory
+        opt.key_file = opt.key_file.map(|key_file| {
+            fs::canonicalize(&key_file).map(|key_file| key_file.to_string_lossy().into_owned()).map_err(|_| "Could not find cluster key file".to_string())
+        }).transpose()?;
+        opt.socket = opt.socket.map(|socket_path| {
+            std::env::current_dir().expect("Could not get working directory").join(socket_path).to_string_lossy().into_owned()
+        });
+        let storage: Box<dyn Storage> = match self.storage {
+            Some(storage) => storage,
+            None if opt.in_memory => Box::new(MemoryStorage::new()),
+            None => {
+                let data_dir = opt.data_dir.as_deref().unwrap_or(FILES_DIR);
+                Box::new(FsStorage::new(data_dir).map_err(|error| format!("Could not create directory for storing files: {error}"))?)
+            }
+        };
+        let saved_state = load_state(&*storage);
+
+        let name = match (opt.name, &saved_state) {
+            (Some(name), Some(saved_state)) if name != saved_state.local.name => {
+                return Err(format!("Files directory belongs to node {}, not {}", saved_state.local.name, name));
+            }
+            (Some(name), _) => name,
+            (None, Some(saved_state)) => saved_state.local.name.clone(),
+            (None, None) => {
+                return Err("Must specify a name for the local node".to_string());
+            }
+        };
+        let local = Node{name};
+
+        let saved_config = saved_state.as_ref().map(|saved_state| saved_state.config.clone()).unwrap_or_default();
+        let config = DaemonConfig {
+            port: opt.port.unwrap_or(saved_config.port),
+            cache_size: opt.cache_size.unwrap_or(saved_config.cache_size),
+            artificial_latency: opt.artificial_latency.unwrap_or(saved_config.artificial_latency),
+            heartbeat_interval: opt.heartbeat_interval.unwrap_or(saved_config.heartbeat_interval),
+            standby: switch(opt.standby, opt.no_standby).unwrap_or(saved_config.standby),
+            key_file: opt.key_file.or(saved_config.key_file),
+            encrypt: switch(opt.encrypt, opt.no_encrypt).unwrap_or(saved_config.encrypt),
+            socket: opt.socket.or(saved_config.socket),
+            tcp_clients: switch(opt.tcp_clients, opt.no_tcp_clients).unwrap_or(saved_config.tcp_clients),
+            quota: if opt.no_quota { None } else { opt.quota.or(saved_config.quota) },
+            chunked: switch(opt.chunked, opt.no_chunked).unwrap_or(saved_config.chunked),
+            connect_timeout: opt.connect_timeout.unwrap_or(saved_config.connect_timeout),
+            failover_after: opt.failover_after.unwrap_or(saved_config.failover_after),
+            max_connections: opt.max_connections.unwrap_or(saved_config.max_connections),
+            connection_queue: opt.connection_queue.unwrap_or(saved_config.connection_queue),
+        };
+        if !config.tcp_clients && config.socket.is_none() {
+            return Err("Must specify a Unix domain socket when user processes may not connect over TCP".to_string());
+        }
+        if config.encrypt && config.key_file.is_none() {
+            return Err("Must specify a cluster key file to encrypt links between daemons".to_string());
+        }
+        let cluster_key = config.key_file.as_ref().map(|key_file| {
+            fs::read(key_file).map_err(|_| "Could not read cluster key file".to_string())
+        }).transpose()?;
+
+        let saved_root = saved_state.as_ref().and_then(|saved_state| saved_state.root.clone());
+        let saved_root_epoch = saved_state.as_ref().map_or(0, |saved_state| saved_state.root_epoch);
+        let saved_known_hosts = saved_state.as_ref().map(|saved_state| saved_state.known_hosts.clone()).unwrap_or_default();
+        let listening_addr = opt.listening_addr.or_else(|| {
+            saved_state.as_ref().and_then(|saved_state| saved_state.local_entry.as_ref().map(|entry| entry.address.clone()))
+        });
+        // A node that was not the root last time reconnects to wherever the root was last seen
+        let root_addr = opt.root_addr.or_else(|| {
+            saved_root.as_ref().filter(|root| **root != local).and_then(|root| saved_known_hosts.get(root).map(|entry| entry.address.clone()))
+        });
+        if root_addr.is_none() && saved_root.as_ref().is_some_and(|root| *root != local) {
+            return Err("Must specify the address of the root node".to_string());
+        }
+        if root_addr.is_some() && listening_addr.is_none() {
+            return Err("Must specify address for other daemons to connect to".to_string());
+        }
+
+        let (listener, addr, listening_addr) = if self.network.is_some() {
+            (None, None, listening_addr)
+        }
+        else {
+            let listener = TcpListener::bind(("0.0.0.0", config.port)).map_err(|error| format!("Could not listen on port {}: {error}", config.port))?;
+            let addr = listener.local_addr().map_err(|error| error.to_string())?;
+            let listening_addr = listening_addr.map(|address| match address.strip_suffix(":0") {
+                Some(host) => format!("{host}:{}", addr.port()),
+                None => address,
+            });
+            (Some(listener), Some(addr), listening_addr)
+        };
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        let workers = WorkerPool::new(config.max_connections + RESERVED_DAEMON_WORKERS, config.connection_queue, MAX_CONNECTION_WAIT);
+        let turning_away = WorkerPool::new(TURNING_AWAY_THREADS, config.connection_queue, MAX_CONNECTION_WAIT);
+
+        let state = DaemonState {
+            root: RwLock::new(if root_addr.is_some() {saved_root} else {Some(local.clone())}),
+            local,
+            connections: Mutex::new(HashMap::new()),
+            known_hosts: Mutex::new(saved_known_hosts),
+            local_entry: listening_addr.map(|address| HostEntry { address, generation: new_generation(&*clock), removed: false }),
+            cache: Mutex::new(LruCache::unbounded()),
+            max_cache_size: config.cache_size,
+            used_cache_bytes: RwLock::new(0),
+            artificial_latency: Duration::from_millis(config.artificial_latency),
+            file_access_lock: RwLock::new(()),
+            node_status: Mutex::new(HashMap::new()),
+            heartbeat_interval: Duration::from_millis(config.heartbeat_interval),
+            connect_timeout: Duration::from_millis(config.connect_timeout),
+            standbys: Mutex::new(saved_state.map(|saved_state| saved_state.standbys).unwrap_or_default()),
+            root_missed_heartbeats: Mutex::new(0),
+            root_epoch: Mutex::new(saved_root_epoch),
+            config,
+            state_file_lock: Mutex::new(()),
+            cluster_key,
+            permissions: Mutex::new(load_metadata(PERMISSIONS_FILE, &*storage)),
+            placement_policies: Mutex::new(load_metadata(PLACEMENT_FILE, &*storage)),
+            next_round_robin: Mutex::new(0),
+            hashes: Mutex::new(load_metadata(HASHES_FILE, &*storage)),
+            chunks: Mutex::new(ChunkStore::load(&*storage)),
+            storage,
+            used_bytes: Mutex::new(0),
+            port: addr.map_or(0, |addr| addr.port()),
+            shutting_down: AtomicBool::new(false),
+            open_sockets: Mutex::new(OpenSockets::default()),
+            in_flight: Mutex::new(0),
+            requests_done: Condvar::new(),
+            workers,
+            turning_away,
+            clients: Mutex::new(0),
+            faults: Mutex::new(FaultPlan::default()),
+            network: self.network.unwrap_or_else(|| Arc::new(TcpNetwork)),
+            clock,
+            rng: Mutex::new(self.seed.map_or_else(StdRng::from_os_rng, StdRng::seed_from_u64)),
+        };
+
+        let state = if let Some(root_addr) = root_addr {
+            println!("running");
+            create(state, root_addr)?
         }
         else {
-            println!("Must specify address for other daemons to connect to");
+            println!("creating");
+            create_root(state)
+        };
+        let mut daemon = Daemon { state: state.clone(), addr, threads: vec![] };
+        if !self.manual_heartbeats {
+            daemon.threads.push(start_heartbeats(&state));
         }
-        
-    } else {
-        println!("creating");
-        create_root(opt.port, state);
-    };
-}
\ No newline at end of file
+        if let Some(socket_path) = &state.config.socket {
+            daemon.threads.push(start_local_server(socket_path, &state)?);
+        }
+        if let Some(listener) = listener {
+            daemon.threads.push(thread::spawn(move || serve(listener, state)));
+        }
+        Ok(daemon)
+    }
+}
@@ -0,0 +1,207 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::stream::Connection;
+
+// Every message sent after the hello, and every file's data, travels in its own frame:
+//   magic (2 bytes) | kind (1 byte) | length (u32) | payload crc32 (u32) | header crc32 (u32) | payload
+// The length is checked against MAX_FRAME_LEN before anything is allocated for the payload
+const MAGIC: [u8; 2] = *b"VF";
+const HEADER_LEN: usize = 15;
+// Largest payload a frame may carry, which also bounds the size of a single file
+pub const MAX_FRAME_LEN: usize = 64 << 20;
+// Smaller data is not worth compressing
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum FrameKind {
+    Message,
+    Data,
+    // Deflate compressed data, only sent to peers that negotiated compression
+    CompressedData,
+}
+
+impl FrameKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            FrameKind::Message => 0,
+            FrameKind::Data => 1,
+            FrameKind::CompressedData => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<FrameKind> {
+        match byte {
+            0 => Some(FrameKind::Message),
+            1 => Some(FrameKind::Data),
+            2 => Some(FrameKind::CompressedData),
+            _ => None,
+        }
+    }
+}
+
+// Only Io leaves the stream unusable. After any other error the reader is at the start of the next
+// frame, or skips ahead to it on the next read, so the connection can answer with an error and carry on
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    // The header or the payload did not match its checksum
+    Corrupt,
+    TooLarge(usize),
+    // The frame was intact but did not hold what was expected
+    Malformed(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Io(error) => write!(f, "{error}"),
+            FrameError::Corrupt => write!(f, "Frame failed its checksum"),
+            FrameError::TooLarge(len) => write!(f, "Frame of {len} bytes is larger than the limit of {MAX_FRAME_LEN} bytes"),
+            FrameError::Malformed(reason) => write!(f, "Malformed frame: {reason}"),
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(error: io::Error) -> Self {
+        FrameError::Io(error)
+    }
+}
+
+pub fn write_frame<W: Write + ?Sized>(stream: &mut W, kind: FrameKind, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, FrameError::TooLarge(payload.len()).to_string()));
+    }
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.push(kind.to_byte());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+    let header_crc = crc32fast::hash(&frame);
+    frame.extend_from_slice(&header_crc.to_be_bytes());
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)
+}
+
+// The kind, payload length and payload crc of an intact header
+fn parse_header(header: &[u8]) -> Option<(FrameKind, usize, u32)> {
+    let header_crc = u32::from_be_bytes(header[11..15].try_into().unwrap());
+    if header[..2] != MAGIC || crc32fast::hash(&header[..11]) != header_crc {
+        return None;
+    }
+    let kind = FrameKind::from_byte(header[2])?;
+    let len = u32::from_be_bytes(header[3..7].try_into().unwrap()) as usize;
+    let payload_crc = u32::from_be_bytes(header[7..11].try_into().unwrap());
+    Some((kind, len, payload_crc))
+}
+
+// A broken header is reported straight away, so the peer gets its error even if nothing follows
+// the broken frame. The rest of it is only skipped when the next frame is read
+pub fn read_frame(stream: &mut Connection) -> Result<(FrameKind, Vec<u8>), FrameError> {
+    if stream.resync_pending {
+        resync(stream)?;
+        stream.resync_pending = false;
+    }
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header)?;
+    let Some((kind, len, payload_crc)) = parse_header(&header) else {
+        stream.unread(&header[1..]);
+        stream.resync_pending = true;
+        return Err(FrameError::Corrupt);
+    };
+    if len > MAX_FRAME_LEN {
+        // The header is intact, so its length can be trusted to skip the payload
+        io::copy(&mut (&mut *stream).take(len as u64), &mut io::sink())?;
+        return Err(FrameError::TooLarge(len));
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    if crc32fast::hash(&payload) != payload_crc {
+        return Err(FrameError::Corrupt);
+    }
+    Ok((kind, payload))
+}
+
+// Used after a header was broken, so the length of its frame can not be trusted. Skips ahead to the
+// next intact header, which is left to be read as the next frame. Frames sent after the broken
+// one, for example by clients pipelining requests, are not lost
+fn resync(stream: &mut Connection) -> io::Result<()> {
+    let mut pending = vec![];
+    let mut buf = [0u8; 4096];
+    loop {
+        let mut start = 0;
+        while start + HEADER_LEN <= pending.len() {
+            if parse_header(&pending[start..start + HEADER_LEN]).is_some() {
+                stream.unread(&pending[start..]);
+                return Ok(());
+            }
+            start += 1;
+        }
+        pending.drain(..start);
+        match stream.read(&mut buf) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(read) => pending.extend_from_slice(&buf[..read]),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+pub fn send_framed<W: Write + ?Sized, T: Serialize>(stream: &mut W, message: &T) -> io::Result<()> {
+    let payload = serde_bare::to_vec(message).map_err(io::Error::other)?;
+    write_frame(stream, FrameKind::Message, &payload)
+}
+
+// Data frames found in place of a message are left over from a request that was already answered
+// with an error, and are skipped
+pub fn receive_framed<T: DeserializeOwned>(stream: &mut Connection) -> Result<T, FrameError> {
+    loop {
+        let (kind, payload) = read_frame(stream)?;
+        if kind == FrameKind::Message {
+            return serde_bare::from_slice(&payload).map_err(|error| FrameError::Malformed(error.to_string()));
+        }
+    }
+}
+
+// Data is only sent compressed when the peer accepts it and compressing actually makes it smaller
+pub fn send_data<W: Write + ?Sized>(stream: &mut W, data: &[u8], compress: bool) -> io::Result<()> {
+    if compress && data.len() >= COMPRESSION_THRESHOLD {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() < data.len() {
+            return write_frame(stream, FrameKind::CompressedData, &compressed);
+        }
+    }
+    write_frame(stream, FrameKind::Data, data)
+}
+
+// Reads the data announced by the message just received, which must be exactly len bytes long.
+// Compressed data is never inflated past len, so it can not be used to exhaust memory either
+pub fn receive_data(stream: &mut Connection, len: usize) -> Result<Vec<u8>, FrameError> {
+    if len > MAX_FRAME_LEN {
+        return Err(FrameError::TooLarge(len));
+    }
+    let data = match read_frame(stream)? {
+        (FrameKind::Data, data) => data,
+        (FrameKind::CompressedData, compressed) => {
+            let mut data = Vec::with_capacity(len);
+            DeflateDecoder::new(compressed.as_slice()).take(len as u64 + 1).read_to_end(&mut data)
+                .map_err(|error| FrameError::Malformed(format!("Could not decompress data: {error}")))?;
+            data
+        }
+        (FrameKind::Message, _) => return Err(FrameError::Malformed("Expected data but got a message".to_string())),
+    };
+    if data.len() != len {
+        return Err(FrameError::Malformed(format!("Expected {len} bytes of data but got {}", data.len())));
+    }
+    Ok(data)
+}
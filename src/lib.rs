@@ -1,118 +1,315 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io::{Read, Write};
+use hmac::Mac;
+use std::ffi::CStr;
+use std::io;
 use std::net::{TcpStream};
-use std::sync::{Arc, Mutex};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Mutex;
 
 pub mod messages;
+pub mod stream;
+pub mod frame;
+pub mod daemon;
+pub mod storage;
+pub mod clock;
+pub mod network;
+pub mod sim;
+pub mod r#async;
+mod chunks;
+mod pool;
+use frame::*;
 use messages::*;
+use stream::*;
+use daemon::{accepting_role, auth_mac, new_nonce};
 
 pub struct VPFS {
     pub local: Node,
-    connection: Mutex<TcpStream>
+    pub user: String,
+    pub protocol: NegotiatedProtocol,
+    connection: Mutex<Connection>
+}
+
+// Modes of files and directories created without an explicit mode
+pub const DEFAULT_FILE_MODE: u16 = 0o644;
+pub const DEFAULT_DIRECTORY_MODE: u16 = 0o755;
+
+// The user running this process, which is who it acts as when connecting through the daemon's
+// Unix domain socket
+pub fn current_user() -> String {
+    user_name(unsafe { libc::geteuid() })
+}
+
+// Name of the user with the given uid, or the uid itself if the user database has no entry for it
+pub(crate) fn user_name(uid: u32) -> String {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let error = unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if error != 0 || result.is_null() {
+        return uid.to_string();
+    }
+    unsafe { CStr::from_ptr(passwd.pw_name) }.to_string_lossy().into_owned()
+}
+
+// Introduces a user process to its local daemon, returning the protocol negotiated and the local
+// node. A process given the cluster key proves it holds the key, and may then act as any user
+fn client_hello(stream: &mut Connection, user: &str, cluster_key: Option<&[u8]>) -> Result<(NegotiatedProtocol, Node), std::io::Error> {
+    let protocol = Protocol::current();
+    let hello = match cluster_key {
+        Some(_) => Hello::AuthenticatedClientHello(protocol.clone(), user.to_string()),
+        None => Hello::ClientHello(protocol.clone(), user.to_string()),
+    };
+    serde_bare::to_writer(&mut *stream, &hello)?;
+    let mut hello_response = serde_bare::from_reader::<_, HelloResponse>(&mut *stream);
+    if let (Ok(HelloResponse::Challenge(challenge)), Some(cluster_key)) = (&hello_response, cluster_key) {
+        answer_challenge(stream, cluster_key, challenge)?;
+        hello_response = serde_bare::from_reader(&mut *stream);
+    }
+    match hello_response {
+        Ok(HelloResponse::ClientHello(negotiated, local_node)) => {
+            if let Err(reason) = protocol.accepts(&negotiated) {
+                return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, reason));
+            }
+            stream.protocol = Some(negotiated.clone());
+            Ok((negotiated, local_node))
+        }
+        Ok(HelloResponse::Rejected(reason)) => {
+            Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, reason))
+        }
+        Ok(HelloResponse::Busy) => {
+            Err(std::io::Error::new(std::io::ErrorKind::ResourceBusy, "The daemon is serving too many connections, try again later"))
+        }
+        Ok(_) => panic!("Got wrong hello response"),
+        Err(_) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Could not understand the daemon's hello response, it may speak an incompatible protocol version")),
+    }
+}
+
+// Proves to the daemon that this process holds the cluster key, and checks that the daemon does too.
+// Connections of user processes are not encrypted, whether or not the links between daemons are
+fn answer_challenge(stream: &mut Connection, cluster_key: &[u8], challenge: &[u8]) -> io::Result<()> {
+    let nonce = new_nonce();
+    let proof = auth_mac(cluster_key, b"connecting", challenge, &nonce).finalize().into_bytes().to_vec();
+    serde_bare::to_writer(&mut *stream, &ChallengeResponse { nonce: nonce.clone(), proof })?;
+    match serde_bare::from_reader(&mut *stream) {
+        Ok(HelloResponse::Authenticated(peer_proof, _)) => {
+            auth_mac(cluster_key, accepting_role(false), &nonce, challenge).verify_slice(&peer_proof)
+                .map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "The daemon does not hold the cluster key"))
+        }
+        Ok(HelloResponse::Rejected(reason)) => Err(io::Error::new(io::ErrorKind::PermissionDenied, reason)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Got bad authentication response")),
+    }
 }
 
 impl VPFS {
+    // Connect over TCP as the user nobody
     pub fn connect(listen_port: u16) -> Result<VPFS, std::io::Error> {
         let stream = TcpStream::connect(format!("localhost:{}", listen_port))?;
+        VPFS::hello(Connection::new(stream), NOBODY, None)
+    }
 
-        serde_bare::to_writer(&stream, &Hello::ClientHello)?;
-        let hello_response = serde_bare::from_reader::<_, HelloResponse>(&stream);
-        if let Ok(HelloResponse::ClientHello(local_node)) = hello_response{
-            let vpfs = VPFS { 
+    // Connect over TCP as any user, which only processes holding the cluster key may do
+    pub fn connect_as(listen_port: u16, user: &str, cluster_key: &[u8]) -> Result<VPFS, std::io::Error> {
+        let stream = TcpStream::connect(format!("localhost:{}", listen_port))?;
+        VPFS::hello(Connection::new(stream), user, Some(cluster_key))
+    }
+
+    // Connect over the daemon's Unix domain socket, as the user running this process
+    pub fn connect_unix<P: AsRef<Path>>(socket_path: P) -> Result<VPFS, std::io::Error> {
+        let stream = UnixStream::connect(socket_path)?;
+        VPFS::hello(Connection::new(stream), &current_user(), None)
+    }
+
+    // Connect as the user nobody over any stream that reaches a daemon, such as one from a MemoryNetwork
+    pub fn connect_stream<S: Stream + 'static>(stream: S) -> Result<VPFS, std::io::Error> {
+        VPFS::hello(Connection::new(stream), NOBODY, None)
+    }
+
+    fn hello(mut stream: Connection, user: &str, cluster_key: Option<&[u8]>) -> Result<VPFS, std::io::Error> {
+        let (negotiated, local_node) = client_hello(&mut stream, user, cluster_key)?;
+        Ok(VPFS {
             local: local_node,
+            user: user.to_string(),
+            protocol: negotiated,
             connection: Mutex::new(stream),
-            };
-            Ok(vpfs)
-        }
-        else {
-            panic!("Got wrong hello response");
-        }
-        
+        })
     }
 
-    fn send_request_async(&self, stream: &TcpStream, req: ClientRequest) {
-        serde_bare::to_writer(stream, &req).unwrap();
+    fn send_request_async(&self, stream: &mut Connection, req: ClientRequest) {
+        send_framed(stream, &req).unwrap();
     }
 
-    fn receive_response_async(&self, stream: &TcpStream) -> ClientResponse {
-        let resp = serde_bare::from_reader(stream).unwrap();
-        resp
+    // A response that arrived damaged is reported as an error, the connection itself is still in sync
+    fn receive_response_async(&self, stream: &mut Connection) -> ClientResponse {
+        match receive_framed(stream) {
+            Ok(resp) => resp,
+            Err(FrameError::Io(error)) => panic!("Lost connection to the daemon: {error}"),
+            Err(error) => ClientResponse::Error(VPFSError::BadFrame(error.to_string())),
+        }
     }
 
     fn send_request(&self, req: ClientRequest) -> ClientResponse {
-        let stream = self.connection.lock().unwrap();
-        serde_bare::to_writer(&mut &*stream, &req).unwrap();
-        let resp = serde_bare::from_reader(&*stream).unwrap();
-        resp
+        let mut stream = self.connection.lock().unwrap();
+        self.send_request_async(&mut stream, req);
+        self.receive_response_async(&mut stream)
     }
 
     pub fn find(&self, path: &str) -> Result<DirectoryEntry, VPFSError> {
-        if let ClientResponse::Find(find_result) = self.send_request(ClientRequest::Find(path.to_string())) {
-            find_result
-        }
-        else {
-            panic!("Bad responce to find")
+        match self.send_request(ClientRequest::Find(path.to_string())) {
+            ClientResponse::Find(find_result) => find_result,
+            ClientResponse::Error(error) => Err(error),
+            _ => panic!("Bad responce to find"),
         }
     }
 
-    pub fn place(&self, path: &str, at: Node) -> Result<Location, VPFSError>{
-        if let ClientResponse::Place(place_result) = self.send_request(ClientRequest::Place(path.to_string(), at)) {
-            place_result
-        }
-        else {
-            panic!("Bad responce to place")
-        }
+    pub fn place(&self, path: &str, at: impl Into<Placement>) -> Result<Location, VPFSError>{
+        self.place_with_mode(path, at, DEFAULT_FILE_MODE)
     }
 
-    pub fn mkdir(&self, path: &str, at: Node) -> Result<Location, VPFSError>{
-        if let ClientResponse::Mkdir(mkdir_result) = self.send_request(ClientRequest::Mkdir(path.to_string(), at)) {
-            mkdir_result
+    pub fn place_with_mode(&self, path: &str, at: impl Into<Placement>, mode: u16) -> Result<Location, VPFSError>{
+        match self.send_request(ClientRequest::Place(path.to_string(), at.into(), mode)) {
+            ClientResponse::Place(place_result) => place_result,
+            ClientResponse::Error(error) => Err(error),
+            _ => panic!("Bad responce to place"),
         }
-        else {
-            panic!("Bad responce to mkdir")
+    }
+
+    pub fn mkdir(&self, path: &str, at: impl Into<Placement>) -> Result<Location, VPFSError>{
+        self.mkdir_with_mode(path, at, DEFAULT_DIRECTORY_MODE)
+    }
+
+    pub fn mkdir_with_mode(&self, path: &str, at: impl Into<Placement>, mode: u16) -> Result<Location, VPFSError>{
+        match self.send_request(ClientRequest::Mkdir(path.to_string(), at.into(), mode)) {
+            ClientResponse::Mkdir(mkdir_result) => mkdir_result,
+            ClientResponse::Error(error) => Err(error),
+            _ => panic!("Bad responce to mkdir"),
         }
     }
 
     pub fn read(&self, what: Location) -> Result<Vec<u8>, VPFSError> {
         let mut stream = self.connection.lock().unwrap();
-        self.send_request_async(&stream, ClientRequest::Read(what));
-        match self.receive_response_async(&stream) {
-            ClientResponse::Read(Ok(len)) => {
-                let mut buf=vec![0u8;len];
-                stream.read_exact(&mut buf);
+        self.send_request_async(&mut stream, ClientRequest::Read(what));
+        match self.receive_response_async(&mut stream) {
+            ClientResponse::Read(Ok((len, hash))) => {
+                let buf = receive_data(&mut stream, len).map_err(|error| VPFSError::BadFrame(error.to_string()))?;
+                if content_hash(&buf) != hash {
+                    return Err(VPFSError::Corrupted);
+                }
                 Ok(buf)
             },
-            ClientResponse::Read(Err(error)) => {
+            ClientResponse::Read(Err(error)) | ClientResponse::Error(error) => {
                 Err(error)
             },
             _ => panic!("Bad response to read!"),
         }
     } 
     pub fn write(&self, what: Location, buf: &[u8]) -> Result<(), VPFSError> {
+        if buf.len() > MAX_FRAME_LEN {
+            return Err(VPFSError::TooLarge);
+        }
         let mut stream = self.connection.lock().unwrap();
-        self.send_request_async(&stream, ClientRequest::Write(what, buf.len()));
-        stream.write_all(buf);
+        self.send_request_async(&mut stream, ClientRequest::Write(what, buf.len()));
+        let compress = stream.supports(COMPRESSION);
+        send_data(&mut *stream, buf, compress).unwrap();
 
-        match self.receive_response_async(&stream) {
+        match self.receive_response_async(&mut stream) {
             ClientResponse::Write(Ok(len)) => {
                 assert!(len == buf.len());
                 Ok(())
             },
-            ClientResponse::Write(Err(error)) => {
+            ClientResponse::Write(Err(error)) | ClientResponse::Error(error) => {
                 Err(error)
             },
             _ => panic!("Bad response to write!"),
         }
     }
 
+    pub fn nodes(&self) -> Vec<NodeStatus> {
+        if let ClientResponse::Nodes(nodes) = self.send_request(ClientRequest::Nodes) {
+            nodes
+        }
+        else {
+            panic!("Bad responce to nodes")
+        }
+    }
+
+    // Needs write permission on the directory, None removes the directory's policy
+    pub fn set_placement_policy(&self, directory: &str, policy: Option<PlacementPolicy>) -> Result<(), VPFSError> {
+        match self.send_request(ClientRequest::SetPlacementPolicy(directory.to_string(), policy)) {
+            ClientResponse::SetPlacementPolicy(result) => result,
+            ClientResponse::Error(error) => Err(error),
+            _ => panic!("Bad responce to set placement policy"),
+        }
+    }
+
+    pub fn df(&self) -> Vec<NodeCapacity> {
+        if let ClientResponse::Df(capacities) = self.send_request(ClientRequest::Df) {
+            capacities
+        }
+        else {
+            panic!("Bad responce to df")
+        }
+    }
+
+    // Admin requests are refused with PermissionDenied, unless the process connected through the
+    // Unix domain socket as root or as the daemon's user, or holds the cluster key
+    pub fn admin(&self, req: AdminRequest) -> Result<AdminResponse, VPFSError> {
+        if !self.protocol.speaks(req.version()) {
+            return Err(VPFSError::Other(format!("The local daemon speaks protocol version {}, which does not support {req:?}", self.protocol.version)));
+        }
+        match self.send_request(ClientRequest::Admin(req)) {
+            ClientResponse::Admin(admin_response) => Ok(admin_response),
+            ClientResponse::Error(error) => Err(error),
+            _ => panic!("Bad responce to admin request"),
+        }
+    }
+
+    pub fn promote_to_root(&self) -> Result<(), VPFSError> {
+        match self.admin(AdminRequest::PromoteToRoot)? {
+            AdminResponse::PromoteToRoot(result) => result,
+            _ => panic!("Bad responce to promote"),
+        }
+    }
+
+    // Moves every file and directory off the node and removes it from the cluster. Returns the
+    // number of files and directories moved
+    pub fn drain(&self, node: Node) -> Result<usize, VPFSError> {
+        match self.admin(AdminRequest::Drain(node))? {
+            AdminResponse::Drain(result) => result,
+            _ => panic!("Bad responce to drain"),
+        }
+    }
+
+    pub fn rebalance(&self) -> Result<usize, VPFSError> {
+        match self.admin(AdminRequest::Rebalance)? {
+            AdminResponse::Rebalance(result) => result,
+            _ => panic!("Bad responce to rebalance"),
+        }
+    }
+
+    // Replaces the faults the local daemon injects, for testing. An empty plan stops injecting faults
+    pub fn inject_faults(&self, plan: FaultPlan) -> Result<(), VPFSError> {
+        match self.admin(AdminRequest::InjectFaults(plan))? {
+            AdminResponse::InjectFaults(result) => result,
+            _ => panic!("Bad responce to inject faults"),
+        }
+    }
+
+    // Asks the local daemon to shut down gracefully. It finishes the requests it is serving, so
+    // this connection is closed soon after
+    pub fn shutdown_daemon(&self) -> Result<(), VPFSError> {
+        match self.admin(AdminRequest::Shutdown)? {
+            AdminResponse::Shutdown => Ok(()),
+            _ => panic!("Bad responce to shutdown"),
+        }
+    }
+
     pub fn fetch(&self, name: &str) -> Result<Vec<u8>, VPFSError> {
         let dir_entry = self.find(name)?;
         self.read(dir_entry.location)
     }
 
     pub fn store(&self, name: &str, buf: &[u8]) -> Result<(), VPFSError> {
-        let location = match self.place(name, self.local.clone()) {
+        let location = match self.place(name, Placement::Auto) {
             Ok(location) => location,
             Err(VPFSError::AlreadyExists(dir_entry)) => dir_entry.location,
             Err(error) => return Err(error),
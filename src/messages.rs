@@ -1,49 +1,267 @@
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
+
+// Newest version of the protocol spoken by this build. Bump it whenever a message changes
+pub const PROTOCOL_VERSION: u32 = 9;
+// Oldest version this build can still speak
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
+// Versions that added messages, which are only sent over connections that negotiated at least that
+// version. Messages added by MIN_PROTOCOL_VERSION or earlier are always understood
+pub const LEAVING_VERSION: u32 = 5;
+pub const BUSY_VERSION: u32 = 6;
+pub const FAULTS_VERSION: u32 = 8;
+pub const SHUTDOWN_VERSION: u32 = 8;
+pub const ROOT_EPOCH_VERSION: u32 = 9;
+// Optional features this build supports, only used when both sides support them
+pub const CAPABILITIES: &[&str] = &[COMPRESSION];
+// File data may be sent deflate compressed
+pub const COMPRESSION: &str = "compression";
+// Files may be read and written as content defined chunks. Only offered by daemons that store
+// files in chunks, so it is not part of CAPABILITIES
+pub const CHUNKS: &str = "chunks";
+
+// Versions and capabilities offered by the side opening a connection. It comes first in every
+// hello, and hello variants are only ever appended, so peers of any version can read it
+#[derive(Serialize,Deserialize,Clone,Debug,Eq,PartialEq)]
+pub struct Protocol {
+    pub min_version: u32,
+    pub max_version: u32,
+    pub capabilities: Vec<String>,
+}
+
+// Highest version and the capabilities both sides support, chosen by the accepting side
+#[derive(Serialize,Deserialize,Clone,Debug,Eq,PartialEq)]
+pub struct NegotiatedProtocol {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Protocol {
+    pub fn current() -> Protocol {
+        Protocol {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|capability| capability.to_string()).collect(),
+        }
+    }
+
+    pub fn negotiate(&self, peer: &Protocol) -> Result<NegotiatedProtocol, String> {
+        let version = self.max_version.min(peer.max_version);
+        if version < self.min_version.max(peer.min_version) {
+            return Err(format!("Incompatible protocol versions, peer speaks {}-{} but this node speaks {}-{}",
+                peer.min_version, peer.max_version, self.min_version, self.max_version));
+        }
+        Ok(NegotiatedProtocol {
+            version,
+            capabilities: self.capabilities.iter().filter(|capability| peer.capabilities.contains(capability)).cloned().collect(),
+        })
+    }
+
+    // Checks the accepting side's choice, in case it picked a version this side does not speak
+    pub fn accepts(&self, negotiated: &NegotiatedProtocol) -> Result<(), String> {
+        if negotiated.version < self.min_version || negotiated.version > self.max_version {
+            return Err(format!("Peer chose protocol version {} but this node speaks {}-{}", negotiated.version, self.min_version, self.max_version));
+        }
+        if let Some(capability) = negotiated.capabilities.iter().find(|capability| !self.capabilities.contains(capability)) {
+            return Err(format!("Peer chose capability {capability} which this node does not support"));
+        }
+        Ok(())
+    }
+}
+
+impl NegotiatedProtocol {
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|negotiated| negotiated == capability)
+    }
+
+    pub fn speaks(&self, version: u32) -> bool {
+        self.version >= version
+    }
+}
+
+// User processes connecting over TCP without the cluster key act as this user
+pub const NOBODY: &str = "nobody";
 
 #[derive(Serialize,Deserialize)]
 pub enum Hello {
-    ClientHello,
-    DaemonHello,
-    RootHello(Node, String),
+    // Carries the user the process acts on behalf of, which the daemon checks against who is
+    // connecting. See NOBODY
+    ClientHello(Protocol, String),
+    DaemonHello(Protocol),
+    RootHello(Protocol, Node, HostEntry),
+    StandbyHello(Protocol, Node, HostEntry),
+    // Answered with a challenge, like the hellos of daemons. A process that proves it holds the
+    // cluster key may act as the user it names
+    AuthenticatedClientHello(Protocol, String),
 }
 
+impl Hello {
+    pub fn protocol(&self) -> &Protocol {
+        match self {
+            Hello::ClientHello(protocol, _) => protocol,
+            Hello::DaemonHello(protocol) => protocol,
+            Hello::RootHello(protocol, _, _) => protocol,
+            Hello::StandbyHello(protocol, _, _) => protocol,
+            Hello::AuthenticatedClientHello(protocol, _) => protocol,
+        }
+    }
+}
+
+// Variants are only ever appended, so that Rejected can be read by peers of any version
 #[derive(Serialize,Deserialize)]
 pub enum HelloResponse {
-    ClientHello(Node),
-    DaemonHello,
-    RootHello(Node, HashMap<Node, String>),
+    ClientHello(NegotiatedProtocol, Node),
+    DaemonHello(NegotiatedProtocol),
+    RootHello(NegotiatedProtocol, Node, HashMap<Node, HostEntry>, Vec<Node>),
+    // Sent instead of the response to a daemon hello when the cluster uses a pre-shared key
+    Challenge(Vec<u8>),
+    Authenticated(Vec<u8>, bool),
+    Rejected(String),
+    // The daemon is serving as many connections as it can, the connection may be retried later.
+    // Peers older than BUSY_VERSION are sent Rejected instead
+    Busy,
+}
+
+impl HelloResponse {
+    // The protocol chosen by the accepting side, only carried by the final response to a hello
+    pub fn negotiated(&self) -> Option<&NegotiatedProtocol> {
+        match self {
+            HelloResponse::ClientHello(negotiated, _) => Some(negotiated),
+            HelloResponse::DaemonHello(negotiated) => Some(negotiated),
+            HelloResponse::RootHello(negotiated, _, _, _) => Some(negotiated),
+            _ => None,
+        }
+    }
+}
+
+// Answer to HelloResponse::Challenge, proves the connecting daemon holds the cluster key
+#[derive(Serialize,Deserialize)]
+pub struct ChallengeResponse {
+    pub nonce: Vec<u8>,
+    pub proof: Vec<u8>,
 }
 
 #[derive(Serialize,Deserialize)]
 pub enum DaemonRequest {
-    Place,
-    Read(String, Option<SystemTime>),
-    Write(String, usize),
-    Remove(String),
-    AppendDirectoryEntry(String, DirectoryEntry),
-    AddressFor(Node)
+    // Requests on behalf of a user name the user, so the daemon that owns the file can enforce
+    // its permissions. Reads without a user are made by the daemon itself, e.g. to traverse directories
+    Place(Permissions),
+    Read(String, Option<SystemTime>, Option<String>),
+    Write(String, usize, String),
+    Remove(String, String),
+    AppendDirectoryEntry(String, DirectoryEntry, String),
+    AddressFor(Node),
+    Heartbeat,
+    ClusterInfo,
+    PromoteToRoot,
+    NewRoot(Node),
+    Gossip(HashMap<Node, HostEntry>),
+    Capacity,
+    PlacementPolicy(String),
+    SetPlacementPolicy(String, Option<PlacementPolicy>, String),
+    // Followed by the file's data, stores a file that is moving off another node
+    Migrate(Permissions, Option<PlacementPolicy>, usize),
+    ReplaceDirectoryEntry(String, DirectoryEntry),
+    RemoveNode(Node),
+    // Only sent to daemons that negotiated chunks. Like Read, but answered with the chunks the file
+    // is made of instead of its data
+    ReadChunks(String, Option<SystemTime>, Option<String>),
+    // Answered with a data frame for each of the file's chunks asked for, in the order asked
+    FetchChunks(String, Vec<ContentHash>, Option<String>),
+    // Replaces a file with the chunks listed. Followed by a data frame for each of the included
+    // chunks, the owner must already have the others or it answers with the ones it is missing
+    WriteChunks(String, Vec<ChunkRef>, Vec<ContentHash>, String),
+    // Sent by a daemon that is shutting down, so its peers treat it as down until it answers a
+    // heartbeat again
+    Leaving(Node),
+    // The root and its epoch as known by the sender. Answered with the receiver's, and each side
+    // takes whichever has the higher epoch
+    RootEpoch(Node, u64),
+    // Asks whether the receiver also sees the root as down, before failing over
+    RootDown(Node),
+}
+
+// Names of the requests, as used to pick the requests a fault is injected into
+pub const DAEMON_REQUEST_NAMES: &[&str] = &[
+    "Place", "Read", "Write", "Remove", "AppendDirectoryEntry", "AddressFor", "Heartbeat", "ClusterInfo",
+    "PromoteToRoot", "NewRoot", "Gossip", "Capacity", "PlacementPolicy", "SetPlacementPolicy", "Migrate",
+    "ReplaceDirectoryEntry", "RemoveNode", "ReadChunks", "FetchChunks", "WriteChunks", "Leaving",
+    "RootEpoch", "RootDown",
+];
+
+impl DaemonRequest {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DaemonRequest::Place(..) => "Place",
+            DaemonRequest::Read(..) => "Read",
+            DaemonRequest::Write(..) => "Write",
+            DaemonRequest::Remove(..) => "Remove",
+            DaemonRequest::AppendDirectoryEntry(..) => "AppendDirectoryEntry",
+            DaemonRequest::AddressFor(..) => "AddressFor",
+            DaemonRequest::Heartbeat => "Heartbeat",
+            DaemonRequest::ClusterInfo => "ClusterInfo",
+            DaemonRequest::PromoteToRoot => "PromoteToRoot",
+            DaemonRequest::NewRoot(..) => "NewRoot",
+            DaemonRequest::Gossip(..) => "Gossip",
+            DaemonRequest::Capacity => "Capacity",
+            DaemonRequest::PlacementPolicy(..) => "PlacementPolicy",
+            DaemonRequest::SetPlacementPolicy(..) => "SetPlacementPolicy",
+            DaemonRequest::Migrate(..) => "Migrate",
+            DaemonRequest::ReplaceDirectoryEntry(..) => "ReplaceDirectoryEntry",
+            DaemonRequest::RemoveNode(..) => "RemoveNode",
+            DaemonRequest::ReadChunks(..) => "ReadChunks",
+            DaemonRequest::FetchChunks(..) => "FetchChunks",
+            DaemonRequest::WriteChunks(..) => "WriteChunks",
+            DaemonRequest::Leaving(..) => "Leaving",
+            DaemonRequest::RootEpoch(..) => "RootEpoch",
+            DaemonRequest::RootDown(..) => "RootDown",
+        }
+    }
 }
 
 #[derive(Serialize,Deserialize)]
 pub enum DaemonResponse {
-    Place(String),
-    Read(Result<usize, VPFSError>),
+    Place(Result<String, VPFSError>),
+    Read(Result<(usize, Option<Permissions>, ContentHash), VPFSError>),
     Write(Result<usize, VPFSError>),
     Remove(Result<(), VPFSError>),
     AppendDirectoryEntry(Result<(), VPFSError>),
-    AddressFor(Option<String>)
+    AddressFor(Option<String>),
+    Heartbeat,
+    ClusterInfo(HashMap<Node, HostEntry>, Vec<Node>),
+    PromoteToRoot(Result<(), VPFSError>),
+    NewRoot,
+    Gossip(HashMap<Node, HostEntry>),
+    Capacity(Capacity),
+    PlacementPolicy(Option<PlacementPolicy>),
+    SetPlacementPolicy(Result<(), VPFSError>),
+    Migrate(Result<String, VPFSError>),
+    ReplaceDirectoryEntry(Result<(), VPFSError>),
+    RemoveNode,
+    // The request could not be read, the connection is still usable
+    Error(VPFSError),
+    ReadChunks(Result<(Vec<ChunkRef>, Option<Permissions>, ContentHash), VPFSError>),
+    FetchChunks(Result<(), VPFSError>),
+    WriteChunks(Result<usize, VPFSError>),
+    Leaving,
+    RootEpoch(Option<Node>, u64),
+    RootDown(bool),
 }
 
 #[derive(Serialize,Deserialize)]
 pub enum ClientRequest {
     Find(String),
-    Place(String, Node),
-    Mkdir(String, Node),
+    Place(String, Placement, u16),
+    Mkdir(String, Placement, u16),
     Read(Location),
     Write(Location, usize),
+    Nodes,
+    Df,
+    SetPlacementPolicy(String, Option<PlacementPolicy>),
+    Admin(AdminRequest),
 }
 
 #[derive(Serialize,Deserialize)]
@@ -51,8 +269,77 @@ pub enum ClientResponse {
     Find(Result<DirectoryEntry, VPFSError>),
     Place(Result<Location, VPFSError>),
     Mkdir(Result<Location, VPFSError>),
-    Read(Result<usize, VPFSError>),
+    Read(Result<(usize, ContentHash), VPFSError>),
     Write(Result<usize, VPFSError>),
+    Nodes(Vec<NodeStatus>),
+    Df(Vec<NodeCapacity>),
+    SetPlacementPolicy(Result<(), VPFSError>),
+    Admin(AdminResponse),
+    // The request could not be read, the connection is still usable
+    Error(VPFSError),
+}
+
+// Operations for managing the cluster, rather than the files stored in it
+#[derive(Serialize,Deserialize,Debug)]
+pub enum AdminRequest {
+    PromoteToRoot,
+    Drain(Node),
+    Rebalance,
+    // Replaces the faults the daemon injects, an empty plan stops injecting faults
+    InjectFaults(FaultPlan),
+    // Answered before the daemon starts shutting down, so the response does not wait for the shutdown
+    Shutdown,
+}
+
+impl AdminRequest {
+    // Version that added the request, clients only send it to daemons that negotiated at least that
+    pub fn version(&self) -> u32 {
+        match self {
+            AdminRequest::InjectFaults(_) => FAULTS_VERSION,
+            AdminRequest::Shutdown => SHUTDOWN_VERSION,
+            _ => MIN_PROTOCOL_VERSION,
+        }
+    }
+}
+
+#[derive(Serialize,Deserialize,Debug)]
+pub enum AdminResponse {
+    PromoteToRoot(Result<(), VPFSError>),
+    // Number of files and directories moved
+    Drain(Result<usize, VPFSError>),
+    Rebalance(Result<usize, VPFSError>),
+    InjectFaults(Result<(), VPFSError>),
+    Shutdown,
+}
+
+// Faults a daemon injects into the requests it sends to other daemons and into its own disk
+// writes, for testing how the cluster copes with failures
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug,Default)]
+pub struct FaultPlan {
+    pub rules: Vec<FaultRule>,
+    // Writing files and directory entries stored on the daemon fails, as if its disk was full
+    pub fail_writes: bool,
+}
+
+// A fault injected into requests to the peer, or to every peer if there is none, whose name is
+// the request's name, or into every request if there is none. The first matching rule applies
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub struct FaultRule {
+    pub peer: Option<Node>,
+    pub request: Option<String>,
+    pub fault: Fault,
+}
+
+#[derive(Serialize,Deserialize,Clone,Copy,Eq,PartialEq,Debug)]
+pub enum Fault {
+    // The daemon does not connect to the peer at all, and drops any connection it has to it.
+    // Only applies to rules covering every request
+    Partition,
+    // The connection is closed instead of sending the request
+    Disconnect,
+    // The response is held back for a random time between the two lengths in milliseconds, so
+    // responses to concurrent requests can arrive in a different order than they were sent
+    Delay(u64, u64),
 }
 
 #[derive(Debug,Clone,Eq,Hash,PartialEq,Serialize,Deserialize)]
@@ -70,7 +357,117 @@ pub struct Node {
 pub struct DirectoryEntry {
     pub location: Location,
     pub name: String,
-    pub is_dir: bool
+    pub is_dir: bool,
+    pub permissions: Permissions,
+}
+
+// SHA-256 of a file's contents, sent along with the contents so the receiver can verify them
+pub type ContentHash = [u8; 32];
+
+pub fn content_hash(data: &[u8]) -> ContentHash {
+    Sha256::digest(data).into()
+}
+
+// One piece of a file stored in chunks
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,Eq,PartialEq)]
+pub struct ChunkRef {
+    pub hash: ContentHash,
+    pub len: u32,
+}
+
+// Who may access a file or directory. The mode uses Unix permission bits, but only the read and
+// write bits of the owner and of other users are checked
+#[derive(Serialize,Deserialize,Clone,Eq,Hash,PartialEq,Debug)]
+pub struct Permissions {
+    pub owner: String,
+    pub mode: u16,
+}
+
+#[derive(Debug,Clone,Copy,Eq,PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+impl Permissions {
+    pub fn allows(&self, user: &str, access: Access) -> bool {
+        let mode = if user == self.owner {self.mode >> 6} else {self.mode};
+        match access {
+            Access::Read => mode & 0o4 != 0,
+            Access::Write => mode & 0o2 != 0,
+        }
+    }
+}
+
+// Address a node can be reached at. Only the node itself issues new generations of its
+// entry, so the entry with the highest generation is always the most recent address
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub struct HostEntry {
+    pub address: String,
+    pub generation: u64,
+    // Tombstone for a node that was drained and left the cluster
+    pub removed: bool,
+}
+
+// Liveness of a node as last observed by the local daemon's heartbeats
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub struct NodeStatus {
+    pub node: Node,
+    pub address: Option<String>,
+    pub reachable: bool,
+    pub last_seen: Option<SystemTime>,
+    pub round_trip_time: Option<Duration>,
+}
+
+// Where to store a new file or directory. Auto leaves the choice to the placement policy of the
+// directory it is created in
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub enum Placement {
+    Node(Node),
+    Auto,
+}
+
+impl From<Node> for Placement {
+    fn from(node: Node) -> Placement {
+        Placement::Node(node)
+    }
+}
+
+// How automatically placed files are spread over the nodes. A directory without a policy uses
+// the policy of the closest ancestor that has one, or SameAsParent if none of them do
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub enum PlacementPolicy {
+    SameAsParent,
+    MostFreeSpace,
+    RoundRobin,
+    // The node of the rule with the longest prefix matching the path, or the parent's node if no rule matches
+    Rules(Vec<PlacementRule>),
+}
+
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub struct PlacementRule {
+    pub prefix: String,
+    pub node: Node,
+}
+
+// Bytes stored on a node, and the most it may store if it has a quota
+#[derive(Serialize,Deserialize,Clone,Copy,Eq,PartialEq,Debug)]
+pub struct Capacity {
+    pub used: u64,
+    pub quota: Option<u64>,
+}
+
+impl Capacity {
+    pub fn free(&self) -> Option<u64> {
+        self.quota.map(|quota| quota.saturating_sub(self.used))
+    }
+}
+
+// Capacity is None for nodes that could not be reached
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub struct NodeCapacity {
+    pub node: Node,
+    pub capacity: Option<Capacity>,
 }
 
 #[derive(Serialize,Deserialize,Clone,Eq,Hash,PartialEq,Debug)]
@@ -88,5 +485,13 @@ pub enum VPFSError {
     NotAccessible, // We can not access the node need to complete request
     NotADirectory,
     AlreadyExists(DirectoryEntry),
+    PermissionDenied,
+    QuotaExceeded,
+    BadFrame(String),
+    TooLarge,
+    // The data read did not match the hash stored with it
+    Corrupted,
+    // The chunks a write referred to that the owner does not have
+    MissingChunks(Vec<ContentHash>),
     Other(String),
 }
\ No newline at end of file
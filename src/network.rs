@@ -0,0 +1,121 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::stream::*;
+
+// How a daemon opens connections to other daemons. Addresses are the ones daemons announce with
+// their listening address, the network decides what they mean
+pub trait Network: Send + Sync {
+    fn connect(&self, addr: &str, timeout: Duration) -> io::Result<Box<dyn Stream>>;
+}
+
+pub struct TcpNetwork;
+
+impl Network for TcpNetwork {
+    fn connect(&self, addr: &str, timeout: Duration) -> io::Result<Box<dyn Stream>> {
+        let mut last_error = io::Error::from(io::ErrorKind::AddrNotAvailable);
+        for socket_addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket_addr, timeout) {
+                Ok(stream) => return Ok(Box::new(stream)),
+                Err(error) => last_error = error,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+type Acceptor = Arc<dyn Fn(MemoryStream) + Send + Sync>;
+
+// Connects daemons running in one process through memory streams. Each daemon connects through
+// its own endpoint, so the links between two addresses can be cut to simulate a partition
+#[derive(Default)]
+pub struct MemoryNetwork {
+    listeners: Mutex<HashMap<String, Acceptor>>,
+    partitions: Mutex<HashSet<(String, String)>>,
+    links: Mutex<Vec<Link>>,
+}
+
+struct Link {
+    ends: (String, String),
+    closer: MemoryStreamCloser,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Arc<MemoryNetwork> {
+        Arc::new(MemoryNetwork::default())
+    }
+
+    // Connections to addr are handed to accept, which must not block
+    pub fn listen(&self, addr: &str, accept: impl Fn(MemoryStream) + Send + Sync + 'static) {
+        self.listeners.lock().unwrap().insert(addr.to_string(), Arc::new(accept));
+    }
+
+    // Refuses further connections to addr and closes the ones it has, as if its machine went down
+    pub fn unlisten(&self, addr: &str) {
+        self.listeners.lock().unwrap().remove(addr);
+        self.close_links(|ends| ends.0 == addr || ends.1 == addr);
+    }
+
+    // The network as seen by the daemon listening on addr
+    pub fn endpoint(self: &Arc<Self>, addr: &str) -> Arc<dyn Network> {
+        Arc::new(MemoryEndpoint { network: self.clone(), addr: addr.to_string() })
+    }
+
+    pub fn connect_from(&self, from: &str, to: &str) -> io::Result<MemoryStream> {
+        if self.is_partitioned(from, to) {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        let accept = self.listeners.lock().unwrap().get(to).cloned().ok_or(io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        let (stream, accepted) = MemoryStream::pair();
+        let mut links = self.links.lock().unwrap();
+        links.retain(|link| !link.closer.is_closed());
+        links.push(Link { ends: (from.to_string(), to.to_string()), closer: stream.closer() });
+        drop(links);
+        accept(accepted);
+        Ok(stream)
+    }
+
+    // Cuts every link between the two addresses, in both directions, until healed
+    pub fn partition(&self, a: &str, b: &str) {
+        self.partitions.lock().unwrap().insert(MemoryNetwork::pair(a, b));
+        self.close_links(|ends| MemoryNetwork::pair(&ends.0, &ends.1) == MemoryNetwork::pair(a, b));
+    }
+
+    pub fn heal(&self, a: &str, b: &str) {
+        self.partitions.lock().unwrap().remove(&MemoryNetwork::pair(a, b));
+    }
+
+    pub fn heal_all(&self) {
+        self.partitions.lock().unwrap().clear();
+    }
+
+    pub fn is_partitioned(&self, a: &str, b: &str) -> bool {
+        self.partitions.lock().unwrap().contains(&MemoryNetwork::pair(a, b))
+    }
+
+    fn pair(a: &str, b: &str) -> (String, String) {
+        if a <= b { (a.to_string(), b.to_string()) } else { (b.to_string(), a.to_string()) }
+    }
+
+    fn close_links(&self, matches: impl Fn(&(String, String)) -> bool) {
+        let mut links = self.links.lock().unwrap();
+        for link in links.iter().filter(|link| matches(&link.ends)) {
+            link.closer.close();
+        }
+        links.retain(|link| !link.closer.is_closed());
+    }
+}
+
+struct MemoryEndpoint {
+    network: Arc<MemoryNetwork>,
+    addr: String,
+}
+
+impl Network for MemoryEndpoint {
+    fn connect(&self, addr: &str, _timeout: Duration) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(self.network.connect_from(&self.addr, addr)?))
+    }
+}
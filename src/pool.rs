@@ -0,0 +1,126 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Called with whether the job waited in the queue for too long, in which case it is not run
+type Job = Box<dyn FnOnce(bool) + Send>;
+
+struct QueuedJob {
+    job: Job,
+    queued_at: Instant,
+}
+
+// A bounded number of threads running jobs, started as they are needed and then kept around.
+// Jobs wait in a bounded queue while every thread is busy, and are refused once it is full. A job
+// that waits longer than max_wait is expired instead of run, by a thread of its own
+pub struct WorkerPool {
+    max_workers: usize,
+    max_queued: usize,
+    max_wait: Duration,
+    state: Mutex<PoolState>,
+    job_ready: Condvar,
+    queue_changed: Condvar,
+}
+
+#[derive(Default)]
+struct PoolState {
+    workers: usize,
+    idle: usize,
+    queue: VecDeque<QueuedJob>,
+    closed: bool,
+}
+
+impl WorkerPool {
+    pub fn new(max_workers: usize, max_queued: usize, max_wait: Duration) -> Arc<WorkerPool> {
+        let pool = Arc::new(WorkerPool {
+            max_workers: max_workers.max(1),
+            max_queued,
+            max_wait,
+            state: Mutex::new(PoolState::default()),
+            job_ready: Condvar::new(),
+            queue_changed: Condvar::new(),
+        });
+        let expiring_pool = pool.clone();
+        thread::spawn(move || expiring_pool.expire());
+        pool
+    }
+
+    // Runs job with value on a worker thread, or expired with value if it waits too long for one.
+    // Expired runs on the thread that expires jobs, so it must not block. If the pool is full or
+    // closed, value is handed back so the caller can turn it away
+    pub fn execute<T: Send + 'static>(self: &Arc<Self>, value: T, job: impl FnOnce(T) + Send + 'static, expired: impl FnOnce(T) + Send + 'static) -> Result<(), T> {
+        let mut state = self.state.lock().unwrap();
+        let has_room = state.workers < self.max_workers || state.queue.len() < state.idle + self.max_queued;
+        if state.closed || !has_room {
+            return Err(value);
+        }
+        let job: Job = Box::new(move |too_late| if too_late { expired(value) } else { job(value) });
+        state.queue.push_back(QueuedJob { job, queued_at: Instant::now() });
+        if state.queue.len() > state.idle && state.workers < self.max_workers {
+            state.workers += 1;
+            let pool = self.clone();
+            thread::spawn(move || pool.work());
+        }
+        else {
+            self.job_ready.notify_one();
+        }
+        self.queue_changed.notify_one();
+        Ok(())
+    }
+
+    // Refuses further jobs and drops the queued ones. Jobs already running are left to finish,
+    // after which their threads exit
+    pub fn close(&self) {
+        let queued = {
+            let mut state = self.state.lock().unwrap();
+            state.closed = true;
+            std::mem::take(&mut state.queue)
+        };
+        drop(queued);
+        self.job_ready.notify_all();
+        self.queue_changed.notify_all();
+    }
+
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(queued) = state.queue.pop_front() {
+                drop(state);
+                // A job that panics only loses its own work, not the thread's place in the pool
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| (queued.job)(false)));
+                state = self.state.lock().unwrap();
+            }
+            else if state.closed {
+                state.workers -= 1;
+                return;
+            }
+            else {
+                state.idle += 1;
+                state = self.job_ready.wait(state).unwrap();
+                state.idle -= 1;
+            }
+        }
+    }
+
+    // Jobs are queued in order, so only the oldest one has to be watched
+    fn expire(&self) {
+        let mut state = self.state.lock().unwrap();
+        while !state.closed {
+            let Some(queued_at) = state.queue.front().map(|queued| queued.queued_at) else {
+                state = self.queue_changed.wait(state).unwrap();
+                continue;
+            };
+            let waited = queued_at.elapsed();
+            if waited < self.max_wait {
+                state = self.queue_changed.wait_timeout(state, self.max_wait - waited).unwrap().0;
+                continue;
+            }
+            let queued = state.queue.pop_front().expect("Queue emptied while locked");
+            drop(state);
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| (queued.job)(true)));
+            state = self.state.lock().unwrap();
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::{clone, env, io::{self, BufReader, Read, Write}, process::{self, exit, Stdio}, sync::Arc, thread};
+use std::{io::{self, BufReader, Read, Write}, process::{self, exit, Stdio}, sync::Arc, thread};
 use vpfs::*;
 use vpfs::messages::*;
 use clap::Parser;
@@ -8,6 +8,19 @@ use clap::Parser;
 struct Opt {
     #[arg(short, long, default_value_t = 8080)]
     port: u16,
+
+    // Connect through the daemon's Unix domain socket instead of TCP
+    #[arg(short = 'u', long)]
+    socket: Option<String>,
+
+    // User to act as over TCP, which needs the cluster key. Without it the shell acts as nobody,
+    // and through the Unix domain socket it acts as the user running it
+    #[arg(long)]
+    user: Option<String>,
+
+    // File holding the cluster key, to act as a user other than nobody over TCP
+    #[arg(short = 'k', long)]
+    key_file: Option<String>,
 }
 
 enum RedirectType {
@@ -56,6 +69,9 @@ impl Command {
                     }
                 }
             }
+            Err(VPFSError::Corrupted) => {
+                println!("File is corrupted");
+            }
             Err(_) => {
                 println!("No longer able to right to file");
             }
@@ -66,7 +82,9 @@ impl Command {
         let mut data = vec![];
         match pipe.read_to_end(&mut data) {
             Ok(_) => {
-                vpfs.store(&file_name, &data);
+                if let Err(error) = vpfs.store(&file_name, &data) {
+                    println!("Could not store {}: {:?}", file_name, error);
+                }
             }
             Err(error) => {
                 println!("Got {} error trying to read from pipe", error);
@@ -184,10 +202,10 @@ impl Command {
 }
 
 fn file_name_to_full_path(cwd: &str, file_name: &str) -> String {
-    if file_name.starts_with('/') {
-        file_name[1..].to_string()
+    if let Some(file_name) = file_name.strip_prefix('/') {
+        file_name.to_string()
     }
-    else if cwd != ""{
+    else if !cwd.is_empty(){
         format!("{}/{}", cwd, file_name)
     }
     else {
@@ -200,13 +218,13 @@ fn parse_piped_command(command_string: &str, cwd: &str) -> Option<PipeableComman
     let lhs_command = parse_nonpiped_command(lhs_string, cwd);
     let rhs_command = parse_command(rhs_string, cwd);
 
-    if rhs_command.is_none() {
+    let Some(rhs_command) = rhs_command else {
         println!("Syntax error, right side of pipe invalid");
-        None
-    }
-    else if let Some(lhs_command) = lhs_command
+        return None;
+    };
+    if let Some(lhs_command) = lhs_command
     {
-        let rhs_command = Box::from(rhs_command.unwrap());
+        let rhs_command = Box::from(rhs_command);
         Some(PipeableCommand::Piped(lhs_command,  rhs_command))
     }
     else {
@@ -276,7 +294,7 @@ fn parse_nonpiped_command(mut command_string: &str, cwd: &str) -> Option<Command
     if let Some(program) = program{
         let mut command = Command {
             program: String::from(program),
-            args: args,
+            args,
             stdin: RedirectType::NoRedirect,
             stdout: RedirectType::NoRedirect,
             stderr: RedirectType::NoRedirect
@@ -303,17 +321,14 @@ fn parse_command(command_string: &str, cwd: &str) -> Option<PipeableCommand> {
     }
     else {
         let command = parse_nonpiped_command(command_string, cwd);
-        match command {
-            Some(command) => Some(PipeableCommand::NonPiped(command)),
-            None => None
-        }
+        command.map(PipeableCommand::NonPiped)
     }
 }
 
 fn run_cd(command: Command, vpfs: Arc<VPFS>, cwd: &mut String){
     if let Some(path) = command.args.first() {
         let full_path = file_name_to_full_path(cwd, path);
-        if full_path == "" {
+        if full_path.is_empty() {
             *cwd = String::from("");
         }
         else if let Ok(directory_entry) = vpfs.find(&full_path) {
@@ -345,8 +360,8 @@ fn run_mkdir(command: Command, vpfs: Arc<VPFS>, cwd: &str) {
     }
 }
 
-fn run_ls(command: Command, vpfs: Arc<VPFS>, cwd: &str) {
-    let fetch_result = if cwd == "" {
+fn run_ls(_command: Command, vpfs: Arc<VPFS>, cwd: &str) {
+    let fetch_result = if cwd.is_empty() {
         vpfs.fetch(".")
     }
     else {
@@ -356,7 +371,7 @@ fn run_ls(command: Command, vpfs: Arc<VPFS>, cwd: &str) {
         let mut directory_reader = BufReader::new(&*directory_data);
         let mut read_result: Result<DirectoryEntry, serde_bare::error::Error> = serde_bare::from_reader(&mut directory_reader);
         while let Ok(entry) = read_result {
-            println!("{} {} {}", if entry.is_dir {"d"} else {"-"}, entry.name, entry.location.node.name);
+            println!("{} {:03o} {} {} {}", if entry.is_dir {"d"} else {"-"}, entry.permissions.mode, entry.permissions.owner, entry.name, entry.location.node.name);
             read_result = serde_bare::from_reader(&mut directory_reader);
         }
     }
@@ -365,6 +380,63 @@ fn run_ls(command: Command, vpfs: Arc<VPFS>, cwd: &str) {
     }
 }
 
+fn run_nodes(vpfs: Arc<VPFS>) {
+    for status in vpfs.nodes() {
+        let state = if status.reachable {"up"} else {"down"};
+        let rtt = match status.round_trip_time {
+            Some(rtt) => format!("{}ms", rtt.as_millis()),
+            None => "-".to_string(),
+        };
+        println!("{} {} {} {}", status.node.name, state, rtt, status.address.unwrap_or("-".to_string()));
+    }
+}
+
+fn run_df(vpfs: Arc<VPFS>) {
+    for node_capacity in vpfs.df() {
+        match node_capacity.capacity {
+            Some(capacity) => {
+                let quota = capacity.quota.map_or("-".to_string(), |quota| quota.to_string());
+                let free = capacity.free().map_or("-".to_string(), |free| free.to_string());
+                println!("{} {} {} {}", node_capacity.node.name, capacity.used, quota, free);
+            }
+            None => println!("{} down", node_capacity.node.name),
+        }
+    }
+}
+
+fn parse_placement_policy(args: &[String], cwd: &str) -> Option<Option<PlacementPolicy>> {
+    match args.first().map(String::as_str) {
+        Some("none") => Some(None),
+        Some("same-as-parent") => Some(Some(PlacementPolicy::SameAsParent)),
+        Some("most-free") => Some(Some(PlacementPolicy::MostFreeSpace)),
+        Some("round-robin") => Some(Some(PlacementPolicy::RoundRobin)),
+        Some("rules") => {
+            let mut rules = vec![];
+            for rule in &args[1..] {
+                let (prefix, node) = rule.split_once('=')?;
+                rules.push(PlacementRule { prefix: file_name_to_full_path(cwd, prefix), node: Node { name: node.to_string() } });
+            }
+            Some(Some(PlacementPolicy::Rules(rules)))
+        }
+        _ => None,
+    }
+}
+
+fn run_placement(command: Command, vpfs: Arc<VPFS>, cwd: &str) {
+    let Some(path) = command.args.first() else {
+        println!("Error no path specified");
+        return;
+    };
+    let Some(policy) = parse_placement_policy(&command.args[1..], cwd) else {
+        println!("Usage: placement <directory> none|same-as-parent|most-free|round-robin|rules <prefix>=<node>...");
+        return;
+    };
+    let full_path = file_name_to_full_path(cwd, path);
+    if let Err(error) = vpfs.set_placement_policy(&full_path, policy) {
+        println!("Could not set placement policy of {}: {:?}", path, error);
+    }
+}
+
 fn run_nonpiped_command(command: Command, vpfs: Arc<VPFS>, cwd: &mut String) {
     let program = command.program.clone();
     match program.as_str() {
@@ -374,6 +446,9 @@ fn run_nonpiped_command(command: Command, vpfs: Arc<VPFS>, cwd: &mut String) {
         "pwd" => println!("/{}", cwd),        
         "mkdir" => run_mkdir(command, vpfs, cwd),
         "ls" => run_ls(command, vpfs, cwd),
+        "nodes" => run_nodes(vpfs),
+        "df" => run_df(vpfs),
+        "placement" => run_placement(command, vpfs, cwd),
         // Normal binaries
         _ => {
             let fork_ret = command.spawn(vpfs);
@@ -427,7 +502,23 @@ fn run_command(command: PipeableCommand, vpfs: Arc<VPFS>, cwd: &mut String) {
 
 fn main() {
     let opt = Opt::parse();
-    let vpfs = Arc::new(VPFS::connect(opt.port).expect("Failed to connect to local daemon"));
+    let vpfs = match (opt.socket, opt.key_file) {
+        (Some(_), _) if opt.user.is_some() => {
+            eprintln!("Through the Unix domain socket the shell acts as the user running it");
+            exit(1);
+        }
+        (Some(socket_path), _) => VPFS::connect_unix(socket_path),
+        (None, Some(key_file)) => {
+            let cluster_key = std::fs::read(&key_file).expect("Could not read cluster key file");
+            VPFS::connect_as(opt.port, &opt.user.unwrap_or_else(current_user), &cluster_key)
+        }
+        (None, None) if opt.user.is_some() => {
+            eprintln!("Acting as a user over TCP needs the cluster key, see -k");
+            exit(1);
+        }
+        (None, None) => VPFS::connect(opt.port),
+    };
+    let vpfs = Arc::new(vpfs.expect("Failed to connect to local daemon"));
     let mut cwd = "".to_string();
 
     loop {
@@ -0,0 +1,408 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::VPFS;
+use crate::clock::*;
+use crate::daemon::Daemon;
+use crate::messages::*;
+use crate::network::*;
+use crate::storage::*;
+
+// Deterministic simulation of a whole cluster in one process. Daemons talk over a MemoryNetwork,
+// keep their files in MemoryStorage and read the time from a ManualClock. Every client operation,
+// heartbeat, partition and crash is picked by an RNG seeded from the config and run to completion
+// before the next one starts, so a seed always replays the same run. The results of every step
+// are checked against a model of what the cluster may return
+pub struct SimConfig {
+    pub seed: u64,
+    // The first node is the root, and is never crashed
+    pub nodes: usize,
+    pub steps: usize,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig { seed: 0, nodes: 4, steps: 200 }
+    }
+}
+
+// One line for every step taken, so two runs of one seed can be compared
+pub struct SimReport {
+    pub log: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct SimFailure {
+    pub seed: u64,
+    pub step: usize,
+    pub message: String,
+    pub log: Vec<String>,
+}
+
+impl fmt::Display for SimFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Simulation with seed {} failed at step {}: {}", self.seed, self.step, self.message)?;
+        for line in &self.log {
+            writeln!(f, "  {line}")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn run(config: &SimConfig) -> Result<SimReport, SimFailure> {
+    let mut simulation = Simulation::new(config.seed);
+    let fail = |simulation: Simulation, step: usize, message: String| SimFailure {
+        seed: config.seed,
+        step,
+        message,
+        log: simulation.log,
+    };
+    if let Err(message) = simulation.start_cluster(config.nodes) {
+        return Err(fail(simulation, 0, message));
+    }
+    for step in 1..=config.steps {
+        simulation.clock.advance(Duration::from_millis(1));
+        if let Err(message) = simulation.step() {
+            return Err(fail(simulation, step, message));
+        }
+    }
+    if let Err(message) = simulation.recover() {
+        return Err(fail(simulation, config.steps + 1, message));
+    }
+    Ok(SimReport { log: simulation.log })
+}
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(1000);
+
+struct SimNode {
+    name: String,
+    addr: String,
+    // Outlives the daemon, so a crashed node restarts with the files it had
+    storage: Arc<MemoryStorage>,
+    // None while the node is crashed
+    daemon: Option<Daemon>,
+    client: Option<VPFS>,
+}
+
+impl SimNode {
+    fn node(&self) -> Node {
+        Node { name: self.name.clone() }
+    }
+}
+
+// What the cluster may return for a file
+struct FileModel {
+    location: Location,
+    // Every version written successfully, the last one is the owner's data
+    versions: Vec<Vec<u8>>,
+    // Data of writes that failed since, which the owner may still have stored
+    uncertain: Vec<Vec<u8>>,
+}
+
+impl FileModel {
+    fn may_hold(&self, data: &[u8]) -> bool {
+        self.versions.last().is_some_and(|current| current == data) || self.uncertain.iter().any(|version| version == data)
+    }
+
+    fn ever_held(&self, data: &[u8]) -> bool {
+        self.versions.iter().chain(&self.uncertain).any(|version| version == data)
+    }
+}
+
+struct Simulation {
+    rng: StdRng,
+    clock: Arc<ManualClock>,
+    network: Arc<MemoryNetwork>,
+    nodes: Vec<SimNode>,
+    files: BTreeMap<String, FileModel>,
+    // Paths of places that failed, the file may or may not have been created
+    uncertain_paths: Vec<String>,
+    next_file: usize,
+    log: Vec<String>,
+}
+
+impl Simulation {
+    fn new(seed: u64) -> Simulation {
+        Simulation {
+            rng: StdRng::seed_from_u64(seed),
+            clock: Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000_000))),
+            network: MemoryNetwork::new(),
+            nodes: vec![],
+            files: BTreeMap::new(),
+            uncertain_paths: vec![],
+            next_file: 0,
+            log: vec![],
+        }
+    }
+
+    // Every node gets a directory of its own, so finds traverse directories stored on other nodes
+    fn start_cluster(&mut self, nodes: usize) -> Result<(), String> {
+        for index in 0..nodes {
+            let name = if index == 0 { "root".to_string() } else { format!("node{index}") };
+            self.nodes.push(SimNode {
+                addr: format!("{name}:8080"),
+                name,
+                storage: Arc::new(MemoryStorage::with_clock(self.clock.clone())),
+                daemon: None,
+                client: None,
+            });
+            self.start(index)?;
+        }
+        for index in 0..nodes {
+            let at = self.nodes[index].node();
+            self.client(0).mkdir(&format!("d{index}"), at).map_err(|error| format!("Could not create d{index}: {error:?}"))?;
+        }
+        self.log.push(format!("started {nodes} nodes"));
+        Ok(())
+    }
+
+    fn start(&mut self, index: usize) -> Result<(), String> {
+        let seed = self.rng.random();
+        let node = &self.nodes[index];
+        let mut builder = Daemon::builder()
+            .name(&node.name)
+            .listening_addr(&node.addr)
+            .storage(Box::new(node.storage.clone()))
+            .network(self.network.endpoint(&node.addr))
+            .clock(self.clock.clone())
+            .heartbeat_interval(HEARTBEAT_INTERVAL)
+            .seed(seed)
+            .manual_heartbeats();
+        if index != 0 {
+            builder = builder.root_addr(&self.nodes[0].addr);
+        }
+        let daemon = builder.spawn()?;
+        self.network.listen(&node.addr, daemon.acceptor());
+        let stream = self.network.connect_from(&format!("client@{}", node.name), &node.addr).map_err(|error| error.to_string())?;
+        let client = VPFS::connect_stream(stream).map_err(|error| error.to_string())?;
+        let node = &mut self.nodes[index];
+        node.daemon = Some(daemon);
+        node.client = Some(client);
+        Ok(())
+    }
+
+    fn crash(&mut self, index: usize) {
+        let node = &mut self.nodes[index];
+        node.client = None;
+        self.network.unlisten(&node.addr);
+        if let Some(daemon) = node.daemon.take() {
+            daemon.kill();
+        }
+    }
+
+    fn client(&self, index: usize) -> &VPFS {
+        self.nodes[index].client.as_ref().expect("Node is down")
+    }
+
+    fn running_nodes(&self) -> Vec<usize> {
+        (0..self.nodes.len()).filter(|index| self.nodes[*index].daemon.is_some()).collect()
+    }
+
+    fn pick<T: Copy>(&mut self, choices: &[T]) -> Option<T> {
+        (!choices.is_empty()).then(|| choices[self.rng.random_range(0..choices.len())])
+    }
+
+    fn pick_path(&mut self) -> Option<String> {
+        let paths: Vec<&String> = self.files.keys().collect();
+        let index = (!paths.is_empty()).then(|| self.rng.random_range(0..paths.len()))?;
+        Some(self.files.keys().nth(index).unwrap().clone())
+    }
+
+    fn step(&mut self) -> Result<(), String> {
+        let running = self.running_nodes();
+        let via = self.pick(&running).unwrap();
+        match self.rng.random_range(0..100) {
+            0..15 => self.place(via),
+            15..40 => self.write(via),
+            40..65 => self.read(via),
+            65..78 => self.find(via),
+            78..88 => {
+                self.clock.advance(HEARTBEAT_INTERVAL);
+                for index in &running {
+                    self.nodes[*index].daemon.as_ref().unwrap().heartbeat();
+                }
+                self.log.push("heartbeat".to_string());
+                Ok(())
+            }
+            88..92 => {
+                let a = self.rng.random_range(0..self.nodes.len());
+                let b = self.rng.random_range(0..self.nodes.len());
+                if a != b {
+                    self.network.partition(&self.nodes[a].addr, &self.nodes[b].addr);
+                    self.log.push(format!("partition {} {}", self.nodes[a].name, self.nodes[b].name));
+                }
+                Ok(())
+            }
+            92..95 => {
+                self.network.heal_all();
+                self.log.push("heal".to_string());
+                Ok(())
+            }
+            95..97 => {
+                let crashable: Vec<usize> = running.into_iter().filter(|index| *index != 0).collect();
+                if let Some(index) = self.pick(&crashable) {
+                    self.crash(index);
+                    self.log.push(format!("crash {}", self.nodes[index].name));
+                }
+                Ok(())
+            }
+            _ => {
+                let crashed: Vec<usize> = (0..self.nodes.len()).filter(|index| !running.contains(index)).collect();
+                if let Some(index) = self.pick(&crashed) {
+                    self.start(index)?;
+                    self.log.push(format!("restart {}", self.nodes[index].name));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // A find after a successful place must return the location the place did
+    fn place(&mut self, via: usize) -> Result<(), String> {
+        let at = self.rng.random_range(0..self.nodes.len());
+        let directory = self.rng.random_range(0..=self.nodes.len());
+        let path = if directory == self.nodes.len() { format!("f{}", self.next_file) } else { format!("d{directory}/f{}", self.next_file) };
+        self.next_file += 1;
+        let result = self.client(via).place(&path, self.nodes[at].node());
+        self.log.push(format!("{} place {path} on {}: {result:?}", self.nodes[via].name, self.nodes[at].name));
+        let Ok(location) = result else {
+            self.uncertain_paths.push(path);
+            return Ok(());
+        };
+        let found = self.client(via).find(&path);
+        self.log.push(format!("{} find {path}: {found:?}", self.nodes[via].name));
+        match found {
+            Ok(entry) if entry.location == location => {}
+            found => return Err(format!("Find of {path} returned {found:?} right after place returned {location:?}")),
+        }
+        self.files.insert(path, FileModel { location, versions: vec![vec![]], uncertain: vec![] });
+        Ok(())
+    }
+
+    fn write(&mut self, via: usize) -> Result<(), String> {
+        let Some(path) = self.pick_path() else {
+            return Ok(());
+        };
+        let len = self.rng.random_range(1..=256);
+        let data: Vec<u8> = (0..len).map(|_| self.rng.random()).collect();
+        let location = self.files[&path].location.clone();
+        let result = self.client(via).write(location, &data);
+        self.log.push(format!("{} write {path} {}: {result:?}", self.nodes[via].name, hex(&content_hash(&data))));
+        let file = self.files.get_mut(&path).unwrap();
+        match result {
+            Ok(()) => {
+                file.versions.push(data);
+                file.uncertain.clear();
+            }
+            Err(_) => file.uncertain.push(data),
+        }
+        Ok(())
+    }
+
+    // Reads must return the owner's data, and cached reads data the owner has had
+    fn read(&mut self, via: usize) -> Result<(), String> {
+        let Some(path) = self.pick_path() else {
+            return Ok(());
+        };
+        let location = self.files[&path].location.clone();
+        let result = self.client(via).read(location);
+        self.log.push(format!("{} read {path}: {}", self.nodes[via].name, describe(&result)));
+        let file = self.files.get_mut(&path).unwrap();
+        match result {
+            Ok(data) if file.may_hold(&data) => {
+                if file.versions.last() != Some(&data) {
+                    file.versions.push(data);
+                    file.uncertain.clear();
+                }
+            }
+            Ok(data) => return Err(format!("Read of {path} returned {}, which is not the owner's data", hex(&content_hash(&data)))),
+            Err(VPFSError::OnlyInCache(cache_location)) => {
+                let cached = self.client(via).read(cache_location);
+                self.log.push(format!("{} read cached {path}: {}", self.nodes[via].name, describe(&cached)));
+                let file = &self.files[&path];
+                if let Ok(data) = cached {
+                    if !file.ever_held(&data) {
+                        return Err(format!("Cached copy of {path} is {}, which the owner never had", hex(&content_hash(&data))));
+                    }
+                }
+            }
+            Err(_) => {}
+        }
+        Ok(())
+    }
+
+    fn find(&mut self, via: usize) -> Result<(), String> {
+        let uncertain = !self.uncertain_paths.is_empty() && self.rng.random_range(0..4) == 0;
+        let path = if uncertain {
+            self.uncertain_paths[self.rng.random_range(0..self.uncertain_paths.len())].clone()
+        }
+        else if let Some(path) = self.pick_path() {
+            path
+        }
+        else {
+            return Ok(());
+        };
+        let result = self.client(via).find(&path);
+        self.log.push(format!("{} find {path}: {result:?}", self.nodes[via].name));
+        let entry = match result {
+            Ok(entry) | Err(VPFSError::CacheNeededForTraversal(entry)) => entry,
+            Err(_) => return Ok(()),
+        };
+        match self.files.get(&path) {
+            Some(file) if file.location != entry.location => {
+                Err(format!("Find of {path} returned {:?} but it was placed at {:?}", entry.location, file.location))
+            }
+            Some(_) => Ok(()),
+            None => {
+                // The failed place did create the file after all
+                self.uncertain_paths.retain(|uncertain_path| *uncertain_path != path);
+                self.files.insert(path, FileModel { location: entry.location, versions: vec![vec![]], uncertain: vec![] });
+                Ok(())
+            }
+        }
+    }
+
+    // Once every node is back and the network is healed, every file must be readable again
+    fn recover(&mut self) -> Result<(), String> {
+        self.network.heal_all();
+        for index in 0..self.nodes.len() {
+            if self.nodes[index].daemon.is_none() {
+                self.start(index)?;
+            }
+        }
+        for _ in 0..2 {
+            self.clock.advance(HEARTBEAT_INTERVAL);
+            for node in &self.nodes {
+                node.daemon.as_ref().unwrap().heartbeat();
+            }
+        }
+        self.log.push("recovered".to_string());
+        let paths: Vec<String> = self.files.keys().cloned().collect();
+        for path in paths {
+            let file = &self.files[&path];
+            let result = self.client(0).read(file.location.clone());
+            self.log.push(format!("root read {path}: {}", describe(&result)));
+            match result {
+                Ok(data) if file.may_hold(&data) => {}
+                result => return Err(format!("Read of {path} after recovering returned {}", describe(&result))),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn hex(hash: &ContentHash) -> String {
+    hash.iter().take(4).map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn describe(result: &Result<Vec<u8>, VPFSError>) -> String {
+    match result {
+        Ok(data) => format!("Ok({})", hex(&content_hash(data))),
+        Err(error) => format!("{error:?}"),
+    }
+}
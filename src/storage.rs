@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::clock::*;
+
+// Where a daemon keeps the files it stores, its cached copies and its metadata. Files are named
+// relative to the backend's root, a name containing '/' places the file in a subdirectory.
+// Locking is left to the daemon, a backend only needs to keep each call consistent on its own
+pub trait Storage: Send + Sync {
+    fn read(&self, name: &str) -> io::Result<Vec<u8>>;
+
+    // Creates the file if it does not exist, otherwise replaces its contents
+    fn write(&self, name: &str, data: &[u8]) -> io::Result<()>;
+
+    // Fails with NotFound if the file does not exist
+    fn append(&self, name: &str, data: &[u8]) -> io::Result<()>;
+
+    // Creates an empty file, failing with AlreadyExists if there already is one with that name
+    fn create_new(&self, name: &str) -> io::Result<()>;
+
+    fn remove(&self, name: &str) -> io::Result<()>;
+
+    // Replaces any file already named to
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    fn len(&self, name: &str) -> io::Result<u64>;
+
+    fn modified(&self, name: &str) -> io::Result<SystemTime>;
+
+    // Names of the files at the top level, files in subdirectories are left out
+    fn list(&self) -> io::Result<Vec<String>>;
+}
+
+// Files in a directory on disk
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    // Creates the directory if it does not exist yet
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<FsStorage> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(FsStorage { root })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+}
+
+impl Storage for FsStorage {
+    fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(name))
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.path(name);
+        match fs::write(&path, data) {
+            // Subdirectories are created the first time a file is written into them
+            Err(error) if error.kind() == io::ErrorKind::NotFound && name.contains('/') => {
+                fs::create_dir_all(path.parent().unwrap())?;
+                fs::write(&path, data)
+            }
+            result => result,
+        }
+    }
+
+    fn append(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        fs::OpenOptions::new().append(true).open(self.path(name))?.write_all(data)
+    }
+
+    fn create_new(&self, name: &str) -> io::Result<()> {
+        fs::File::create_new(self.path(name)).map(|_| ())
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.path(name))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.path(from), self.path(to))
+    }
+
+    fn len(&self, name: &str) -> io::Result<u64> {
+        fs::metadata(self.path(name)).map(|metadata| metadata.len())
+    }
+
+    fn modified(&self, name: &str) -> io::Result<SystemTime> {
+        fs::metadata(self.path(name))?.modified()
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = vec![];
+        for dir_entry in fs::read_dir(&self.root)?.flatten() {
+            if dir_entry.file_type().is_ok_and(|file_type| file_type.is_file()) {
+                names.push(dir_entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Ok(names)
+    }
+}
+
+// Storage shared with whoever else holds it, e.g. to restart a daemon on the storage it left behind
+impl<S: Storage + ?Sized> Storage for Arc<S> {
+    fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        (**self).read(name)
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        (**self).write(name, data)
+    }
+
+    fn append(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        (**self).append(name, data)
+    }
+
+    fn create_new(&self, name: &str) -> io::Result<()> {
+        (**self).create_new(name)
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        (**self).remove(name)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        (**self).rename(from, to)
+    }
+
+    fn len(&self, name: &str) -> io::Result<u64> {
+        (**self).len(name)
+    }
+
+    fn modified(&self, name: &str) -> io::Result<SystemTime> {
+        (**self).modified(name)
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        (**self).list()
+    }
+}
+
+// Files kept in memory, lost when the daemon exits. Lets a daemon run without touching the disk
+pub struct MemoryStorage {
+    files: Mutex<HashMap<String, MemoryFile>>,
+    clock: Arc<dyn Clock>,
+}
+
+struct MemoryFile {
+    data: Vec<u8>,
+    modified: SystemTime,
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage::with_clock(Arc::new(SystemClock))
+    }
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    // Files are stamped with the clock's time when written
+    pub fn with_clock(clock: Arc<dyn Clock>) -> MemoryStorage {
+        MemoryStorage { files: Mutex::new(HashMap::new()), clock }
+    }
+
+    fn with_file<T>(&self, name: &str, f: impl FnOnce(&mut MemoryFile) -> T) -> io::Result<T> {
+        let mut files = self.files.lock().unwrap();
+        files.get_mut(name).map(f).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        self.with_file(name, |file| file.data.clone())
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        let file = MemoryFile { data: data.to_vec(), modified: self.clock.now() };
+        self.files.lock().unwrap().insert(name.to_string(), file);
+        Ok(())
+    }
+
+    fn append(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        self.with_file(name, |file| {
+            file.data.extend_from_slice(data);
+            file.modified = self.clock.now();
+        })
+    }
+
+    fn create_new(&self, name: &str) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        if files.contains_key(name) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        files.insert(name.to_string(), MemoryFile { data: vec![], modified: self.clock.now() });
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        self.files.lock().unwrap().remove(name).map(|_| ()).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.remove(from).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        files.insert(to.to_string(), file);
+        Ok(())
+    }
+
+    fn len(&self, name: &str) -> io::Result<u64> {
+        self.with_file(name, |file| file.data.len() as u64)
+    }
+
+    fn modified(&self, name: &str) -> io::Result<SystemTime> {
+        self.with_file(name, |file| file.modified)
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        Ok(self.files.lock().unwrap().keys().filter(|name| !name.contains('/')).cloned().collect())
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Duration;
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::messages::NegotiatedProtocol;
+
+// Byte stream that VPFS messages are sent over. Code sending and receiving messages does not
+// need to know whether the link underneath is a plain socket or an encrypted one
+pub trait Stream: Read + Write + Send {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    // Closes both directions, pending and later reads and writes on either end fail
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+// A stream together with the protocol negotiated for it during the hello
+pub struct Connection {
+    stream: Box<dyn Stream>,
+    pub protocol: Option<NegotiatedProtocol>,
+    // Bytes read ahead while looking for the next frame after a broken one, read again first
+    unread: Vec<u8>,
+    // Set after a frame with a broken header, whose rest is skipped before the next frame is read
+    pub(crate) resync_pending: bool,
+}
+
+impl Connection {
+    pub fn new<S: Stream + 'static>(stream: S) -> Connection {
+        Connection { stream: Box::new(stream), protocol: None, unread: vec![], resync_pending: false }
+    }
+
+    pub(crate) fn unread(&mut self, bytes: &[u8]) {
+        self.unread.splice(0..0, bytes.iter().copied());
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.protocol.as_ref().is_some_and(|protocol| protocol.supports(capability))
+    }
+
+    pub fn speaks(&self, version: u32) -> bool {
+        self.protocol.as_ref().is_some_and(|protocol| protocol.speaks(version))
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.unread.is_empty() {
+            return self.stream.read(buf);
+        }
+        let len = buf.len().min(self.unread.len());
+        buf[..len].copy_from_slice(&self.unread[..len]);
+        self.unread.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Stream for Connection {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.stream.shutdown()
+    }
+}
+
+impl Stream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+impl Stream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+impl<S: Stream + ?Sized> Stream for Box<S> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        (**self).shutdown()
+    }
+}
+
+// One direction of a MemoryStream
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_all();
+    }
+}
+
+// Stream between two ends in the same process, so daemons can talk without sockets. Dropping or
+// shutting down either end closes both directions, like closing a socket
+pub struct MemoryStream {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl MemoryStream {
+    pub fn pair() -> (MemoryStream, MemoryStream) {
+        let (there, back) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+        let end = |incoming: &Arc<Pipe>, outgoing: &Arc<Pipe>| MemoryStream {
+            incoming: incoming.clone(),
+            outgoing: outgoing.clone(),
+            read_timeout: Mutex::new(None),
+        };
+        (end(&back, &there), end(&there, &back))
+    }
+
+    // Lets the stream be closed from elsewhere, without keeping it open
+    pub fn closer(&self) -> MemoryStreamCloser {
+        MemoryStreamCloser { incoming: Arc::downgrade(&self.incoming), outgoing: Arc::downgrade(&self.outgoing) }
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let mut state = self.incoming.state.lock().unwrap();
+        while state.data.is_empty() && !state.closed && !buf.is_empty() {
+            state = match timeout {
+                Some(timeout) => {
+                    let (state, wait) = self.incoming.readable.wait_timeout(state, timeout).unwrap();
+                    if wait.timed_out() && state.data.is_empty() && !state.closed {
+                        return Err(io::Error::from(io::ErrorKind::WouldBlock));
+                    }
+                    state
+                }
+                None => self.incoming.readable.wait(state).unwrap(),
+            };
+        }
+        let len = buf.len().min(state.data.len());
+        for (byte, data) in buf.iter_mut().zip(state.data.drain(..len)) {
+            *byte = data;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.outgoing.state.lock().unwrap();
+        if state.closed {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+        state.data.extend(buf);
+        self.outgoing.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for MemoryStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.incoming.close();
+        self.outgoing.close();
+        Ok(())
+    }
+}
+
+pub struct MemoryStreamCloser {
+    incoming: Weak<Pipe>,
+    outgoing: Weak<Pipe>,
+}
+
+impl MemoryStreamCloser {
+    pub fn close(&self) {
+        for pipe in [&self.incoming, &self.outgoing] {
+            if let Some(pipe) = pipe.upgrade() {
+                pipe.close();
+            }
+        }
+    }
+
+    // Also true once both ends have been dropped
+    pub fn is_closed(&self) -> bool {
+        self.outgoing.upgrade().is_none_or(|pipe| pipe.state.lock().unwrap().closed)
+    }
+}
+
+// Largest plaintext sent in a single record
+const MAX_RECORD_LEN: usize = 1 << 16;
+const TAG_LEN: usize = 16;
+
+// Encrypts everything written to the inner stream with ChaCha20-Poly1305. Each write is sent as a
+// record of its length followed by the ciphertext. Each direction has its own key, and the nonce
+// is a counter of the records sent in that direction, so records can not be replayed or reordered
+pub struct EncryptedStream<S: Stream> {
+    inner: S,
+    send_cipher: ChaCha20Poly1305,
+    receive_cipher: ChaCha20Poly1305,
+    send_counter: u64,
+    receive_counter: u64,
+    plaintext: Vec<u8>,
+    plaintext_pos: usize,
+}
+
+impl<S: Stream> EncryptedStream<S> {
+    pub fn new(inner: S, send_key: &[u8; 32], receive_key: &[u8; 32]) -> Self {
+        EncryptedStream {
+            inner,
+            send_cipher: ChaCha20Poly1305::new(Key::from_slice(send_key)),
+            receive_cipher: ChaCha20Poly1305::new(Key::from_slice(receive_key)),
+            send_counter: 0,
+            receive_counter: 0,
+            plaintext: vec![],
+            plaintext_pos: 0,
+        }
+    }
+
+    fn nonce(counter: u64) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[..8].copy_from_slice(&counter.to_le_bytes());
+        Nonce::from(nonce)
+    }
+
+    // Returns false if the inner stream was closed between records
+    fn receive_record(&mut self) -> io::Result<bool> {
+        let mut len_bytes = [0u8; 4];
+        match self.inner.read_exact(&mut len_bytes) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(error) => return Err(error),
+        }
+        let len = u32::from_be_bytes(len_bytes) as usize;
+        if len > MAX_RECORD_LEN + TAG_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Encrypted record too long"));
+        }
+        let mut ciphertext = vec![0u8; len];
+        self.inner.read_exact(&mut ciphertext)?;
+        self.plaintext = self.receive_cipher.decrypt(&Self::nonce(self.receive_counter), ciphertext.as_slice())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Could not decrypt record"))?;
+        self.plaintext_pos = 0;
+        self.receive_counter += 1;
+        Ok(true)
+    }
+}
+
+impl<S: Stream> Read for EncryptedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.plaintext_pos == self.plaintext.len() {
+            if !self.receive_record()? {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.plaintext.len() - self.plaintext_pos);
+        buf[..len].copy_from_slice(&self.plaintext[self.plaintext_pos..self.plaintext_pos + len]);
+        self.plaintext_pos += len;
+        Ok(len)
+    }
+}
+
+impl<S: Stream> Write for EncryptedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(MAX_RECORD_LEN);
+        let ciphertext = self.send_cipher.encrypt(&Self::nonce(self.send_counter), &buf[..len])
+            .map_err(|_| io::Error::other("Could not encrypt record"))?;
+        self.send_counter += 1;
+        let mut record = Vec::with_capacity(4 + ciphertext.len());
+        record.extend_from_slice(&(ciphertext.len() as u32).to_be_bytes());
+        record.extend_from_slice(&ciphertext);
+        self.inner.write_all(&record)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Stream> Stream for EncryptedStream<S> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown()
+    }
+}
@@ -0,0 +1,90 @@
+use vpfs::*;
+use vpfs::messages::Node;
+use vpfs::daemon::{Daemon, DaemonBuilder};
+use tempfile::TempDir;
+
+// A cluster of daemons running inside the test process. The first daemon is the root, and every
+// daemon listens on a port picked by the OS and keeps its files in its own temporary directory,
+// so tests can run in parallel without sharing any files. The daemons share a cluster key, which
+// test processes use to act as any user. Dropping the cluster shuts the daemons down and removes
+// their directories
+pub struct TestCluster {
+    // Declared before the directories, so the daemons are shut down before their files are removed
+    daemons: Vec<Daemon>,
+    dirs: Vec<TempDir>,
+    key_dir: TempDir,
+    key: Vec<u8>,
+}
+
+impl TestCluster {
+    pub fn start(size: usize) -> TestCluster {
+        TestCluster::start_with(size, |builder| builder)
+    }
+
+    // Lets a test set extra options on every daemon, for example to run a chunked cluster
+    pub fn start_with(size: usize, configure: impl Fn(DaemonBuilder) -> DaemonBuilder) -> TestCluster {
+        let key_dir = TempDir::new().unwrap();
+        let key = format!("cluster key {}", key_dir.path().display()).into_bytes();
+        std::fs::write(key_dir.path().join("key"), &key).unwrap();
+        let mut cluster = TestCluster { daemons: vec![], dirs: vec![], key_dir, key };
+        for _ in 0..size {
+            cluster.add_with(&configure);
+        }
+        cluster
+    }
+
+    // Starts one more daemon, which joins the cluster through the root unless it is the first
+    pub fn add_with(&mut self, configure: impl FnOnce(DaemonBuilder) -> DaemonBuilder) -> Node {
+        let dir = TempDir::new().unwrap();
+        let mut builder = Daemon::builder()
+            .name(&TestCluster::node_name(self.daemons.len()))
+            .port(0)
+            .listening_addr("127.0.0.1:0")
+            .data_dir(dir.path().to_str().unwrap())
+            .key_file(self.key_file().to_str().unwrap());
+        if let Some(root) = self.daemons.first() {
+            builder = builder.root_addr(&format!("127.0.0.1:{}", root.port()));
+        }
+        self.daemons.push(configure(builder).spawn().unwrap());
+        self.dirs.push(dir);
+        self.node(self.daemons.len() - 1)
+    }
+
+    fn node_name(index: usize) -> String {
+        if index == 0 { "root".to_string() } else { format!("node{index}") }
+    }
+
+    pub fn daemon(&self, index: usize) -> &Daemon {
+        &self.daemons[index]
+    }
+
+    pub fn node(&self, index: usize) -> Node {
+        self.daemons[index].node().clone()
+    }
+
+    pub fn root(&self) -> Node {
+        self.node(0)
+    }
+
+    // Where the daemon keeps its files, for tests that tamper with them
+    pub fn data_dir(&self, index: usize) -> &std::path::Path {
+        self.dirs[index].path()
+    }
+
+    pub fn key_file(&self) -> std::path::PathBuf {
+        self.key_dir.path().join("key")
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    // Connects as the user running the tests, with the cluster key
+    pub fn connect(&self, index: usize) -> VPFS {
+        self.connect_as(index, &current_user())
+    }
+
+    pub fn connect_as(&self, index: usize, user: &str) -> VPFS {
+        VPFS::connect_as(self.daemons[index].port(), user, &self.key).unwrap()
+    }
+}
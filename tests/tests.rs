@@ -1,15 +1,16 @@
+mod common;
+
 use vpfs::*;
 use vpfs::messages::*;
-
-const LOCAL_PORT: u16 = 8080;
-const REMOTE_NAME: &str = "remote";
+use common::TestCluster;
 
 #[test]
 fn find_and_place_root_directory_remote() {
+    let cluster = TestCluster::start(2);
     let file_name = "test0";
-    let root_node = Node {name: REMOTE_NAME.to_string()};
+    let root_node = cluster.root();
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
     let place_ret = vpfs.place(file_name, root_node);
     assert!(place_ret.is_ok());
@@ -20,9 +21,10 @@ fn find_and_place_root_directory_remote() {
 
 #[test]
 fn find_and_place_root_directory_local() {
+    let cluster = TestCluster::start(2);
     let file_name = "test1";
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
     let place_ret = vpfs.place(file_name, vpfs.local.clone());
     assert!(place_ret.is_ok());
@@ -33,39 +35,42 @@ fn find_and_place_root_directory_local() {
 
 #[test]
 fn read_and_write_root_directory_remote() {
+    let cluster = TestCluster::start(2);
     let file_name = "test2";
     let data = "Hello world 2".as_bytes();
-    let root_node = Node {name: REMOTE_NAME.to_string()};
+    let root_node = cluster.root();
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
     let location = vpfs.place(file_name, root_node).unwrap();
 
-    vpfs.write(location.clone(), &data);
+    vpfs.write(location.clone(), data).unwrap();
     assert_eq!(vpfs.read(location).unwrap(), data);
 }
 
 #[test]
 fn read_and_write_root_directory_local() {
+    let cluster = TestCluster::start(2);
     let file_name = "test3";
     let data = "Hello world 3".as_bytes();
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
     let location = vpfs.place(file_name, vpfs.local.clone()).unwrap();
 
-    vpfs.write(location.clone(), &data);
+    vpfs.write(location.clone(), data).unwrap();
     assert_eq!(vpfs.read(location).unwrap(), data);
 }
 
 #[test]
 fn store_root_directory_local() {
+    let cluster = TestCluster::start(2);
     let file_name = "test4";
     let data = "Hello world 4".as_bytes();
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
-    vpfs.store(file_name, data);
+    vpfs.store(file_name, data).unwrap();
     let location = vpfs.find(file_name);
     assert!(location.is_ok());
     assert_eq!(vpfs.read(location.unwrap().location).unwrap(), data);
@@ -73,35 +78,38 @@ fn store_root_directory_local() {
 
 #[test]
 fn fetch_root_directory_local() {
+    let cluster = TestCluster::start(2);
     let file_name = "test5";
     let data = "Hello world 5".as_bytes();
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
-    vpfs.store(file_name, data);
+    vpfs.store(file_name, data).unwrap();
     assert_eq!(vpfs.fetch(file_name).unwrap(), data);
 }
 
 #[test]
 fn fetch_root_directory_remote() {
+    let cluster = TestCluster::start(2);
     let file_name = "test6";
     let data = "Hello world 6".as_bytes();
-    let root_node = Node {name: REMOTE_NAME.to_string()};
+    let root_node = cluster.root();
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
     let location = vpfs.place(file_name, root_node).unwrap();
-    vpfs.write(location, data);
+    vpfs.write(location, data).unwrap();
     assert_eq!(vpfs.fetch(file_name).unwrap(), data);
 }
 
 #[test]
 fn find_and_place_non_root_directory_remote() {
+    let cluster = TestCluster::start(2);
     let dir_name = "dir7";
     let file_name = &format!("{dir_name}/test7");
-    let root_node = Node {name: REMOTE_NAME.to_string()};
+    let root_node = cluster.root();
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
     let mkdir_ret = vpfs.mkdir(dir_name, root_node.clone());
     assert!(mkdir_ret.is_ok());
@@ -114,10 +122,11 @@ fn find_and_place_non_root_directory_remote() {
 
 #[test]
 fn find_and_place_non_root_directory_local() {
+    let cluster = TestCluster::start(2);
     let dir_name = "dir8";
     let file_name = &format!("{dir_name}/test8");
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
     let mkdir_ret = vpfs.mkdir(dir_name, vpfs.local.clone());
     assert!(mkdir_ret.is_ok());
@@ -130,49 +139,52 @@ fn find_and_place_non_root_directory_local() {
 
 #[test]
 fn read_and_write_non_root_directory_remote() {
+    let cluster = TestCluster::start(2);
     let dir_name = "dir9";
     let file_name = &format!("{dir_name}/test9");
     let data = "Hello world 9".as_bytes();
-    let root_node = Node {name: REMOTE_NAME.to_string()};
+    let root_node = cluster.root();
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
     let mkdir_ret = vpfs.mkdir(dir_name, root_node.clone());
     assert!(mkdir_ret.is_ok());
     let location = vpfs.place(file_name, root_node).unwrap();
 
-    vpfs.write(location.clone(), &data);
+    vpfs.write(location.clone(), data).unwrap();
     assert_eq!(vpfs.read(location).unwrap(), data);
 }
 
 #[test]
 fn read_and_write_non_root_directory_local() {
+    let cluster = TestCluster::start(2);
     let dir_name = "dir10";
     let file_name = &format!("{dir_name}/test10");
     let data = "Hello world 10".as_bytes();
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
     let mkdir_ret = vpfs.mkdir(dir_name, vpfs.local.clone());
     assert!(mkdir_ret.is_ok());
     let location = vpfs.place(file_name, vpfs.local.clone()).unwrap();
 
-    vpfs.write(location.clone(), &data);
+    vpfs.write(location.clone(), data).unwrap();
     assert_eq!(vpfs.read(location).unwrap(), data);
 }
 
 #[test]
 fn store_non_root_directory_remote() {
+    let cluster = TestCluster::start(2);
     let dir_name = "dir11";
     let file_name = &format!("{dir_name}/test11");
     let data = "Hello world 11".as_bytes();
-    let root_node = Node {name: REMOTE_NAME.to_string()};
+    let root_node = cluster.root();
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
     let mkdir_ret = vpfs.mkdir(dir_name, root_node);
     assert!(mkdir_ret.is_ok());
-    vpfs.store(file_name, data);
+    vpfs.store(file_name, data).unwrap();
     let location = vpfs.find(file_name);
     assert!(location.is_ok());
     assert_eq!(vpfs.read(location.unwrap().location).unwrap(), data);
@@ -180,37 +192,40 @@ fn store_non_root_directory_remote() {
 
 #[test]
 fn fetch_non_root_directory_local() {
+    let cluster = TestCluster::start(2);
     let dir_name = "dir12";
     let file_name = &format!("{dir_name}/test12");
     let data = "Hello world 12".as_bytes();
-    let root_node = Node {name: REMOTE_NAME.to_string()};
+    let root_node = cluster.root();
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
     let mkdir_ret = vpfs.mkdir(dir_name, root_node);
     assert!(mkdir_ret.is_ok());
-    vpfs.store(file_name, data);
+    vpfs.store(file_name, data).unwrap();
     assert_eq!(vpfs.fetch(file_name).unwrap(), data);
 }
 
 #[test]
 fn fetch_non_root_directory_remote() {
+    let cluster = TestCluster::start(2);
     let dir_name = "dir13";
     let file_name = &format!("{dir_name}/test13");
     let data = "Hello world 13".as_bytes();
-    let root_node = Node {name: REMOTE_NAME.to_string()};
+    let root_node = cluster.root();
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
     let mkdir_ret = vpfs.mkdir(dir_name, root_node.clone());
     assert!(mkdir_ret.is_ok());
     let location = vpfs.place(file_name, root_node).unwrap();
-    vpfs.write(location, data);
+    vpfs.write(location, data).unwrap();
     assert_eq!(vpfs.fetch(file_name).unwrap(), data);
 }
 
 #[test]
 fn multiple_nested_directories(){
+    let cluster = TestCluster::start(2);
     let dir_name1 = "dir14";
     let dir_name2 = &format!("{dir_name1}/dir14");
     let dir_name3 = &format!("{dir_name2}/dir14");
@@ -218,9 +233,9 @@ fn multiple_nested_directories(){
     let file_name2 = &format!("{dir_name3}/test14");
     let file_data1 = "First file data".as_bytes();
     let file_data2 = "Second file data".as_bytes();
-    let root_node = Node {name: REMOTE_NAME.to_string()};
+    let root_node = cluster.root();
 
-    let vpfs = VPFS::connect(LOCAL_PORT).unwrap();
+    let vpfs = cluster.connect(1);
 
     let mkdir_ret = vpfs.mkdir(dir_name1, vpfs.local.clone());
     assert!(mkdir_ret.is_ok());
@@ -229,10 +244,1036 @@ fn multiple_nested_directories(){
     let mkdir_ret = vpfs.mkdir(dir_name3, vpfs.local.clone());
     assert!(mkdir_ret.is_ok());
 
-    vpfs.store(&file_name1, file_data1);
+    vpfs.store(file_name1, file_data1).unwrap();
     assert_eq!(vpfs.fetch(file_name1).unwrap(), file_data1);
 
-    let location = vpfs.place(&file_name2, root_node).unwrap();
-    vpfs.write(location.clone(), file_data2);
+    let location = vpfs.place(file_name2, root_node).unwrap();
+    vpfs.write(location.clone(), file_data2).unwrap();
     assert_eq!(vpfs.read(location).unwrap(), file_data2);
-}
\ No newline at end of file
+}
+#[test]
+fn permissions_of_other_users(){
+    let cluster = TestCluster::start(2);
+    let dir_name = "dir15";
+    let file_name = &format!("{dir_name}/test15");
+    let data = "Owner data".as_bytes();
+    let root_node = cluster.root();
+
+    let owner = cluster.connect_as(1, "owner15");
+    let other = cluster.connect_as(1, "other15");
+
+    owner.mkdir(dir_name, root_node.clone()).unwrap();
+    let location = owner.place(file_name, root_node).unwrap();
+    owner.write(location.clone(), data).unwrap();
+
+    assert_eq!(other.read(location.clone()).unwrap(), data);
+    assert_eq!(other.write(location.clone(), "Other data".as_bytes()), Err(VPFSError::PermissionDenied));
+    assert_eq!(other.place(&format!("{dir_name}/other15"), other.local.clone()), Err(VPFSError::PermissionDenied));
+
+    let private_location = owner.place_with_mode(&format!("{dir_name}/private15"), owner.local.clone(), 0o600).unwrap();
+    assert_eq!(other.read(private_location), Err(VPFSError::PermissionDenied));
+}
+
+#[test]
+fn df_reports_every_node(){
+    let cluster = TestCluster::start(2);
+    let vpfs = cluster.connect(1);
+
+    let capacities = vpfs.df();
+    for node in [vpfs.local.clone(), cluster.root()] {
+        let node_capacity = capacities.iter().find(|node_capacity| node_capacity.node == node).unwrap();
+        assert!(node_capacity.capacity.is_some());
+    }
+}
+
+#[test]
+fn automatic_placement_rules(){
+    let cluster = TestCluster::start(2);
+    let dir_name = "dir16";
+    let sub_dir_name = &format!("{dir_name}/sub16");
+    let root_node = cluster.root();
+
+    let vpfs = cluster.connect(1);
+
+    vpfs.mkdir(dir_name, root_node.clone()).unwrap();
+    vpfs.mkdir(sub_dir_name, root_node.clone()).unwrap();
+    let rules = vec![PlacementRule {prefix: sub_dir_name.clone(), node: vpfs.local.clone()}];
+    assert!(vpfs.set_placement_policy(dir_name, Some(PlacementPolicy::Rules(rules))).is_ok());
+
+    let location = vpfs.place(&format!("{dir_name}/test16"), Placement::Auto).unwrap();
+    assert_eq!(location.node, root_node);
+    let location = vpfs.place(&format!("{sub_dir_name}/test16"), Placement::Auto).unwrap();
+    assert_eq!(location.node, vpfs.local);
+}
+
+#[test]
+fn incompatible_protocol_version_is_rejected(){
+    let cluster = TestCluster::start(2);
+    let mut stream = std::net::TcpStream::connect(format!("localhost:{}", cluster.daemon(1).port())).unwrap();
+    let protocol = Protocol {
+        min_version: PROTOCOL_VERSION + 1,
+        max_version: PROTOCOL_VERSION + 1,
+        capabilities: vec![],
+    };
+    serde_bare::to_writer(&mut stream, &Hello::ClientHello(protocol, "test17".to_string())).unwrap();
+    let hello_response: HelloResponse = serde_bare::from_reader(&mut stream).unwrap();
+    assert!(matches!(hello_response, HelloResponse::Rejected(_)));
+}
+
+#[test]
+fn bad_frames_get_error_responses(){
+    let cluster = TestCluster::start(2);
+    let mut stream = stream::Connection::new(std::net::TcpStream::connect(format!("localhost:{}", cluster.daemon(1).port())).unwrap());
+    serde_bare::to_writer(&mut stream, &Hello::ClientHello(Protocol::current(), NOBODY.to_string())).unwrap();
+    let hello_response: HelloResponse = serde_bare::from_reader(&mut stream).unwrap();
+    assert!(matches!(hello_response, HelloResponse::ClientHello(_, _)));
+
+    let request = serde_bare::to_vec(&ClientRequest::Find("/".to_string())).unwrap();
+    let mut corrupt_frame = vec![];
+    frame::write_frame(&mut corrupt_frame, frame::FrameKind::Message, &request).unwrap();
+    *corrupt_frame.last_mut().unwrap() ^= 1;
+    std::io::Write::write_all(&mut stream, &corrupt_frame).unwrap();
+    let response: ClientResponse = frame::receive_framed(&mut stream).unwrap();
+    assert!(matches!(response, ClientResponse::Error(VPFSError::BadFrame(_))));
+
+    // Garbage in front of a request gets its own error, and the request is still answered
+    std::io::Write::write_all(&mut stream, b"not a frame at all").unwrap();
+    frame::send_framed(&mut stream, &ClientRequest::Find("/".to_string())).unwrap();
+    let response: ClientResponse = frame::receive_framed(&mut stream).unwrap();
+    assert!(matches!(response, ClientResponse::Error(VPFSError::BadFrame(_))));
+    let response: ClientResponse = frame::receive_framed(&mut stream).unwrap();
+    assert!(matches!(response, ClientResponse::Find(_)));
+}
+
+#[test]
+fn a_request_with_a_broken_header_is_answered_straight_away(){
+    let cluster = TestCluster::start(2);
+    let tcp_stream = std::net::TcpStream::connect(format!("localhost:{}", cluster.daemon(1).port())).unwrap();
+    tcp_stream.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+    let mut stream = stream::Connection::new(tcp_stream);
+    serde_bare::to_writer(&mut stream, &Hello::ClientHello(Protocol::current(), NOBODY.to_string())).unwrap();
+    let hello_response: HelloResponse = serde_bare::from_reader(&mut stream).unwrap();
+    assert!(matches!(hello_response, HelloResponse::ClientHello(_, _)));
+
+    // Nothing follows the broken frame, so the error can not wait for the next one
+    let request = serde_bare::to_vec(&ClientRequest::Find("test52".to_string())).unwrap();
+    let mut corrupt_frame = vec![];
+    frame::write_frame(&mut corrupt_frame, frame::FrameKind::Message, &request).unwrap();
+    corrupt_frame[5] ^= 1;
+    std::io::Write::write_all(&mut stream, &corrupt_frame).unwrap();
+    let response: ClientResponse = frame::receive_framed(&mut stream).unwrap();
+    assert!(matches!(response, ClientResponse::Error(VPFSError::BadFrame(_))));
+
+    frame::send_framed(&mut stream, &ClientRequest::Find("test52".to_string())).unwrap();
+    let response: ClientResponse = frame::receive_framed(&mut stream).unwrap();
+    assert!(matches!(response, ClientResponse::Find(Err(VPFSError::DoesNotExist))));
+}
+
+#[test]
+fn large_files_are_compressed_in_transit(){
+    let cluster = TestCluster::start(2);
+    let file_name = "test19";
+    let data = "Hello world 19, this line compresses well\n".repeat(50_000);
+    let root_node = cluster.root();
+
+    let vpfs = cluster.connect(1);
+    assert!(vpfs.protocol.supports(COMPRESSION));
+
+    let location = vpfs.place(file_name, root_node).unwrap();
+    vpfs.write(location.clone(), data.as_bytes()).unwrap();
+    assert_eq!(vpfs.read(location).unwrap(), data.as_bytes());
+}
+
+#[test]
+fn chunked_files_between_three_nodes(){
+    let cluster = TestCluster::start_with(3, |builder| builder.chunked());
+    let file_name = "test20";
+    let mut data = "Hello world 20, chunk after chunk\n".repeat(20_000).into_bytes();
+
+    let writer = cluster.connect(1);
+    let reader = cluster.connect(0);
+
+    let location = writer.place(file_name, cluster.node(2)).unwrap();
+    writer.write(location.clone(), &data).unwrap();
+    assert_eq!(reader.read(location.clone()).unwrap(), data);
+
+    data[300_000] ^= 1;
+    writer.write(location.clone(), &data).unwrap();
+    assert_eq!(reader.read(location).unwrap(), data);
+}
+
+#[test]
+fn encrypted_links_between_daemons(){
+    let cluster = TestCluster::start_with(2, |builder| builder.encrypt());
+    let file_name = "test21";
+    let data = "Hello world 21".as_bytes();
+
+    let vpfs = cluster.connect(1);
+
+    let location = vpfs.place(file_name, cluster.root()).unwrap();
+    vpfs.write(location.clone(), data).unwrap();
+    assert_eq!(vpfs.read(location).unwrap(), data);
+}
+
+fn partition_from(node: Node) -> FaultPlan {
+    FaultPlan { rules: vec![FaultRule { peer: Some(node), request: None, fault: Fault::Partition }], fail_writes: false }
+}
+
+#[test]
+fn partitioned_owner_is_not_accessible(){
+    let cluster = TestCluster::start(3);
+    let file_name = "test22";
+    let data = "Hello world 22".as_bytes();
+
+    let vpfs = cluster.connect(1);
+
+    let location = vpfs.place(file_name, cluster.node(2)).unwrap();
+    vpfs.inject_faults(partition_from(cluster.node(2))).unwrap();
+    assert_eq!(vpfs.write(location.clone(), data), Err(VPFSError::NotAccessible));
+    assert_eq!(vpfs.read(location.clone()), Err(VPFSError::NotAccessible));
+
+    vpfs.inject_faults(FaultPlan::default()).unwrap();
+    vpfs.write(location.clone(), data).unwrap();
+    assert_eq!(vpfs.read(location).unwrap(), data);
+}
+
+#[test]
+fn partitioned_owner_falls_back_to_cache(){
+    let cluster = TestCluster::start(3);
+    let dir_name = "dir23";
+    let file_name = &format!("{dir_name}/test23");
+    let data = "Hello world 23".as_bytes();
+
+    let vpfs = cluster.connect(1);
+
+    vpfs.mkdir(dir_name, cluster.node(2)).unwrap();
+    let location = vpfs.place(file_name, cluster.node(2)).unwrap();
+    vpfs.write(location.clone(), data).unwrap();
+    assert_eq!(vpfs.fetch(file_name).unwrap(), data);
+
+    vpfs.inject_faults(partition_from(cluster.node(2))).unwrap();
+    let Err(VPFSError::CacheNeededForTraversal(entry)) = vpfs.find(file_name) else {
+        panic!("Expected the cached directory to be needed");
+    };
+    assert_eq!(entry.location, location);
+    let Err(VPFSError::OnlyInCache(cache_location)) = vpfs.read(location) else {
+        panic!("Expected the file to only be in the cache");
+    };
+    assert_eq!(cache_location.node, vpfs.local);
+    assert_eq!(vpfs.read(cache_location).unwrap(), data);
+}
+
+#[test]
+fn faults_apply_to_matching_requests(){
+    let cluster = TestCluster::start(3);
+    let file_name = "test24";
+    let data = "Hello world 24".as_bytes();
+
+    let vpfs = cluster.connect(1);
+
+    let location = vpfs.place(file_name, cluster.node(2)).unwrap();
+    let rules = vec![
+        FaultRule { peer: Some(cluster.node(2)), request: Some("Read".to_string()), fault: Fault::Disconnect },
+        FaultRule { peer: Some(cluster.node(2)), request: Some("Write".to_string()), fault: Fault::Delay(200, 200) },
+    ];
+    vpfs.inject_faults(FaultPlan { rules, fail_writes: false }).unwrap();
+    let start = std::time::Instant::now();
+    vpfs.write(location.clone(), data).unwrap();
+    assert!(start.elapsed() >= std::time::Duration::from_millis(200));
+    assert_eq!(vpfs.read(location), Err(VPFSError::NotAccessible));
+
+    let unknown_request = FaultRule { peer: None, request: Some("Teleport".to_string()), fault: Fault::Disconnect };
+    assert!(vpfs.inject_faults(FaultPlan { rules: vec![unknown_request], fail_writes: false }).is_err());
+}
+
+#[test]
+fn failed_disk_writes_are_reported(){
+    let cluster = TestCluster::start(2);
+    let file_name = "test25";
+    let data = "Hello world 25".as_bytes();
+
+    let vpfs = cluster.connect(1);
+    let owner = cluster.connect(0);
+
+    let location = vpfs.place(file_name, cluster.root()).unwrap();
+    owner.inject_faults(FaultPlan { rules: vec![], fail_writes: true }).unwrap();
+    assert!(matches!(vpfs.write(location.clone(), data), Err(VPFSError::Other(_))));
+    assert!(vpfs.place("test25b", cluster.root()).is_err());
+
+    owner.inject_faults(FaultPlan::default()).unwrap();
+    vpfs.write(location.clone(), data).unwrap();
+    assert_eq!(vpfs.read(location).unwrap(), data);
+}
+
+#[test]
+fn simulated_clusters_keep_invariants(){
+    for seed in 0..8 {
+        let config = vpfs::sim::SimConfig { seed, ..Default::default() };
+        if let Err(failure) = vpfs::sim::run(&config) {
+            panic!("{failure}");
+        }
+    }
+}
+
+#[test]
+fn simulation_is_deterministic(){
+    let config = vpfs::sim::SimConfig { seed: 42, ..Default::default() };
+    let first = vpfs::sim::run(&config).unwrap();
+    let second = vpfs::sim::run(&config).unwrap();
+    assert_eq!(first.log, second.log);
+}
+
+#[test]
+fn shutdown_tells_peers_the_node_is_leaving(){
+    // Heartbeats are too rare to notice the node is gone, only its leaving message can tell
+    let cluster = TestCluster::start_with(3, |builder| builder.heartbeat_interval(std::time::Duration::from_secs(60)));
+    let file_name = "test26";
+    let data = "Hello world 26".as_bytes();
+
+    let vpfs = cluster.connect(1);
+    let location = vpfs.place(file_name, cluster.node(2)).unwrap();
+    vpfs.write(location.clone(), data).unwrap();
+
+    cluster.connect(2).shutdown_daemon().unwrap();
+    let start = std::time::Instant::now();
+    while vpfs.nodes().iter().any(|status| status.node == cluster.node(2) && status.reachable) {
+        assert!(start.elapsed() < std::time::Duration::from_secs(5), "node2 was not reported as down");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert_eq!(vpfs.read(location), Err(VPFSError::NotAccessible));
+}
+
+#[test]
+fn connections_beyond_the_limit_are_turned_away(){
+    let cluster = TestCluster::start_with(1, |builder| builder.max_connections(1).connection_queue(0));
+    let port = cluster.daemon(0).port();
+
+    let first = cluster.connect(0);
+    let busy = VPFS::connect(port).err().unwrap();
+    assert_eq!(busy.kind(), std::io::ErrorKind::ResourceBusy);
+
+    // The connection's thread is free again once the daemon notices it closed
+    drop(first);
+    let start = std::time::Instant::now();
+    let second = loop {
+        match VPFS::connect(port) {
+            Ok(vpfs) => break vpfs,
+            Err(error) => assert!(error.kind() == std::io::ErrorKind::ResourceBusy && start.elapsed() < std::time::Duration::from_secs(5)),
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    };
+    assert_eq!(second.nodes().len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_runs_requests_concurrently(){
+    let cluster = TestCluster::start(2);
+    let client = std::sync::Arc::new(vpfs::r#async::Client::connect_as_with(cluster.daemon(1).port(), "test", cluster.key(), 2).await.unwrap());
+
+    let mut tasks = vec![];
+    for index in 0..16 {
+        let (client, at) = (client.clone(), cluster.node(index % 2));
+        tasks.push(tokio::spawn(async move {
+            let location = client.place(&format!("test27-{index}"), at).await?;
+            client.write(location.clone(), format!("Hello world 27 {index}").as_bytes()).await?;
+            Ok::<_, VPFSError>(location)
+        }));
+    }
+    let mut locations = vec![];
+    for task in tasks {
+        locations.push(task.await.unwrap().unwrap());
+    }
+
+    let reads: Vec<_> = locations.into_iter().map(|location| {
+        let client = client.clone();
+        tokio::spawn(async move { client.read(location).await })
+    }).collect();
+    for (index, read) in reads.into_iter().enumerate() {
+        assert_eq!(read.await.unwrap().unwrap(), format!("Hello world 27 {index}").as_bytes());
+    }
+    assert_eq!(client.fetch("test27-3").await.unwrap(), "Hello world 27 3".as_bytes());
+    assert!(client.find("test27-missing").await.is_err());
+}
+
+// Forwards every connection to the port, and keeps the client side of each so a test can cut it
+fn start_proxy(port: u16) -> (u16, std::sync::Arc<std::sync::Mutex<Vec<std::net::TcpStream>>>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_port = listener.local_addr().unwrap().port();
+    let accepted = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let connections = accepted.clone();
+    std::thread::spawn(move || {
+        for client in listener.incoming() {
+            let client = client.unwrap();
+            let daemon = std::net::TcpStream::connect(format!("localhost:{port}")).unwrap();
+            let (mut client_reader, mut daemon_writer) = (client.try_clone().unwrap(), daemon.try_clone().unwrap());
+            let (mut daemon_reader, mut client_writer) = (daemon, client.try_clone().unwrap());
+            std::thread::spawn(move || std::io::copy(&mut client_reader, &mut daemon_writer));
+            std::thread::spawn(move || std::io::copy(&mut daemon_reader, &mut client_writer));
+            connections.lock().unwrap().push(client);
+        }
+    });
+    (proxy_port, accepted)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_replaces_lost_connections(){
+    let cluster = TestCluster::start(2);
+    let (proxy_port, connections) = start_proxy(cluster.daemon(1).port());
+    let client = vpfs::r#async::Client::connect_as_with(proxy_port, "test", cluster.key(), 2).await.unwrap();
+    let location = client.place("test53", cluster.node(1)).await.unwrap();
+
+    connections.lock().unwrap()[0].shutdown(std::net::Shutdown::Both).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    for index in 0..8 {
+        client.write(location.clone(), format!("Hello world 53 {index}").as_bytes()).await.unwrap();
+        assert_eq!(client.read(location.clone()).await.unwrap(), format!("Hello world 53 {index}").as_bytes());
+    }
+    assert_eq!(connections.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn failed_mkdir_leaves_no_directory_behind(){
+    let cluster = TestCluster::start(3);
+    let directory = "test28";
+
+    let vpfs = cluster.connect(1);
+    cluster.connect(2).inject_faults(FaultPlan { rules: vec![], fail_writes: true }).unwrap();
+    assert!(vpfs.mkdir(directory, cluster.node(2)).is_err());
+    assert!(vpfs.find(directory).is_err());
+
+    cluster.connect(2).inject_faults(FaultPlan::default()).unwrap();
+    vpfs.mkdir(directory, cluster.node(2)).unwrap();
+    vpfs.place(&format!("{directory}/file"), cluster.node(1)).unwrap();
+}
+
+#[test]
+fn nodes_reading_each_others_files_do_not_deadlock(){
+    let cluster = TestCluster::start(3);
+    let data = "Hello world 29".as_bytes();
+
+    let mut readers = vec![];
+    for (index, owner) in [(1, 2), (2, 1)] {
+        let vpfs = cluster.connect(owner);
+        let location = vpfs.place(&format!("test29-{owner}"), cluster.node(owner)).unwrap();
+        vpfs.write(location.clone(), data).unwrap();
+        let vpfs = cluster.connect(index);
+        readers.push(std::thread::spawn(move || {
+            for _ in 0..200 {
+                assert_eq!(vpfs.read(location.clone()).unwrap(), data);
+            }
+        }));
+    }
+
+    let (done, finished) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for reader in readers {
+            let _ = done.send(reader.join().is_ok());
+        }
+    });
+    for _ in 0..2 {
+        assert!(finished.recv_timeout(std::time::Duration::from_secs(30)).expect("reads deadlocked"));
+    }
+}
+
+#[test]
+fn connecting_to_an_unresponsive_node_does_not_hold_up_other_requests(){
+    // Accepts connections but never answers them, so saying hello to it takes the whole connect timeout
+    let unresponsive = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let unresponsive_addr = unresponsive.local_addr().unwrap().to_string();
+    std::thread::spawn(move || unresponsive.incoming().collect::<Vec<_>>());
+
+    let mut cluster = TestCluster::start_with(2, |builder| builder.connect_timeout(std::time::Duration::from_secs(5)));
+    let stuck = cluster.add_with(|builder| builder.listening_addr(&unresponsive_addr));
+
+    let vpfs = cluster.connect(1);
+    let stuck_place = std::thread::spawn(move || vpfs.place("test30-stuck", stuck));
+    std::thread::sleep(std::time::Duration::from_millis(200));
+
+    let start = std::time::Instant::now();
+    let vpfs = cluster.connect(1);
+    let location = vpfs.place("test30", cluster.root()).unwrap();
+    vpfs.write(location.clone(), "Hello world 30".as_bytes()).unwrap();
+    assert_eq!(vpfs.read(location).unwrap(), "Hello world 30".as_bytes());
+    assert!(start.elapsed() < std::time::Duration::from_secs(2), "requests waited for the connection to the unresponsive node");
+    assert!(stuck_place.join().unwrap().is_err());
+}
+
+#[test]
+fn daemons_can_connect_while_user_processes_fill_the_limit(){
+    let mut cluster = TestCluster::start_with(1, |builder| builder.max_connections(1).connection_queue(0));
+    let port = cluster.daemon(0).port();
+
+    let _first = cluster.connect(0);
+    assert_eq!(VPFS::connect(port).err().unwrap().kind(), std::io::ErrorKind::ResourceBusy);
+
+    let node = cluster.add_with(|builder| builder);
+    let vpfs = cluster.connect(1);
+    let location = vpfs.place("test31", cluster.root()).unwrap();
+    vpfs.write(location.clone(), "Hello world 31".as_bytes()).unwrap();
+    assert_eq!(vpfs.read(location).unwrap(), "Hello world 31".as_bytes());
+    assert_eq!(vpfs.local, node);
+}
+
+#[test]
+fn user_processes_without_the_cluster_key_act_as_nobody(){
+    let cluster = TestCluster::start(2);
+    let port = cluster.daemon(1).port();
+    let data = "Hello world 32".as_bytes();
+
+    let owner = cluster.connect_as(1, "test32");
+    let location = owner.place("test32", cluster.root()).unwrap();
+    owner.write(location.clone(), data).unwrap();
+
+    let anonymous = VPFS::connect(port).unwrap();
+    assert_eq!(anonymous.user, NOBODY);
+    assert_eq!(anonymous.read(location.clone()).unwrap(), data);
+    assert_eq!(anonymous.write(location, data), Err(VPFSError::PermissionDenied));
+
+    let mut stream = std::net::TcpStream::connect(format!("localhost:{port}")).unwrap();
+    serde_bare::to_writer(&mut stream, &Hello::ClientHello(Protocol::current(), "test32".to_string())).unwrap();
+    let hello_response: HelloResponse = serde_bare::from_reader(&mut stream).unwrap();
+    assert!(matches!(hello_response, HelloResponse::Rejected(_)));
+
+    let wrong_key = VPFS::connect_as(port, "test32", b"not the cluster key").err().unwrap();
+    assert_eq!(wrong_key.kind(), std::io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn local_processes_act_as_the_user_running_them(){
+    let socket_dir = tempfile::TempDir::new().unwrap();
+    let socket_path = socket_dir.path().join("socket");
+    let cluster = TestCluster::start_with(1, |builder| builder.socket(socket_path.to_str().unwrap()));
+
+    let vpfs = VPFS::connect_unix(&socket_path).unwrap();
+    assert_eq!(vpfs.user, current_user());
+    vpfs.place("test33", cluster.root()).unwrap();
+    assert_eq!(vpfs.find("test33").unwrap().permissions.owner, current_user());
+
+    let mut stream = std::os::unix::net::UnixStream::connect(&socket_path).unwrap();
+    serde_bare::to_writer(&mut stream, &Hello::ClientHello(Protocol::current(), format!("not-{}", current_user()))).unwrap();
+    let hello_response: HelloResponse = serde_bare::from_reader(&mut stream).unwrap();
+    assert!(matches!(hello_response, HelloResponse::Rejected(_)));
+}
+
+#[test]
+fn admin_requests_need_the_cluster_key_or_the_daemons_user(){
+    let socket_dir = tempfile::TempDir::new().unwrap();
+    let socket_path = socket_dir.path().join("socket");
+    let mut cluster = TestCluster::start_with(1, |builder| builder.socket(socket_path.to_str().unwrap()));
+    cluster.add_with(|builder| builder);
+
+    let anonymous = VPFS::connect(cluster.daemon(1).port()).unwrap();
+    assert_eq!(anonymous.promote_to_root(), Err(VPFSError::PermissionDenied));
+    assert_eq!(anonymous.drain(cluster.node(1)), Err(VPFSError::PermissionDenied));
+    assert_eq!(anonymous.rebalance(), Err(VPFSError::PermissionDenied));
+    assert_eq!(anonymous.nodes().len(), 2);
+
+    assert_eq!(cluster.connect(0).rebalance(), Ok(0));
+    assert_eq!(VPFS::connect_unix(&socket_path).unwrap().rebalance(), Ok(0));
+}
+
+#[test]
+fn delayed_requests_do_not_hold_up_others_to_the_same_peer(){
+    let cluster = TestCluster::start(3);
+    let data = "Hello world 37".as_bytes();
+
+    let vpfs = cluster.connect(1);
+    let delayed = vpfs.place("test37a", cluster.node(2)).unwrap();
+    let location = vpfs.place("test37b", cluster.node(2)).unwrap();
+    vpfs.write(location.clone(), data).unwrap();
+    let rules = vec![FaultRule { peer: Some(cluster.node(2)), request: Some("Write".to_string()), fault: Fault::Delay(1000, 1000) }];
+    vpfs.inject_faults(FaultPlan { rules, fail_writes: false }).unwrap();
+
+    let writer = cluster.connect(1);
+    let write = std::thread::spawn(move || writer.write(delayed, "Delayed".as_bytes()));
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    let start = std::time::Instant::now();
+    assert_eq!(vpfs.read(location).unwrap(), data);
+    assert!(start.elapsed() < std::time::Duration::from_millis(500));
+    write.join().unwrap().unwrap();
+
+    let anonymous = VPFS::connect(cluster.daemon(1).port()).unwrap();
+    assert_eq!(anonymous.inject_faults(FaultPlan::default()), Err(VPFSError::PermissionDenied));
+}
+
+#[test]
+fn only_administrators_can_shut_a_daemon_down(){
+    let cluster = TestCluster::start(2);
+    let anonymous = VPFS::connect(cluster.daemon(1).port()).unwrap();
+    assert_eq!(anonymous.shutdown_daemon(), Err(VPFSError::PermissionDenied));
+    assert_eq!(anonymous.nodes().len(), 2);
+    assert!(VPFS::connect(cluster.daemon(1).port()).is_ok());
+
+    cluster.connect(1).shutdown_daemon().unwrap();
+    let start = std::time::Instant::now();
+    while VPFS::connect(cluster.daemon(1).port()).is_ok() {
+        assert!(start.elapsed() < std::time::Duration::from_secs(15), "node1 did not shut down");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn pipelined_requests_after_a_corrupted_frame_are_answered(){
+    let cluster = TestCluster::start(2);
+    cluster.connect(1).place("test38", cluster.root()).unwrap();
+    let mut stream = stream::Connection::new(std::net::TcpStream::connect(format!("localhost:{}", cluster.daemon(1).port())).unwrap());
+    serde_bare::to_writer(&mut stream, &Hello::ClientHello(Protocol::current(), NOBODY.to_string())).unwrap();
+    let hello_response: HelloResponse = serde_bare::from_reader(&mut stream).unwrap();
+    assert!(matches!(hello_response, HelloResponse::ClientHello(_, _)));
+
+    // Requests sent back to back, with a broken header in the first and a broken payload in the third
+    let find = |path: &str| {
+        let mut frame = vec![];
+        frame::send_framed(&mut frame, &ClientRequest::Find(path.to_string())).unwrap();
+        frame
+    };
+    let mut requests = find("test38");
+    requests[4] ^= 1;
+    requests.extend(find("test38"));
+    let mut corrupt_payload = find("test38");
+    *corrupt_payload.last_mut().unwrap() ^= 1;
+    requests.extend(corrupt_payload);
+    requests.extend(find("missing"));
+    std::io::Write::write_all(&mut stream, &requests).unwrap();
+
+    let response: ClientResponse = frame::receive_framed(&mut stream).unwrap();
+    assert!(matches!(response, ClientResponse::Error(VPFSError::BadFrame(_))));
+    let response: ClientResponse = frame::receive_framed(&mut stream).unwrap();
+    assert!(matches!(response, ClientResponse::Find(Ok(_))));
+    let response: ClientResponse = frame::receive_framed(&mut stream).unwrap();
+    assert!(matches!(response, ClientResponse::Error(VPFSError::BadFrame(_))));
+    let response: ClientResponse = frame::receive_framed(&mut stream).unwrap();
+    assert!(matches!(response, ClientResponse::Find(Err(_))));
+}
+
+#[test]
+fn corrupted_files_and_directories_are_reported(){
+    let cluster = TestCluster::start(2);
+    let vpfs = cluster.connect(1);
+    let corrupt = |location: &Location| {
+        let path = cluster.data_dir(1).join(&location.uri);
+        let mut data = std::fs::read(&path).unwrap();
+        match data.last_mut() {
+            Some(byte) => *byte ^= 1,
+            None => data.push(0),
+        }
+        std::fs::write(&path, data).unwrap();
+    };
+
+    let file = vpfs.place("test39", cluster.node(1)).unwrap();
+    vpfs.write(file.clone(), "Hello world 39".as_bytes()).unwrap();
+    corrupt(&file);
+    assert_eq!(vpfs.read(file), Err(VPFSError::Corrupted));
+
+    let never_written = vpfs.place("test39-empty", cluster.node(1)).unwrap();
+    corrupt(&never_written);
+    assert_eq!(vpfs.read(never_written), Err(VPFSError::Corrupted));
+
+    let directory = vpfs.mkdir("test39-dir", cluster.node(1)).unwrap();
+    vpfs.place("test39-dir/file", cluster.node(1)).unwrap();
+    assert!(vpfs.read(directory.clone()).is_ok());
+    corrupt(&directory);
+    assert_eq!(vpfs.read(directory), Err(VPFSError::Corrupted));
+}
+
+#[test]
+fn metadata_changes_survive_a_restart(){
+    let data_dir = tempfile::TempDir::new().unwrap();
+    let key_dir = tempfile::TempDir::new().unwrap();
+    let key_file = key_dir.path().join("key");
+    std::fs::write(&key_file, "cluster key 40").unwrap();
+    let start = || vpfs::daemon::Daemon::builder()
+        .name("root")
+        .port(0)
+        .listening_addr("127.0.0.1:0")
+        .data_dir(data_dir.path().to_str().unwrap())
+        .key_file(key_file.to_str().unwrap())
+        .spawn()
+        .unwrap();
+    let data = "Hello world 40".as_bytes();
+
+    let daemon = start();
+    let owner = VPFS::connect_as(daemon.port(), "test40", b"cluster key 40").unwrap();
+    let location = owner.place("test40", Placement::Auto).unwrap();
+    owner.write(location.clone(), data).unwrap();
+    drop(owner);
+    daemon.shutdown();
+
+    let daemon = start();
+    let other = VPFS::connect_as(daemon.port(), "not-test40", b"cluster key 40").unwrap();
+    assert_eq!(other.write(location.clone(), data), Err(VPFSError::PermissionDenied));
+    assert_eq!(other.read(location).unwrap(), data);
+    assert!(data_dir.path().join("permissions").exists());
+    assert!(!data_dir.path().join("permissions.journal").exists());
+}
+
+#[test]
+fn saved_switches_can_be_turned_off(){
+    let cluster = TestCluster::start(1);
+    let data_dir = tempfile::TempDir::new().unwrap();
+    let start = |configure: fn(vpfs::daemon::DaemonBuilder) -> vpfs::daemon::DaemonBuilder| configure(vpfs::daemon::Daemon::builder()
+        .name("node41")
+        .port(0)
+        .listening_addr("127.0.0.1:0")
+        .root_addr(&format!("127.0.0.1:{}", cluster.daemon(0).port()))
+        .data_dir(data_dir.path().to_str().unwrap())
+        .key_file(cluster.key_file().to_str().unwrap()))
+        .spawn()
+        .unwrap();
+    let stored_len = |location: &Location| std::fs::metadata(data_dir.path().join(&location.uri)).unwrap().len();
+    let data = "Hello world 41".as_bytes();
+
+    let daemon = start(|builder| builder.standby().chunked());
+    let vpfs = VPFS::connect_as(daemon.port(), &current_user(), cluster.key()).unwrap();
+    let chunked = vpfs.place("test41a", vpfs.local.clone()).unwrap();
+    vpfs.write(chunked.clone(), data).unwrap();
+    assert_eq!(stored_len(&chunked), 0);
+    drop(vpfs);
+    daemon.shutdown();
+
+    let daemon = start(|builder| builder.no_standby().no_chunked());
+    let vpfs = VPFS::connect_as(daemon.port(), &current_user(), cluster.key()).unwrap();
+    let whole = vpfs.place("test41b", vpfs.local.clone()).unwrap();
+    vpfs.write(whole.clone(), data).unwrap();
+    assert_eq!(stored_len(&whole), data.len() as u64);
+    assert_eq!(vpfs.read(chunked).unwrap(), data);
+    assert!(vpfs.promote_to_root().is_err());
+    drop(vpfs);
+    daemon.shutdown();
+
+    // Switched off in the state file too, not just for the run that was told to
+    let daemon = start(|builder| builder);
+    let vpfs = VPFS::connect_as(daemon.port(), &current_user(), cluster.key()).unwrap();
+    let still_whole = vpfs.place("test41c", vpfs.local.clone()).unwrap();
+    vpfs.write(still_whole.clone(), data).unwrap();
+    assert_eq!(stored_len(&still_whole), data.len() as u64);
+    assert!(vpfs.promote_to_root().is_err());
+}
+
+#[test]
+fn config_file_options_can_be_overridden_on_the_command_line(){
+    let config_dir = tempfile::TempDir::new().unwrap();
+    std::fs::write(config_dir.path().join("key"), "cluster key 42").unwrap();
+    let config_file = config_dir.path().join("config.toml");
+    std::fs::write(&config_file, r#"
+name = "root"
+port = 0
+listening-addr = "127.0.0.1:0"
+data-dir = "data"
+key-file = "key"
+socket = "socket"
+no-tcp-clients = true
+quota = 1000
+"#).unwrap();
+    let config = config_file.to_str().unwrap();
+    let data = vec![42u8; 2000];
+
+    let daemon = daemon::Daemon::builder().options(daemon::Opt::from_command_line(["daemon", "--config", config]).unwrap()).spawn().unwrap();
+    assert!(VPFS::connect(daemon.port()).is_err());
+    let vpfs = VPFS::connect_unix(config_dir.path().join("socket")).unwrap();
+    let location = vpfs.place("test42", Placement::Auto).unwrap();
+    assert_eq!(vpfs.write(location.clone(), &data), Err(VPFSError::QuotaExceeded));
+    drop(vpfs);
+    daemon.shutdown();
+
+    let daemon = daemon::Daemon::builder().options(daemon::Opt::from_command_line(["daemon", "--config", config, "--tcp-clients", "--no-quota"]).unwrap()).spawn().unwrap();
+    let vpfs = VPFS::connect_as(daemon.port(), &current_user(), b"cluster key 42").unwrap();
+    vpfs.write(location.clone(), &data).unwrap();
+    drop(vpfs);
+    daemon.shutdown();
+
+    // Without the config file, the options saved by the last run apply
+    let data_dir = config_dir.path().join("data");
+    let daemon = daemon::Daemon::builder().options(daemon::Opt::from_command_line(["daemon", "--data-dir", data_dir.to_str().unwrap(), "--port", "0"]).unwrap()).spawn().unwrap();
+    let vpfs = VPFS::connect(daemon.port()).unwrap();
+    assert_eq!(vpfs.read(location).unwrap(), data);
+
+    std::fs::write(&config_file, "quota = 1000\nno-quota = true\n").unwrap();
+    assert!(daemon::Opt::from_command_line(["daemon", "--config", config]).is_err());
+}
+
+#[test]
+fn saved_options_can_be_removed_through_the_builder(){
+    let dir = tempfile::TempDir::new().unwrap();
+    let socket_path = dir.path().join("socket");
+    let data_dir = dir.path().join("data");
+    let start = |configure: fn(daemon::DaemonBuilder) -> daemon::DaemonBuilder| configure(daemon::Daemon::builder()
+        .name("root")
+        .port(0)
+        .data_dir(data_dir.to_str().unwrap())
+        .socket(socket_path.to_str().unwrap()))
+        .spawn()
+        .unwrap();
+    let data = vec![43u8; 2000];
+
+    let daemon = start(|builder| builder.no_tcp_clients().quota(1000));
+    assert!(VPFS::connect(daemon.port()).is_err());
+    let vpfs = VPFS::connect_unix(&socket_path).unwrap();
+    let location = vpfs.place("test43", Placement::Auto).unwrap();
+    assert_eq!(vpfs.write(location.clone(), &data), Err(VPFSError::QuotaExceeded));
+    drop(vpfs);
+    daemon.shutdown();
+
+    let daemon = start(|builder| builder.tcp_clients().no_quota());
+    daemon.shutdown();
+
+    let daemon = start(|builder| builder);
+    assert!(VPFS::connect(daemon.port()).is_ok());
+    let vpfs = VPFS::connect_unix(&socket_path).unwrap();
+    vpfs.write(location, &data).unwrap();
+}
+
+#[test]
+fn daemons_without_the_cluster_key_are_rejected(){
+    let cluster = TestCluster::start(1);
+    let key_dir = tempfile::TempDir::new().unwrap();
+    let wrong_key_file = key_dir.path().join("key");
+    std::fs::write(&wrong_key_file, "not the cluster key").unwrap();
+    let data_dir = tempfile::TempDir::new().unwrap();
+    let join = |key_file: Option<&std::path::Path>| {
+        let builder = daemon::Daemon::builder()
+            .name("node44")
+            .port(0)
+            .listening_addr("127.0.0.1:0")
+            .root_addr(&format!("127.0.0.1:{}", cluster.daemon(0).port()))
+            .data_dir(data_dir.path().to_str().unwrap());
+        match key_file {
+            Some(key_file) => builder.key_file(key_file.to_str().unwrap()),
+            None => builder,
+        }.spawn()
+    };
+
+    assert!(join(None).is_err());
+    assert!(join(Some(&wrong_key_file)).is_err());
+    assert!(cluster.connect(0).nodes().iter().all(|status| status.node.name != "node44"));
+    assert!(join(Some(&cluster.key_file())).is_ok());
+}
+
+#[test]
+fn encrypted_clusters_only_link_encrypting_daemons(){
+    let cluster = TestCluster::start_with(3, |builder| builder.encrypt());
+    let data = "Hello world 45".as_bytes();
+
+    let writer = cluster.connect(1);
+    let location = writer.place("test45", cluster.node(2)).unwrap();
+    writer.write(location.clone(), data).unwrap();
+    assert_eq!(cluster.connect(0).read(location.clone()).unwrap(), data);
+    assert_eq!(cluster.connect(2).read(location).unwrap(), data);
+
+    let data_dir = tempfile::TempDir::new().unwrap();
+    let plain = daemon::Daemon::builder()
+        .name("node45")
+        .port(0)
+        .listening_addr("127.0.0.1:0")
+        .root_addr(&format!("127.0.0.1:{}", cluster.daemon(0).port()))
+        .data_dir(data_dir.path().to_str().unwrap())
+        .key_file(cluster.key_file().to_str().unwrap())
+        .spawn();
+    assert!(plain.is_err());
+}
+
+#[test]
+fn quota_is_freed_by_overwrites_and_drains(){
+    let mut cluster = TestCluster::start(2);
+    let node = cluster.add_with(|builder| builder.quota(5000));
+    let vpfs = cluster.connect(1);
+    let used = || cluster.connect(2).df().into_iter().find(|capacity| capacity.node == node).unwrap().capacity.unwrap().used;
+
+    let first = vpfs.place("test46a", node.clone()).unwrap();
+    vpfs.write(first.clone(), &[46u8; 3000]).unwrap();
+    let second = vpfs.place("test46b", node.clone()).unwrap();
+    assert_eq!(vpfs.write(second.clone(), &[46u8; 3000]), Err(VPFSError::QuotaExceeded));
+
+    vpfs.write(first.clone(), &[46u8; 100]).unwrap();
+    vpfs.write(second.clone(), &[46u8; 3000]).unwrap();
+    assert!(used() >= 3100);
+
+    assert_eq!(vpfs.drain(node.clone()), Ok(2));
+    assert!(used() < 100, "{} bytes still used after the drain", used());
+    assert_eq!(vpfs.fetch("test46b").unwrap(), vec![46u8; 3000]);
+}
+
+#[test]
+fn nodes_find_each_other_through_gossip(){
+    let heartbeats = |builder: daemon::DaemonBuilder| builder.heartbeat_interval(std::time::Duration::from_millis(100));
+    let mut cluster = TestCluster::start_with(2, heartbeats);
+    let data = "Hello world 47".as_bytes();
+
+    // Node1 can not ask the root about nodes that join later, only the newcomers can tell it
+    let vpfs = cluster.connect(1);
+    vpfs.inject_faults(partition_from(cluster.root())).unwrap();
+    let node = cluster.add_with(heartbeats);
+    let start = std::time::Instant::now();
+    while !vpfs.nodes().iter().any(|status| status.node == node && status.reachable) {
+        assert!(start.elapsed() < std::time::Duration::from_secs(5), "node1 did not hear of node2");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+
+    let writer = cluster.connect(2);
+    let location = writer.place("test47", node.clone()).unwrap();
+    writer.write(location.clone(), data).unwrap();
+    assert_eq!(vpfs.read(location).unwrap(), data);
+}
+
+// Cuts a daemon off from the rest of the cluster in both directions
+fn isolate(cluster: &TestCluster, size: usize, index: usize) {
+    let others: Vec<usize> = (0..size).filter(|other| *other != index).collect();
+    let rules = others.iter().map(|other| FaultRule { peer: Some(cluster.node(*other)), request: None, fault: Fault::Partition }).collect();
+    cluster.connect(index).inject_faults(FaultPlan { rules, fail_writes: false }).unwrap();
+    for other in others {
+        cluster.connect(other).inject_faults(partition_from(cluster.node(index))).unwrap();
+    }
+}
+
+fn heal(cluster: &TestCluster, size: usize) {
+    for index in 0..size {
+        cluster.connect(index).inject_faults(FaultPlan::default()).unwrap();
+    }
+}
+
+fn fast_failover(builder: daemon::DaemonBuilder) -> daemon::DaemonBuilder {
+    builder.heartbeat_interval(std::time::Duration::from_millis(100)).failover_after(2)
+}
+
+#[test]
+fn partitioned_root_steps_down_when_it_rejoins(){
+    let mut cluster = TestCluster::start_with(1, fast_failover);
+    cluster.add_with(|builder| fast_failover(builder).standby());
+    cluster.add_with(fast_failover);
+
+    // Node1 and node2 are a majority, so they fail over to node1 and place files in its root directory
+    isolate(&cluster, 3, 0);
+    let writer = cluster.connect(2);
+    let start = std::time::Instant::now();
+    while writer.place("test48", cluster.node(2)).is_err() {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "node2 did not fail over");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    let old_root = cluster.connect(0);
+    assert!(old_root.find("test48").is_err());
+
+    // Back in touch, the old root hears of the newer root and follows it
+    heal(&cluster, 3);
+    let start = std::time::Instant::now();
+    while old_root.find("test48").is_err() {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "old root did not step down");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    assert_eq!(cluster.connect(1).find("test48").unwrap().location, cluster.connect(0).find("test48").unwrap().location);
+}
+
+#[test]
+fn isolated_standby_does_not_promote_itself(){
+    let mut cluster = TestCluster::start_with(1, fast_failover);
+    cluster.add_with(fast_failover);
+    cluster.add_with(|builder| fast_failover(builder).standby());
+
+    // Node2 alone misses the root's heartbeats, which is not a majority of the cluster
+    isolate(&cluster, 3, 2);
+    cluster.connect(1).place("test49", cluster.node(1)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1000));
+    heal(&cluster, 3);
+    std::thread::sleep(std::time::Duration::from_millis(500));
+
+    // Had node2 promoted itself, its newer epoch would win and its copy of the root directory
+    // predates test49
+    assert!(cluster.connect(2).find("test49").is_ok());
+    assert!(cluster.connect(0).find("test49").is_ok());
+}
+
+#[test]
+fn standbys_promoted_apart_agree_on_one_root(){
+    let mut cluster = TestCluster::start_with(1, fast_failover);
+    cluster.add_with(|builder| fast_failover(builder).standby());
+    cluster.add_with(|builder| fast_failover(builder).standby());
+
+    // Both standbys are promoted from epoch 0 while they can not hear of each other
+    isolate(&cluster, 3, 2);
+    cluster.connect(1).promote_to_root().unwrap();
+    cluster.connect(2).promote_to_root().unwrap();
+    heal(&cluster, 3);
+
+    // Once every node follows the same root, files placed through node1 are found everywhere
+    let writer = cluster.connect(1);
+    let start = std::time::Instant::now();
+    for attempt in 0.. {
+        let file_name = format!("test50-{attempt}");
+        writer.place(&file_name, cluster.node(1)).unwrap();
+        if cluster.connect(0).find(&file_name).is_ok() && cluster.connect(2).find(&file_name).is_ok() {
+            break;
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "the cluster stayed split between two roots");
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn standby_alone_with_the_root_fails_over(){
+    let mut cluster = TestCluster::start_with(1, fast_failover);
+    cluster.add_with(|builder| fast_failover(builder).standby());
+
+    isolate(&cluster, 2, 0);
+    let writer = cluster.connect(1);
+    let start = std::time::Instant::now();
+    while writer.place("test51", cluster.node(1)).is_err() {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10), "node1 did not fail over");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn rebalancing_moves_files_off_a_full_node(){
+    let cluster = TestCluster::start(3);
+    let vpfs = cluster.connect(0);
+    let data = "Hello world 54\n".repeat(1000);
+
+    // Everything starts out on node1, only test54/pinned is put there by a rule
+    vpfs.mkdir("test54", cluster.root()).unwrap();
+    let rules = vec![PlacementRule {prefix: "test54/pinned".to_string(), node: cluster.node(1)}];
+    vpfs.set_placement_policy("test54", Some(PlacementPolicy::Rules(rules))).unwrap();
+    let names: Vec<String> = ["test54/pinned".to_string()].into_iter().chain((0..6).map(|index| format!("test54/file{index}"))).collect();
+    for name in &names {
+        let location = vpfs.place(name, cluster.node(1)).unwrap();
+        vpfs.write(location, data.as_bytes()).unwrap();
+    }
+
+    let moved = vpfs.rebalance().unwrap();
+    assert!(moved > 0);
+    let locations: Vec<Location> = names.iter().map(|name| vpfs.find(name).unwrap().location).collect();
+    assert_eq!(locations.iter().filter(|location| location.node != cluster.node(1)).count(), moved);
+    assert_eq!(locations[0].node, cluster.node(1));
+    for location in locations {
+        assert_eq!(vpfs.read(location).unwrap(), data.as_bytes());
+    }
+    let used: Vec<u64> = vpfs.df().into_iter().map(|node_capacity| node_capacity.capacity.unwrap().used).collect();
+    assert!(used.iter().all(|used| *used < 6 * data.len() as u64), "{used:?}");
+}
+
+#[test]
+fn state_files_from_older_versions_are_upgraded(){
+    let data_dir = tempfile::TempDir::new().unwrap();
+    let key_dir = tempfile::TempDir::new().unwrap();
+    let key_file = key_dir.path().join("key");
+    std::fs::write(&key_file, "cluster key 55").unwrap();
+
+    // Version 4 had no tombstones in host entries and no options past the quota
+    let local = Node { name: "test55".to_string() };
+    let other = Node { name: "node55".to_string() };
+    let known_hosts = std::collections::HashMap::from([(other.clone(), ("127.0.0.1:1".to_string(), 1u64))]);
+    let config = (0u16, 1u64 << 16, 0u64, 1000u64, false, Some(key_file.to_str().unwrap().to_string()), false, None::<String>, true, Some(1u64 << 20));
+    let mut state_file = serde_bare::to_vec(&4u32).unwrap();
+    state_file.extend(serde_bare::to_vec(&(&local, Some(&local), Some(("127.0.0.1:0".to_string(), 1u64)), known_hosts, Vec::<Node>::new(), config)).unwrap());
+    std::fs::write(data_dir.path().join("state"), state_file).unwrap();
+
+    let daemon = daemon::Daemon::builder().data_dir(data_dir.path().to_str().unwrap()).spawn().unwrap();
+    assert_eq!(*daemon.node(), local);
+    let vpfs = VPFS::connect_as(daemon.port(), "test55", b"cluster key 55").unwrap();
+    assert!(vpfs.nodes().iter().any(|status| status.node == other));
+    let capacity = vpfs.df().into_iter().find(|node_capacity| node_capacity.node == local).unwrap().capacity.unwrap();
+    assert_eq!(capacity.quota, Some(1 << 20));
+    daemon.shutdown();
+
+    // Saved again in the current layout
+    let daemon = daemon::Daemon::builder().data_dir(data_dir.path().to_str().unwrap()).spawn().unwrap();
+    assert_eq!(*daemon.node(), local);
+}